@@ -0,0 +1,355 @@
+//! Asset pipeline tool, runs without a window so it can be used in CI.
+//!
+//! Model and texture paths are relative to the asset root, which is the
+//! current directory unless `--root` or `BONES_ASSET_ROOT` says otherwise.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::{bail, Context};
+use bones::assets::*;
+
+const USAGE: &str = "\
+usage: bones-asset [--root <dir>] <command> [args]
+
+commands:
+    inspect <model>...                  print meshes, materials and bounds
+    validate <model>...                 check that a model and everything it references loads
+    convert <model>...                  write a .mesh cache next to each model
+    pack [--store] <dir> <output>       build a pack file from everything in <dir>";
+
+fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    match run(std::env::args().skip(1).collect()) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {}", describe(&e));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns whether every file passed, errors are for bad usage.
+fn run(mut args: Vec<String>) -> anyhow::Result<bool> {
+    let root = match take_option(&mut args, "--root")? {
+        Some(root) => PathBuf::from(root),
+        None => std::env::var_os("BONES_ASSET_ROOT").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(".")),
+    };
+    set_asset_root(root);
+
+    if args.is_empty() {
+        bail!("missing command\n\n{}", USAGE);
+    }
+    let command = args.remove(0);
+    match command.as_str() {
+        "inspect" => each_file(&args, inspect),
+        "validate" => each_file(&args, validate),
+        "convert" => each_file(&args, convert),
+        "pack" => {
+            let store = take_flag(&mut args, "--store");
+            let [dir, output] = &args[..] else {
+                bail!("pack takes a directory and an output file\n\n{}", USAGE);
+            };
+            pack(Path::new(dir), Path::new(output), store)?;
+            Ok(true)
+        }
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(true)
+        }
+        _ => bail!("unknown command {}\n\n{}", command, USAGE),
+    }
+}
+
+/// The error and its causes, skipping causes that asset errors already
+/// include in their message.
+fn describe(error: &anyhow::Error) -> String {
+    let mut message = String::new();
+    for cause in error.chain() {
+        let cause = cause.to_string();
+        if !message.contains(&cause) {
+            if !message.is_empty() {
+                message.push_str(": ");
+            }
+            message.push_str(&cause);
+        }
+    }
+    message
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let found = args.iter().any(|arg| arg == flag);
+    args.retain(|arg| arg != flag);
+    found
+}
+
+fn take_option(args: &mut Vec<String>, option: &str) -> anyhow::Result<Option<String>> {
+    let Some(i) = args.iter().position(|arg| arg == option) else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        bail!("{} needs a value", option);
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Ok(Some(value))
+}
+
+/// Runs `f` for every file and reports failures without stopping, so one
+/// run lists every broken asset.
+fn each_file(files: &[String], mut f: impl FnMut(&str) -> anyhow::Result<()>) -> anyhow::Result<bool> {
+    if files.is_empty() {
+        bail!("no files given\n\n{}", USAGE);
+    }
+    let mut ok = true;
+    for file_name in files {
+        if let Err(e) = f(file_name) {
+            eprintln!("{}: {}", file_name, describe(&e));
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+fn inspect(file_name: &str) -> anyhow::Result<()> {
+    let data = pollster::block_on(load_model_data(file_name))?;
+
+    let vertices = data.meshes.iter().map(|mesh| mesh.vertices.len()).sum::<usize>();
+    let triangles = data.meshes.iter().map(|mesh| mesh.indices.len() / 3).sum::<usize>();
+    println!("{}", file_name);
+    println!("  {} meshes, {} vertices, {} triangles, {} materials", data.meshes.len(), vertices, triangles, data.materials.len());
+
+    let mut bounds = Bounds::of(&[]);
+    for mesh in &data.meshes {
+        let mesh_bounds = Bounds::of(&mesh.vertices);
+        bounds.add(&mesh_bounds);
+        println!(
+            "  mesh {}: {} vertices, {} triangles, material {}, bounds {}",
+            mesh.name,
+            mesh.vertices.len(),
+            mesh.indices.len() / 3,
+            mesh.material,
+            format_bounds(&mesh_bounds),
+        );
+    }
+    println!("  bounds {}", format_bounds(&bounds));
+
+    for (i, material) in data.materials.iter().enumerate() {
+        let texture = |texture: &Option<TextureData>| match texture {
+            Some(texture) => {
+                let (width, height) = texture.image.dimensions();
+                match &texture.image {
+                    ImageData::Gpu(image) => format!("{} ({}x{} {:?})", texture.label, width, height, image.format),
+                    ImageData::Decoded(_) => format!("{} ({}x{})", texture.label, width, height),
+                }
+            },
+            None => String::from("none"),
+        };
+        println!("  material {} {}:", i, material.name);
+        for (slot, data) in material.textures() {
+            println!("    {} texture {}", slot, texture(data));
+        }
+        let uniform = &material.uniform;
+        println!(
+            "    base color {:?}, metallic {}, roughness {}, emissive {:?}",
+            uniform.base_color, uniform.metallic, uniform.roughness, uniform.emissive,
+        );
+        println!(
+            "    occlusion strength {}, normal scale {}",
+            uniform.occlusion_strength, uniform.normal_scale,
+        );
+        match material.pipeline.alpha_mode {
+            AlphaMode::Mask => println!("    alpha mode Mask, cutoff {}", uniform.alpha_cutoff),
+            alpha_mode => println!("    alpha mode {:?}", alpha_mode),
+        }
+    }
+
+    if let Some(skin) = &data.skin {
+        println!("  skin: {} bones, {} joints", skin.bones.len(), skin.joints.len());
+    }
+    Ok(())
+}
+
+fn format_bounds(bounds: &Bounds) -> String {
+    if bounds.is_empty() {
+        return String::from("empty");
+    }
+    format!("{:?} to {:?}", bounds.min, bounds.max)
+}
+
+fn validate(file_name: &str) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    if file_name.to_lowercase().ends_with(".obj") {
+        problems = validate_obj_references(file_name);
+    }
+
+    // Catches everything the scan above doesn't, like parse errors or
+    // broken glTF buffers. Not worth it if the scan found something, it
+    // would only report the first of those again.
+    if problems.is_empty() {
+        if let Err(e) = pollster::block_on(load_source_model_data(file_name)) {
+            problems.push(e.to_string());
+        }
+    }
+
+    if problems.is_empty() {
+        println!("{}: ok", file_name);
+        return Ok(());
+    }
+    for problem in &problems {
+        eprintln!("{}: {}", file_name, problem);
+    }
+    bail!("{} problems", problems.len())
+}
+
+/// The loader stops at the first missing file and quietly falls back for
+/// unknown materials, so go through the references one by one instead.
+fn validate_obj_references(file_name: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let obj = match pollster::block_on(load_string(file_name)) {
+        Ok(obj) => obj,
+        Err(e) => return vec![e.to_string()],
+    };
+
+    let mut defined = Vec::new();
+    let mut used = BTreeSet::new();
+    for line in obj.lines() {
+        let line = line.trim();
+        if let Some(mtl) = line.strip_prefix("mtllib ") {
+            let mtl = mtl.trim();
+            match pollster::block_on(load_string(mtl)) {
+                Ok(text) => {
+                    for line in text.lines() {
+                        let mut words = line.split_whitespace();
+                        match words.next() {
+                            Some("newmtl") => defined.extend(words.next().map(String::from)),
+                            Some(keyword) if keyword.starts_with("map_") || keyword == "norm" || keyword == "bump" => {
+                                // Options like -bm come before the path
+                                let Some(texture) = words.last() else {
+                                    continue;
+                                };
+                                if let Err(e) = pollster::block_on(load_texture_image(texture)) {
+                                    problems.push(format!("{} references texture {}: {}", mtl, texture, e));
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Err(e) => problems.push(format!("mtllib {}: {}", mtl, e)),
+            }
+        } else if let Some(material) = line.strip_prefix("usemtl ") {
+            used.insert(material.trim().to_string());
+        }
+    }
+
+    for material in used {
+        if !defined.contains(&material) {
+            problems.push(format!("usemtl {} isn't defined in any material library", material));
+        }
+    }
+    problems
+}
+
+fn convert(file_name: &str) -> anyhow::Result<()> {
+    let path = MeshCache::import_to_file(file_name)?;
+    println!("{} -> {}", file_name, path.display());
+    Ok(())
+}
+
+fn pack(dir: &Path, output: &Path, store: bool) -> anyhow::Result<()> {
+    let mut writer = PackWriter::new();
+    writer.add_dir(dir).with_context(|| format!("couldn't read {}", dir.display()))?;
+    if store {
+        writer.set_compression(PackCompression::None);
+    }
+    writer.write_to_file(output).with_context(|| format!("couldn't write {}", output.display()))?;
+    println!("{} -> {} ({} files)", dir.display(), output.display(), writer.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    // The asset root is global, so every test shares one. Material and
+    // texture paths are relative to it like everywhere else.
+    fn root() -> &'static Path {
+        static ROOT: OnceLock<PathBuf> = OnceLock::new();
+        ROOT.get_or_init(|| {
+            let root = std::env::temp_dir().join(format!("bones-asset-tool-{}", std::process::id()));
+            std::fs::create_dir_all(&root).unwrap();
+            let triangle = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvn 0 0 1\nf 1/1/1 2/1/1 3/1/1\n";
+            std::fs::write(root.join("good.mtl"), "newmtl red\nKd 1 0 0\n").unwrap();
+            std::fs::write(root.join("good.obj"), format!("mtllib good.mtl\nusemtl red\n{}", triangle)).unwrap();
+            std::fs::write(root.join("bad.mtl"), "newmtl red\nmap_Kd -bm 2 missing.png\n").unwrap();
+            std::fs::write(
+                root.join("bad.obj"),
+                format!("mtllib bad.mtl\nusemtl red\n{0}usemtl blue\n{0}usemtl red\n{0}usemtl blue\n{0}", triangle),
+            )
+            .unwrap();
+            root
+        })
+    }
+
+    fn run_in_root(args: &[&str]) -> anyhow::Result<bool> {
+        let mut all = vec![String::from("--root"), root().display().to_string()];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        run(all)
+    }
+
+    #[test]
+    fn options_and_flags_are_taken_out() {
+        let mut args = ["--store", "a", "--root", "res", "b"].map(String::from).to_vec();
+        assert!(take_flag(&mut args, "--store"));
+        assert!(!take_flag(&mut args, "--store"));
+        assert_eq!(take_option(&mut args, "--root").unwrap().as_deref(), Some("res"));
+        assert_eq!(take_option(&mut args, "--out").unwrap(), None);
+        assert_eq!(args, ["a", "b"]);
+
+        let mut args = vec![String::from("--root")];
+        assert!(take_option(&mut args, "--root").is_err());
+    }
+
+    #[test]
+    fn bad_usage_is_an_error() {
+        assert!(run_in_root(&[]).is_err());
+        assert!(run_in_root(&["frobnicate"]).is_err());
+        assert!(run_in_root(&["inspect"]).is_err());
+        assert!(run_in_root(&["pack", "only-one"]).is_err());
+        assert!(run_in_root(&["help"]).unwrap());
+    }
+
+    #[test]
+    fn valid_models_pass() {
+        root();
+        assert!(run_in_root(&["validate", "good.obj"]).unwrap());
+        assert!(run_in_root(&["inspect", "good.obj"]).unwrap());
+        assert!(!run_in_root(&["inspect", "good.obj", "missing.obj"]).unwrap());
+    }
+
+    #[test]
+    fn every_broken_reference_is_reported_once() {
+        root();
+        let problems = validate_obj_references("bad.obj");
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].contains("missing.png"), "{}", problems[0]);
+        assert!(problems[1].contains("usemtl blue"), "{}", problems[1]);
+        assert!(!run_in_root(&["validate", "bad.obj"]).unwrap());
+    }
+
+    #[test]
+    fn packs_contain_the_whole_directory() {
+        let output = root().with_extension("pak");
+        assert!(run_in_root(&["pack", &root().display().to_string(), &output.display().to_string()]).unwrap());
+
+        let pack = PackAssetIo::open(&output).unwrap();
+        assert_eq!(pack.entries().len(), 4);
+        assert_eq!(pack.read("good.mtl").unwrap(), Some(std::fs::read(root().join("good.mtl")).unwrap()));
+        std::fs::remove_file(&output).unwrap();
+    }
+}
@@ -1,36 +1,32 @@
-#[cfg(target_arch="wasm32")]
-use wasm_bindgen::prelude::*;
 use winit::{
     event::*,
     event_loop::{ ControlFlow, EventLoop },
-    window::WindowBuilder,
 };
 pub mod world;
 pub mod component;
 pub use world::World;
+pub use crate::engine::settings::{RendererSettings, WindowSettings, WindowMode, ShadowSettings, MsaaSettings};
 
 pub type System = fn(&mut World);
+pub use crate::engine::resources::PreloadProgress;
+use crate::engine::resources;
+use crate::engine::asset_io::AssetIo;
+use std::path::PathBuf;
 
+#[derive(Default)]
 pub struct Skeleton {
-    world: World,
     init_system: Vec<System>,
     system: Vec<System>,
-    event_loop: EventLoop<()>
-}
-
-impl Default for Skeleton {
-    fn default() -> Self {
-        let event_loop = EventLoop::new();
-        let window = WindowBuilder::new().build(&event_loop).unwrap();
-        let state = pollster::block_on(crate::engine::State::new(window));
-
-        Self {
-            world: World::new(state),
-            init_system: Vec::new(),
-            system: Vec::new(),
-            event_loop,
-        }
-    }
+    renderer_settings: RendererSettings,
+    window_settings: WindowSettings,
+    preload_assets: Vec<String>,
+    preload_progress: Option<PreloadProgress>,
+    asset_root: Option<PathBuf>,
+    asset_sources: Vec<Box<dyn FnOnce()>>,
+    asset_packs: Vec<String>,
+    shaders: Vec<(String, String)>,
+    hot_reload: bool,
+    shader_dir: Option<PathBuf>,
 }
 
 impl Skeleton {
@@ -38,12 +34,26 @@ impl Skeleton {
         Skeleton::default()
     }
 
-    pub fn run(self) {
-        pollster::block_on(self._internal_run());
+    /// Creates the window and renderer, then runs the event loop. On native
+    /// this only returns if the renderer couldn't be set up. On the web the
+    /// setup is spawned onto the browser's executor and this returns right away,
+    /// errors are logged to the console.
+    pub fn run(self) -> anyhow::Result<()> {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                wasm_bindgen_futures::spawn_local(async move {
+                    if let Err(e) = self._internal_run().await {
+                        log::error!("Failed to start: {:?}", e);
+                    }
+                });
+                Ok(())
+            } else {
+                pollster::block_on(self._internal_run())
+            }
+        }
     }
 
-    #[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
-    async fn _internal_run(mut self) {
+    async fn _internal_run(self) -> anyhow::Result<()> {
         cfg_if::cfg_if! {
             if #[cfg(target_arch = "wasm32")] {
                 std::panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -53,97 +63,70 @@ impl Skeleton {
             }
         }
 
-        // iterate over init systems
-        for &system in &self.init_system[..] {
-            system(&mut self.world);
+        if let Some(root) = self.asset_root {
+            resources::set_asset_root(root);
+        }
+        // Packs go first so loose files in search paths can patch them
+        for pack in &self.asset_packs {
+            resources::mount_pack(pack).await?;
+        }
+        for mount in self.asset_sources {
+            mount();
         }
+        log::info!("Loading assets from {}", resources::asset_root().display());
+
+        // Fetch everything up front so blocking loads later on (e.g. World::load_model)
+        // never have to wait on the network
+        resources::preload(&self.preload_assets, self.preload_progress).await?;
+
+        let event_loop = EventLoop::new();
+        let window = self.window_settings.window_builder().build(&event_loop)?;
 
         #[cfg(target_arch = "wasm32")]
         {
-            // Winit prevents sizing with CSS, so we have to set
-            // the size manually when on web.
-            use winit::dpi::PhysicalSize;
-            engine.set_inner_size(PhysicalSize::new(450, 400));
-            
-            use winit::platform::web::WindowExtWebSys;
-            web_sys::window()
-                .and_then(|win| win.document())
-                .and_then(|doc| {
-                    let dst = doc.get_element_by_id("wasm-canvas")?;
-                    let canvas = web_sys::Element::from(engine.canvas());
-                    dst.append_child(&canvas).ok()?;
-                    Some(())
-                })
-                .expect("Couldn't append canvas to document body.");
+            // Winit prevents sizing with CSS, so we size the canvas to fit
+            // its container before the surface gets configured.
+            crate::engine::web::attach_canvas(&window, self.window_settings.canvas_id.as_deref())?;
+            if let Some(size) = crate::engine::web::container_size(&window) {
+                window.set_inner_size(size);
+            }
+        }
+
+        let mut state = crate::engine::State::new(window, &self.renderer_settings, &self.window_settings).await?;
+        for (name, file_name) in &self.shaders {
+            state.load_shader(name, file_name).await?;
+        }
+        if self.hot_reload {
+            state.enable_hot_reload(self.shader_dir);
+        }
+        let mut world = World::new(state);
+        world.insert_resource(self.window_settings);
+        world.insert_resource(ShadowSettings::default());
+        world.insert_resource(MsaaSettings::default());
+        world.insert_resource(crate::engine::debug_draw::DebugDraw::default());
+        let systems = self.system;
+
+        // iterate over init systems
+        for &system in &self.init_system[..] {
+            system(&mut world);
         }
 
         let mut last_render_time = instant::Instant::now();
 
-        self.event_loop.run(move |event, _, control_flow| {
-        
-            // iterate over systems
-            for &system in &self.system[..] {
-                system(&mut self.world);
-            }
-            
-            match event {
-                Event::DeviceEvent {
-                    event: DeviceEvent::MouseMotion{ delta, },
-                    .. // We're not using device_id currently
-                } => if self.world.state.mouse_pressed {
-                    self.world.state.camera_controller.process_mouse(delta.0, delta.1)
-                }
-                Event::WindowEvent {
-                    ref event,
-                    window_id,
-                } if window_id == self.world.state.window().id() => {
-                    if !self.world.state.input(event) {
-                        match event {
-                            #[cfg(not(target_arch="wasm32"))]
-                            WindowEvent::CloseRequested
-                            | WindowEvent::KeyboardInput {
-                                input:
-                                    KeyboardInput {
-                                        state: ElementState::Pressed,
-                                        virtual_keycode: Some(VirtualKeyCode::Escape),
-                                        ..
-                                    },
-                                ..
-                            } => *control_flow = ControlFlow::Exit,
-                            WindowEvent::Resized(physical_size) => {
-                                self.world.state.resize(*physical_size);
-                            }
-                            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                                // new_inner_size is &&mut so w have to dereference it twice
-                                self.world.state.resize(**new_inner_size);
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-                Event::RedrawRequested(window_id) if window_id == self.world.state.window().id() => {
-                    let now = instant::Instant::now();
-                    let dt = now - last_render_time;
-                    last_render_time = now;
-                    self.world.state.update(dt);
-                    match self.world.state.render() {
-                        Ok(_) => {}
-                        // Reconfigure the surface if it's lost or outdated
-                        Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => self.world.state.resize(self.world.state.size),
-                        // The system is out of memory, we should probably quit
-                        Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-
-                        Err(wgpu::SurfaceError::Timeout) => log::warn!("Surface timeout"),
-                    }
-                }
-                Event::RedrawEventsCleared => {
-                    // RedrawRequested will only trigger once, unless we manually
-                    // request it.
-                    self.world.state.window().request_redraw();
-                }
-                _ => {}
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                // Hands the loop to the browser, which drives it with requestAnimationFrame
+                use winit::platform::web::EventLoopExtWebSys;
+                event_loop.spawn(move |event, _, control_flow| {
+                    handle_event(&mut world, &systems, &mut last_render_time, event, control_flow)
+                });
+                Ok(())
+            } else {
+                event_loop.run(move |event, _, control_flow| {
+                    handle_event(&mut world, &systems, &mut last_render_time, event, control_flow)
+                })
             }
-        });
+        }
     }
 
     pub fn add_init_system(mut self, system: System) -> Skeleton {
@@ -155,4 +138,171 @@ impl Skeleton {
         self.system.push(system);
         self
     }
+
+    pub fn with_renderer_settings(mut self, settings: RendererSettings) -> Skeleton {
+        self.renderer_settings = settings;
+        self
+    }
+
+    /// Sets up the window. Systems can change these later through the
+    /// `WindowSettings` resource.
+    pub fn with_window_settings(mut self, settings: WindowSettings) -> Skeleton {
+        self.window_settings = settings;
+        self
+    }
+
+    /// Files to fetch before the window opens. On the web they are downloaded
+    /// asynchronously and served from memory afterwards.
+    pub fn with_preload_assets(mut self, files: &[&str]) -> Skeleton {
+        self.preload_assets.extend(files.iter().map(|file| file.to_string()));
+        self
+    }
+
+    /// Directory assets are loaded from (a URL path on the web). Defaults to
+    /// `BONES_ASSET_ROOT`, or a `res` directory next to the executable.
+    pub fn with_asset_root<P: Into<PathBuf>>(mut self, root: P) -> Skeleton {
+        self.asset_root = Some(root.into());
+        self
+    }
+
+    /// Adds a directory searched before the asset root, e.g. for mods.
+    /// Paths added later take precedence.
+    pub fn with_asset_path<P: Into<PathBuf>>(mut self, path: P) -> Skeleton {
+        let path = path.into();
+        self.asset_sources.push(Box::new(move || resources::add_search_path(path)));
+        self
+    }
+
+    /// Mounts a custom source, like a `MemoryAssetIo` in tests, on top of the
+    /// root and the search paths.
+    pub fn with_asset_io<I: AssetIo + 'static>(mut self, io: I) -> Skeleton {
+        self.asset_sources.push(Box::new(move || resources::mount(io)));
+        self
+    }
+
+    /// Mounts a pack file (see `PackWriter`) from the asset root, so loads
+    /// find its files as if they were loose. Packs go on top of the root but
+    /// beneath `with_asset_path` and `with_asset_io`, so loose files can patch
+    /// a pack. Later packs win over earlier ones.
+    pub fn with_asset_pack(mut self, file_name: &str) -> Skeleton {
+        self.asset_packs.push(file_name.to_string());
+        self
+    }
+
+    /// Loads a WGSL shader from the assets that materials can use by setting
+    /// `MaterialPipeline::shader` to `name`, see `State::add_shader`.
+    pub fn with_shader(mut self, name: &str, file_name: &str) -> Skeleton {
+        self.shaders.push((name.to_string(), file_name.to_string()));
+        self
+    }
+
+    /// Called after each preloaded file with `(loaded, total)`, e.g. to drive
+    /// a progress bar on the page.
+    pub fn with_preload_progress(mut self, progress: PreloadProgress) -> Skeleton {
+        self.preload_progress = Some(progress);
+        self
+    }
+
+    /// Reloads shaders and assets loaded through the asset server when their
+    /// files change. Meant for development. Models loaded straight into a
+    /// component with `World::load_model` or `World::spawn_gltf` aren't
+    /// reloaded, load a `Handle<Model>` for that.
+    pub fn with_hot_reload(mut self, enabled: bool) -> Skeleton {
+        self.hot_reload = enabled;
+        self
+    }
+
+    /// Where hot reloading looks for the WGSL shaders. Defaults to
+    /// `BONES_SHADER_DIR`, then `shaders` in the asset root, then
+    /// `src/engine` in the working directory.
+    pub fn with_shader_dir<P: Into<PathBuf>>(mut self, dir: P) -> Skeleton {
+        self.shader_dir = Some(dir.into());
+        self
+    }
+}
+
+fn handle_event(
+    world: &mut World,
+    systems: &[System],
+    last_render_time: &mut instant::Instant,
+    event: Event<()>,
+    control_flow: &mut ControlFlow,
+) {
+    // iterate over systems
+    for &system in systems {
+        system(world);
+    }
+
+    match event {
+        Event::DeviceEvent {
+            event: DeviceEvent::MouseMotion{ delta, },
+            .. // We're not using device_id currently
+        } if world.state.mouse_pressed => {
+            world.state.camera_controller.process_mouse(delta.0, delta.1)
+        }
+        Event::WindowEvent {
+            ref event,
+            window_id,
+        } if window_id == world.state.window().id() && !world.state.input(event) => {
+            match event {
+                #[cfg(not(target_arch="wasm32"))]
+                WindowEvent::CloseRequested
+                | WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::Escape),
+                            ..
+                        },
+                    ..
+                } => *control_flow = ControlFlow::Exit,
+                WindowEvent::Resized(physical_size) => {
+                    world.resize(*physical_size);
+                }
+                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    // new_inner_size is &&mut so w have to dereference it twice
+                    world.resize(**new_inner_size);
+                }
+                _ => {}
+            }
+        }
+        Event::RedrawRequested(window_id) if window_id == world.state.window().id() => {
+            let now = instant::Instant::now();
+            let dt = now - *last_render_time;
+            *last_render_time = now;
+            world.sync_window_settings();
+            world.sync_shadow_settings();
+            world.sync_msaa_settings();
+            world.state.update(dt);
+            match world.state.render() {
+                Ok(_) => {}
+                // Reconfigure the surface if it's lost or outdated
+                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => world.state.resize(world.state.size),
+                // The system is out of memory, we should probably quit
+                Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
+
+                Err(wgpu::SurfaceError::Timeout) => log::warn!("Surface timeout"),
+            }
+            // Debug lines only last a frame, drawn or not
+            if let Some(debug) = world.get_resource_mut::<crate::engine::debug_draw::DebugDraw>() {
+                debug.clear();
+            }
+        }
+        Event::RedrawEventsCleared => {
+            // The canvas doesn't follow CSS on its own, so keep it the
+            // size of its container
+            #[cfg(target_arch = "wasm32")]
+            if let Some(size) = crate::engine::web::container_size(world.state.window()) {
+                if size != world.state.size {
+                    world.state.window().set_inner_size(size);
+                    world.resize(size);
+                }
+            }
+
+            // RedrawRequested will only trigger once, unless we manually
+            // request it.
+            world.state.window().request_redraw();
+        }
+        _ => {}
+    }
 }
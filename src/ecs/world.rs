@@ -1,6 +1,12 @@
-use std::cell::{RefCell, RefMut, Ref};
+use std::cell::{RefCell, RefMut};
 use crate::engine::State;
 use crate::engine::model::Model;
+use crate::engine::environment::Environment;
+use crate::engine::debug_draw::DebugDraw;
+use crate::engine::settings::{MsaaSettings, ShadowSettings, WindowSettings};
+use crate::engine::asset_io::AssetIo;
+use crate::engine::asset_server::AssetServer;
+use crate::engine::asset_error::AssetError;
 
 pub struct World {
     pub state: State,
@@ -55,7 +61,7 @@ impl World {
 
     pub fn borrow_component_vec_mut<ComponentType: 'static>(
         &self,
-    ) -> Option<RefMut<Vec<Option<ComponentType>>>> {
+    ) -> Option<RefMut<'_, Vec<Option<ComponentType>>>> {
         for component_vec in self.state.component_vecs.iter() {
             if let Some(component_vec) = component_vec
                 .as_any()
@@ -67,15 +73,117 @@ impl World {
         None
     }
 
-    pub fn load_model(&self, filename: &str) -> Option<Model> {
-        let result = pollster::block_on(crate::engine::resources::load_model(filename, &self.state.device, &self.state.queue, &self.state.texture_bind_group_layout));
-        match result {
-            Ok(model) => {
-                Some(model)
-            }
-            Err(error) => {
-                panic!("Failed to load file: {}", error);
+    /// Stores a single global value of type `R`, replacing any previous one.
+    pub fn insert_resource<R: 'static>(&mut self, resource: R) {
+        if let Some(existing) = self.get_resource_mut::<R>() {
+            *existing = resource;
+            return;
+        }
+        self.state.resources.push(Box::new(resource));
+    }
+
+    pub fn get_resource<R: 'static>(&self) -> Option<&R> {
+        self.state.resources
+            .iter()
+            .find_map(|resource| resource.downcast_ref::<R>())
+    }
+
+    pub fn get_resource_mut<R: 'static>(&mut self) -> Option<&mut R> {
+        self.state.resources
+            .iter_mut()
+            .find_map(|resource| resource.downcast_mut::<R>())
+    }
+
+    pub fn remove_resource<R: 'static>(&mut self) -> Option<R> {
+        let index = self.state.resources.iter().position(|resource| resource.is::<R>())?;
+        self.state.resources.remove(index).downcast::<R>().ok().map(|resource| *resource)
+    }
+
+    /// Applies changes systems made to the `WindowSettings` resource.
+    pub(crate) fn sync_window_settings(&mut self) {
+        if let Some(settings) = self.get_resource::<WindowSettings>().cloned() {
+            self.state.apply_window_settings(&settings);
+        }
+    }
+
+    /// Applies changes systems made to the `ShadowSettings` resource.
+    pub(crate) fn sync_shadow_settings(&mut self) {
+        if let Some(settings) = self.get_resource::<ShadowSettings>().copied() {
+            self.state.apply_shadow_settings(&settings);
+        }
+    }
+
+    /// Applies changes systems made to the `MsaaSettings` resource.
+    pub(crate) fn sync_msaa_settings(&mut self) {
+        if let Some(settings) = self.get_resource::<MsaaSettings>().copied() {
+            self.state.apply_msaa_settings(&settings);
+        }
+    }
+
+    pub(crate) fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        let (width, height) = self.state.window_resized(new_size);
+        if let Some(settings) = self.get_resource_mut::<WindowSettings>() {
+            settings.width = width;
+            settings.height = height;
+        }
+    }
+
+    /// Mounts an extra asset source at runtime, e.g. when a mod gets enabled.
+    pub fn mount_assets<I: AssetIo + 'static>(&mut self, io: I) {
+        crate::engine::resources::mount(io);
+    }
+
+    /// Loads assets in the background, see `AssetServer::load`.
+    pub fn asset_server(&mut self) -> &mut AssetServer {
+        &mut self.state.asset_server
+    }
+
+    /// Lines drawn over the current frame, see `DebugDraw`.
+    pub fn debug_draw(&mut self) -> &mut DebugDraw {
+        if self.get_resource::<DebugDraw>().is_none() {
+            self.insert_resource(DebugDraw::default());
+        }
+        self.get_resource_mut::<DebugDraw>().unwrap()
+    }
+
+    /// Blocks until the model is loaded. Prefer `asset_server().load_model`,
+    /// which doesn't stall the frame, loads each file only once and is hot
+    /// reloaded. The model returned here belongs to the caller and isn't.
+    pub fn load_model(&self, filename: &str) -> Result<Model, AssetError> {
+        pollster::block_on(crate::engine::resources::load_model(filename, &self.state.device, &self.state.queue, &self.state.texture_bind_group_layout))
+    }
+
+    /// Blocks until the panorama is loaded, then lights the scene with it,
+    /// see `Environment::from_equirectangular`.
+    pub fn load_environment(&mut self, filename: &str, face_size: u32) -> Result<(), AssetError> {
+        let img = pollster::block_on(crate::engine::resources::load_image(filename))?;
+        let environment = Environment::from_equirectangular(&self.state.device, &self.state.queue, &img, face_size)
+            .map_err(|e| AssetError::GpuUpload {
+                path: filename.to_string(),
+                message: e.to_string(),
+            })?;
+        self.state.set_environment(environment);
+        Ok(())
+    }
+
+    /// Blocks until the glTF file is loaded, then spawns an entity for every
+    /// node of its scene, with a `Model` for those with a mesh. Returns the
+    /// entities in the same order as the nodes, parents first. Each model's
+    /// instance already holds its node's place in the scene, moving it
+    /// doesn't move the models of child nodes. The models are owned by the
+    /// entities and aren't hot reloaded.
+    pub fn spawn_gltf(&mut self, filename: &str) -> Result<Vec<usize>, AssetError> {
+        let scene = pollster::block_on(crate::engine::gltf_loader::load_gltf_scene(filename))?;
+        let models = scene.upload_nodes(&self.state.device, &self.state.queue, &self.state.texture_bind_group_layout, filename)?;
+
+        let mut entities: Vec<usize> = Vec::with_capacity(scene.nodes.len());
+        for model in models {
+            let entity = self.spawn_entity();
+            if let Some(model) = model {
+                self.add_component_to_entity(entity, model);
             }
+            entities.push(entity);
         }
+        Ok(entities)
     }
 }
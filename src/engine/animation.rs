@@ -0,0 +1,534 @@
+use std::collections::HashMap;
+use std::ops::{Add, Mul};
+use cgmath::prelude::*;
+use wgpu::util::DeviceExt;
+
+use crate::engine::asset_server::{AssetServer, Handle};
+
+/// Most joints a skin can have, matches the joint array in shader.wgsl.
+pub const MAX_JOINTS: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub translation: cgmath::Vector3<f32>,
+    pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: cgmath::Vector3::zero(),
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.translation)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Bone {
+    pub name: String,
+    /// Index into `Skin::bones`
+    pub parent: Option<usize>,
+    /// Rest pose, relative to the parent
+    pub transform: Transform,
+}
+
+/// The bones a skinned mesh is bound to. Vertices reference `joints` by
+/// index, which in turn point at bones. Bones that aren't joints are still
+/// kept since they move the joints below them.
+#[derive(Debug, Clone)]
+pub struct Skin {
+    /// Parents always come before their children
+    pub bones: Vec<Bone>,
+    pub joints: Vec<usize>,
+    pub inverse_bind_matrices: Vec<cgmath::Matrix4<f32>>,
+}
+
+impl Skin {
+    pub fn rest_pose(&self) -> Vec<Transform> {
+        self.bones.iter().map(|bone| bone.transform).collect()
+    }
+
+    /// Turns a pose (one local transform per bone) into the matrices the
+    /// shader skins with.
+    pub fn joint_matrices(&self, pose: &[Transform]) -> Vec<cgmath::Matrix4<f32>> {
+        let mut world: Vec<cgmath::Matrix4<f32>> = Vec::with_capacity(self.bones.len());
+        for (bone, transform) in self.bones.iter().zip(pose) {
+            let parent = bone.parent.map(|p| world[p]).unwrap_or(cgmath::Matrix4::identity());
+            world.push(parent * transform.matrix());
+        }
+        self.joints
+            .iter()
+            .zip(&self.inverse_bind_matrices)
+            .map(|(&bone, inverse_bind)| world[bone] * inverse_bind)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    /// Every key has an in tangent, the value and an out tangent, in that order
+    CubicSpline,
+}
+
+#[derive(Debug, Clone)]
+pub enum Keyframes {
+    Translation(Vec<cgmath::Vector3<f32>>),
+    Rotation(Vec<cgmath::Quaternion<f32>>),
+    Scale(Vec<cgmath::Vector3<f32>>),
+}
+
+/// Animates one property of the bone called `target`.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub target: String,
+    pub interpolation: Interpolation,
+    pub times: Vec<f32>,
+    pub keyframes: Keyframes,
+}
+
+/// A named animation like "walk", load it with the asset server as
+/// `"person.glb#walk"` (or `"person.glb#0"` for the first clip).
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    /// In seconds
+    pub duration: f32,
+    pub channels: Vec<Channel>,
+}
+
+fn sample<T>(
+    times: &[f32],
+    values: &[T],
+    interpolation: Interpolation,
+    time: f32,
+    lerp: impl Fn(T, T, f32) -> T,
+) -> Option<T>
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let stride = if interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+    // Skip the in tangent for cubic splines
+    let value = |key: usize| values.get(key * stride + stride / 2).copied();
+
+    let next = times.partition_point(|&t| t <= time);
+    if next == 0 {
+        return value(0);
+    }
+    if next >= times.len() {
+        return value(times.len() - 1);
+    }
+    let prev = next - 1;
+    let delta = times[next] - times[prev];
+    let s = if delta > 0.0 { (time - times[prev]) / delta } else { 0.0 };
+
+    match interpolation {
+        Interpolation::Step => value(prev),
+        Interpolation::Linear => Some(lerp(value(prev)?, value(next)?, s)),
+        Interpolation::CubicSpline => {
+            // Hermite spline between the two keys, tangents are per second
+            let out_tangent = *values.get(prev * 3 + 2)? * delta;
+            let in_tangent = *values.get(next * 3)? * delta;
+            let (s2, s3) = (s * s, s * s * s);
+            Some(
+                value(prev)? * (2.0 * s3 - 3.0 * s2 + 1.0)
+                    + out_tangent * (s3 - 2.0 * s2 + s)
+                    + value(next)? * (-2.0 * s3 + 3.0 * s2)
+                    + in_tangent * (s3 - s2),
+            )
+        }
+    }
+}
+
+impl Channel {
+    fn sample_into(&self, time: f32, transform: &mut Transform) {
+        match &self.keyframes {
+            Keyframes::Translation(values) => {
+                if let Some(v) = sample(&self.times, values, self.interpolation, time, |a, b, s| a.lerp(b, s)) {
+                    transform.translation = v;
+                }
+            }
+            Keyframes::Rotation(values) => {
+                if let Some(q) = sample(&self.times, values, self.interpolation, time, |a, b, s| a.slerp(b, s)) {
+                    transform.rotation = q.normalize();
+                }
+            }
+            Keyframes::Scale(values) => {
+                if let Some(v) = sample(&self.times, values, self.interpolation, time, |a, b, s| a.lerp(b, s)) {
+                    transform.scale = v;
+                }
+            }
+        }
+    }
+}
+
+impl AnimationClip {
+    /// Poses `skin` at `time`. Bones the clip doesn't animate keep their rest pose.
+    pub fn sample(&self, skin: &Skin, time: f32) -> Vec<Transform> {
+        let mut pose = skin.rest_pose();
+        let bones = bone_indices(skin);
+        for channel in &self.channels {
+            if let Some(&bone) = bones.get(channel.target.as_str()) {
+                channel.sample_into(time, &mut pose[bone]);
+            }
+        }
+        pose
+    }
+}
+
+fn bone_indices(skin: &Skin) -> HashMap<&str, usize> {
+    skin.bones.iter().enumerate().map(|(i, bone)| (bone.name.as_str(), i)).collect()
+}
+
+/// A clip playing on an `AnimationPlayer`.
+#[derive(Debug, Clone)]
+pub struct AnimationLayer {
+    pub clip: Handle<AnimationClip>,
+    /// In seconds since the clip started
+    pub time: f32,
+    pub weight: f32,
+    pub speed: f32,
+    pub looping: bool,
+    // Weight change per second while cross fading
+    fade: f32,
+}
+
+impl AnimationLayer {
+    pub fn set_looping(&mut self, looping: bool) -> &mut Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn set_speed(&mut self, speed: f32) -> &mut Self {
+        self.speed = speed;
+        self
+    }
+}
+
+/// Plays animation clips on the skinned `Model` (or `Handle<Model>`) of the
+/// same entity. Several clips can play at once, their poses are blended by
+/// weight. Entities sharing a `Handle<Model>` also share its pose.
+#[derive(Debug, Clone, Default)]
+pub struct AnimationPlayer {
+    pub layers: Vec<AnimationLayer>,
+    pub paused: bool,
+}
+
+impl AnimationPlayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops everything else and plays `clip` from the start, looping.
+    pub fn play(&mut self, clip: Handle<AnimationClip>) -> &mut AnimationLayer {
+        self.layers.clear();
+        self.blend(clip, 1.0)
+    }
+
+    /// Plays `clip` on top of what is already playing, or changes its weight
+    /// if it already is.
+    pub fn blend(&mut self, clip: Handle<AnimationClip>, weight: f32) -> &mut AnimationLayer {
+        let index = match self.layers.iter().position(|layer| layer.clip == clip) {
+            Some(index) => index,
+            None => {
+                self.layers.push(AnimationLayer {
+                    clip,
+                    time: 0.0,
+                    weight,
+                    speed: 1.0,
+                    looping: true,
+                    fade: 0.0,
+                });
+                self.layers.len() - 1
+            }
+        };
+        let layer = &mut self.layers[index];
+        layer.weight = weight;
+        layer.fade = 0.0;
+        layer
+    }
+
+    /// Fades `clip` in and everything else out over `duration` seconds.
+    pub fn cross_fade(&mut self, clip: Handle<AnimationClip>, duration: f32) -> &mut AnimationLayer {
+        if duration <= 0.0 {
+            return self.play(clip);
+        }
+        for layer in &mut self.layers {
+            layer.fade = -layer.weight / duration;
+        }
+        let index = match self.layers.iter().position(|layer| layer.clip == clip) {
+            Some(index) => index,
+            None => {
+                self.blend(clip, 0.0);
+                self.layers.len() - 1
+            }
+        };
+        let layer = &mut self.layers[index];
+        layer.fade = (1.0 - layer.weight) / duration;
+        layer
+    }
+
+    pub fn stop(&mut self, clip: Handle<AnimationClip>) {
+        self.layers.retain(|layer| layer.clip != clip);
+    }
+
+    pub fn is_playing(&self, clip: Handle<AnimationClip>) -> bool {
+        self.layers.iter().any(|layer| layer.clip == clip)
+    }
+
+    /// Moves time forward, called by the engine every frame. Clips that are
+    /// still loading wait at the start.
+    pub(crate) fn advance(&mut self, dt: f32, assets: &AssetServer) {
+        if self.paused {
+            return;
+        }
+        for layer in &mut self.layers {
+            if let Some(clip) = assets.get(&layer.clip) {
+                layer.time += dt * layer.speed;
+                layer.time = if layer.looping && clip.duration > 0.0 {
+                    layer.time.rem_euclid(clip.duration)
+                } else {
+                    layer.time.clamp(0.0, clip.duration)
+                };
+            }
+
+            layer.weight += layer.fade * dt;
+            if layer.fade > 0.0 && layer.weight >= 1.0 {
+                layer.weight = 1.0;
+                layer.fade = 0.0;
+            }
+        }
+        self.layers.retain(|layer| layer.fade >= 0.0 || layer.weight > 0.0);
+    }
+
+    /// Blends the poses of all layers. Where the weights add up to less
+    /// than one the rest pose makes up the difference.
+    pub fn sample(&self, skin: &Skin, assets: &AssetServer) -> Vec<Transform> {
+        let rest = skin.rest_pose();
+        let mut translations = vec![(cgmath::Vector3::zero(), 0.0); rest.len()];
+        let mut rotations = vec![(cgmath::Quaternion::zero(), 0.0); rest.len()];
+        let mut scales = vec![(cgmath::Vector3::zero(), 0.0); rest.len()];
+
+        let bones = bone_indices(skin);
+        for layer in &self.layers {
+            let weight = layer.weight.max(0.0);
+            let Some(clip) = assets.get(&layer.clip).filter(|_| weight > 0.0) else {
+                continue;
+            };
+            for channel in &clip.channels {
+                let Some(&bone) = bones.get(channel.target.as_str()) else {
+                    continue;
+                };
+                let mut sampled = rest[bone];
+                channel.sample_into(layer.time, &mut sampled);
+                match channel.keyframes {
+                    Keyframes::Translation(_) => {
+                        translations[bone].0 += sampled.translation * weight;
+                        translations[bone].1 += weight;
+                    }
+                    Keyframes::Rotation(_) => {
+                        // q and -q are the same rotation, keep them on one side
+                        let rotation = if sampled.rotation.dot(rest[bone].rotation) < 0.0 {
+                            -sampled.rotation
+                        } else {
+                            sampled.rotation
+                        };
+                        rotations[bone].0 += rotation * weight;
+                        rotations[bone].1 += weight;
+                    }
+                    Keyframes::Scale(_) => {
+                        scales[bone].0 += sampled.scale * weight;
+                        scales[bone].1 += weight;
+                    }
+                }
+            }
+        }
+
+        rest.iter()
+            .enumerate()
+            .map(|(bone, rest)| {
+                let (translation, t_weight) = translations[bone];
+                let (rotation, r_weight) = rotations[bone];
+                let (scale, s_weight) = scales[bone];
+                Transform {
+                    translation: (translation + rest.translation * (1.0 - t_weight).max(0.0)) / t_weight.max(1.0),
+                    rotation: (rotation + rest.rotation * (1.0 - r_weight).max(0.0)).normalize(),
+                    scale: (scale + rest.scale * (1.0 - s_weight).max(0.0)) / s_weight.max(1.0),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Joint matrices of a skinned model on the GPU.
+pub(crate) struct JointBuffer {
+    buffer: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl JointBuffer {
+    pub fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, matrices: &[cgmath::Matrix4<f32>]) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Joint Buffer"),
+            contents: bytemuck::cast_slice(&joint_data(matrices)),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("joint_bind_group"),
+        });
+        Self { buffer, bind_group }
+    }
+
+    pub fn write(&self, queue: &wgpu::Queue, matrices: &[cgmath::Matrix4<f32>]) {
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&joint_data(matrices)));
+    }
+}
+
+// The shader always reads MAX_JOINTS matrices, pad with identity
+fn joint_data(matrices: &[cgmath::Matrix4<f32>]) -> Vec<[[f32; 4]; 4]> {
+    let mut data = matrices
+        .iter()
+        .take(MAX_JOINTS)
+        .map(|&matrix| matrix.into())
+        .collect::<Vec<[[f32; 4]; 4]>>();
+    data.resize(MAX_JOINTS, cgmath::Matrix4::identity().into());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lerp(a: f32, b: f32, s: f32) -> f32 {
+        a + (b - a) * s
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn step_and_linear_sampling() {
+        let times = [0.0, 1.0, 2.0];
+        let values = [0.0, 10.0, 20.0];
+        let step = |time| sample(&times, &values, Interpolation::Step, time, lerp).unwrap();
+        let linear = |time| sample(&times, &values, Interpolation::Linear, time, lerp).unwrap();
+
+        assert_eq!(step(0.5), 0.0);
+        assert_eq!(step(1.0), 10.0);
+        assert_eq!(step(1.99), 10.0);
+        assert!(close(linear(0.25), 2.5));
+        assert!(close(linear(1.5), 15.0));
+        // Clamped to the first and last key outside the clip
+        assert_eq!(linear(-1.0), 0.0);
+        assert_eq!(linear(5.0), 20.0);
+        assert_eq!(sample(&[], &[] as &[f32], Interpolation::Linear, 0.0, lerp), None);
+    }
+
+    #[test]
+    fn cubic_spline_sampling() {
+        // In tangent, value, out tangent per key
+        let flat = [0.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let at = |values: &[f32], time| sample(&[0.0, 2.0], values, Interpolation::CubicSpline, time, lerp).unwrap();
+        assert_eq!(at(&flat, 0.0), 0.0);
+        assert!(close(at(&flat, 1.0), 0.5));
+        assert_eq!(at(&flat, 2.0), 1.0);
+        // Flat tangents ease in, so a quarter of the way is below a quarter
+        assert!(at(&flat, 0.5) < 0.25);
+
+        // Tangents matching the slope give a straight line
+        let straight = [0.5, 0.0, 0.5, 0.5, 1.0, 0.5];
+        assert!(close(at(&straight, 0.5), 0.25));
+        assert!(close(at(&straight, 1.5), 0.75));
+    }
+
+    fn skin() -> Skin {
+        Skin {
+            bones: vec![Bone {
+                name: String::from("root"),
+                parent: None,
+                transform: Transform::default(),
+            }],
+            joints: vec![0],
+            inverse_bind_matrices: vec![cgmath::Matrix4::identity()],
+        }
+    }
+
+    // Moves the root bone to `x` on the X axis over one second
+    fn clip(x: f32) -> AnimationClip {
+        AnimationClip {
+            name: format!("to {}", x),
+            duration: 1.0,
+            channels: vec![Channel {
+                target: String::from("root"),
+                interpolation: Interpolation::Linear,
+                times: vec![0.0, 1.0],
+                keyframes: Keyframes::Translation(vec![cgmath::Vector3::zero(), cgmath::Vector3::new(x, 0.0, 0.0)]),
+            }],
+        }
+    }
+
+    #[test]
+    fn clips_pose_their_bones() {
+        let pose = clip(4.0).sample(&skin(), 0.5);
+        assert!(close(pose[0].translation.x, 2.0));
+        assert_eq!(pose[0].rotation, cgmath::Quaternion::one());
+    }
+
+    #[test]
+    fn blended_layers_are_weighted() {
+        let mut assets = AssetServer::new();
+        let a = assets.add(clip(2.0));
+        let b = assets.add(clip(6.0));
+        let skin = skin();
+
+        let mut player = AnimationPlayer::new();
+        player.play(a).set_looping(false);
+        player.advance(1.0, &assets);
+        assert!(close(player.sample(&skin, &assets)[0].translation.x, 2.0));
+
+        // Half a clip is half way to the rest pose
+        player.blend(a, 0.5);
+        assert!(close(player.sample(&skin, &assets)[0].translation.x, 1.0));
+
+        player.blend(b, 0.5).set_looping(false);
+        player.advance(1.0, &assets);
+        assert!(close(player.sample(&skin, &assets)[0].translation.x, 4.0));
+        assert_eq!(player.layers.len(), 2);
+    }
+
+    #[test]
+    fn cross_fades_replace_the_playing_clip() {
+        let mut assets = AssetServer::new();
+        let a = assets.add(clip(2.0));
+        let b = assets.add(clip(6.0));
+
+        let mut player = AnimationPlayer::new();
+        player.play(a);
+        player.cross_fade(b, 1.0);
+        player.advance(0.5, &assets);
+        let weight = |player: &AnimationPlayer, clip| player.layers.iter().find(|layer| layer.clip == clip).map(|layer| layer.weight);
+        assert!(close(weight(&player, a).unwrap(), 0.5));
+        assert!(close(weight(&player, b).unwrap(), 0.5));
+
+        player.advance(0.6, &assets);
+        assert!(!player.is_playing(a));
+        assert_eq!(weight(&player, b), Some(1.0));
+    }
+}
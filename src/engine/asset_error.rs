@@ -0,0 +1,223 @@
+use std::io;
+
+/// Everything that can go wrong while loading an asset. Loaders return this
+/// instead of panicking so a broken file can be reported and skipped.
+#[derive(Debug, thiserror::Error)]
+pub enum AssetError {
+    #[error("asset {path} not found")]
+    NotFound { path: String },
+
+    #[error("couldn't read {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("failed to parse {path}{}: {message}", line.map(|line| format!(" at line {}", line)).unwrap_or_default())]
+    Parse {
+        path: String,
+        line: Option<usize>,
+        message: String,
+    },
+
+    #[error("material {material} in {path} uses texture {texture} which couldn't be loaded: {source}")]
+    MissingTexture {
+        path: String,
+        material: String,
+        texture: String,
+        #[source]
+        source: Box<AssetError>,
+    },
+
+    #[error("{path} has an unsupported format: {reason}")]
+    UnsupportedFormat { path: String, reason: String },
+
+    /// Fetching an asset on the web failed for a reason other than a 404
+    #[error("couldn't fetch {path}: {message}")]
+    Network { path: String, message: String },
+
+    #[error("failed to upload {path} to the GPU: {message}")]
+    GpuUpload { path: String, message: String },
+}
+
+impl AssetError {
+    pub fn path(&self) -> &str {
+        match self {
+            AssetError::NotFound { path }
+            | AssetError::Io { path, .. }
+            | AssetError::Parse { path, .. }
+            | AssetError::MissingTexture { path, .. }
+            | AssetError::UnsupportedFormat { path, .. }
+            | AssetError::Network { path, .. }
+            | AssetError::GpuUpload { path, .. } => path,
+        }
+    }
+
+    pub(crate) fn io(path: &str, source: io::Error) -> Self {
+        if source.kind() == io::ErrorKind::NotFound {
+            AssetError::NotFound { path: path.to_string() }
+        } else {
+            AssetError::Io { path: path.to_string(), source }
+        }
+    }
+
+    pub(crate) fn parse<M: ToString>(path: &str, line: Option<usize>, message: M) -> Self {
+        AssetError::Parse {
+            path: path.to_string(),
+            line,
+            message: message.to_string(),
+        }
+    }
+
+    pub(crate) fn image(path: &str, error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::Unsupported(e) => AssetError::UnsupportedFormat {
+                path: path.to_string(),
+                reason: e.to_string(),
+            },
+            image::ImageError::IoError(e) => AssetError::io(path, e),
+            e => AssetError::parse(path, None, e),
+        }
+    }
+}
+
+/// Runs `f` inside a wgpu error scope and turns validation or out of memory
+/// errors into `AssetError::GpuUpload` instead of the default panic.
+///
+/// On the web popping the scope can't be waited for, so `f` runs as is and
+/// errors go to the device's uncaptured error handler, which logs them.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn gpu_scope<T>(_device: &wgpu::Device, _path: &str, f: impl FnOnce() -> T) -> Result<T, AssetError> {
+    Ok(f())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn gpu_scope<T>(device: &wgpu::Device, path: &str, f: impl FnOnce() -> T) -> Result<T, AssetError> {
+    device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let value = f();
+    let validation = pollster::block_on(device.pop_error_scope());
+    let out_of_memory = pollster::block_on(device.pop_error_scope());
+
+    match validation.or(out_of_memory) {
+        Some(error) => Err(AssetError::GpuUpload {
+            path: path.to_string(),
+            message: error.to_string(),
+        }),
+        None => Ok(value),
+    }
+}
+
+/// tobj doesn't say where a parse error happened, so look for the first line
+/// that would trigger it.
+pub(crate) fn find_obj_error_line(text: &str, error: &tobj::LoadError) -> Option<usize> {
+    use tobj::LoadError;
+
+    let floats_ok = |args: &[&str], min: usize| {
+        args.len() >= min && args.iter().all(|arg| arg.parse::<f32>().is_ok())
+    };
+
+    let (mut positions, mut texcoords, mut normals) = (0usize, 0usize, 0usize);
+    for (i, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let Some(keyword) = words.next() else {
+            continue;
+        };
+        let args = words.collect::<Vec<_>>();
+        let bad = match (keyword, error) {
+            ("v", LoadError::PositionParseError) => !floats_ok(&args, 3),
+            ("vt", LoadError::TexcoordParseError) => !floats_ok(&args, 1),
+            ("vn", LoadError::NormalParseError) => !floats_ok(&args, 3),
+            ("f", LoadError::FaceParseError | LoadError::InvalidPolygon) => {
+                args.len() < 3 || args.iter().any(|arg| {
+                    arg.split('/').filter(|index| !index.is_empty()).any(|index| index.parse::<isize>().is_err())
+                })
+            }
+            ("f", LoadError::FaceVertexOutOfBounds) => face_out_of_bounds(&args, 0, positions),
+            ("f", LoadError::FaceTexCoordOutOfBounds) => face_out_of_bounds(&args, 1, texcoords),
+            ("f", LoadError::FaceNormalOutOfBounds) => face_out_of_bounds(&args, 2, normals),
+            _ => false,
+        };
+        if bad {
+            // Lines are 1-based in every editor
+            return Some(i + 1);
+        }
+        match keyword {
+            "v" => positions += 1,
+            "vt" => texcoords += 1,
+            "vn" => normals += 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+// OBJ indices are 1-based, negative ones count back from the last element
+fn face_out_of_bounds(args: &[&str], component: usize, count: usize) -> bool {
+    args.iter().any(|arg| {
+        match arg.split('/').nth(component).and_then(|index| index.parse::<isize>().ok()) {
+            Some(index) if index > 0 => index as usize > count,
+            Some(index) if index < 0 => index.unsigned_abs() > count,
+            Some(_) => true,
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses like `resources::load_model_data` and reports where it failed
+    fn error_line(text: &str) -> (tobj::LoadError, Option<usize>) {
+        let options = tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        };
+        let error = tobj::load_obj_buf(&mut io::BufReader::new(text.as_bytes()), &options, |_| {
+            Err(tobj::LoadError::OpenFileFailed)
+        })
+        .expect_err("the OBJ should be rejected");
+        let line = find_obj_error_line(text, &error);
+        (error, line)
+    }
+
+    const TRIANGLE: &str = "# A triangle\n\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvn 0 0 1\n";
+
+    #[test]
+    fn obj_errors_point_at_their_line() {
+        let cases = [
+            ("v 0 0 0\nv 1 zero 0\nv 0 1 0\n", tobj::LoadError::PositionParseError, 2),
+            ("v 0 0 0\nv 1 0\n", tobj::LoadError::PositionParseError, 2),
+            ("vt 0 0\nvt\tu v\n", tobj::LoadError::TexcoordParseError, 2),
+            ("vn 0 0 1\n\nvn 0 one 0\n", tobj::LoadError::NormalParseError, 3),
+            ("f 1 2 3\nf 1 2 three\n", tobj::LoadError::FaceParseError, 9),
+            ("f 1 2 3\nf 1 2 4\n", tobj::LoadError::FaceVertexOutOfBounds, 9),
+            ("f 1 2 3\nf -4 -2 -1\n", tobj::LoadError::FaceVertexOutOfBounds, 9),
+            ("f 1/1 2/1 3/1\nf 1/1 2/2 3/1\n", tobj::LoadError::FaceTexCoordOutOfBounds, 9),
+            ("f 1//1 2//1 3//2\n", tobj::LoadError::FaceNormalOutOfBounds, 8),
+        ];
+        for (body, expected, line) in cases {
+            // Face cases go after the triangle so there is something to index,
+            // their lines count on from its seven
+            let text = if body.starts_with('f') { format!("{}{}", TRIANGLE, body) } else { body.to_string() };
+            let (error, found) = error_line(&text);
+            assert_eq!(error, expected, "{:?}", text);
+            assert_eq!(found, Some(line), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn unlocated_errors_have_no_line() {
+        // Valid as far as the line scan can tell
+        assert_eq!(find_obj_error_line(TRIANGLE, &tobj::LoadError::PositionParseError), None);
+        assert_eq!(find_obj_error_line(TRIANGLE, &tobj::LoadError::ReadError), None);
+
+        let error = AssetError::parse("tree.obj", Some(12), tobj::LoadError::FaceParseError);
+        assert!(error.to_string().starts_with("failed to parse tree.obj at line 12: "), "{}", error);
+        let error = AssetError::parse("tree.obj", None, tobj::LoadError::ReadError);
+        assert!(error.to_string().starts_with("failed to parse tree.obj: "), "{}", error);
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// A place assets can be read from. Paths are relative, `/` separated
+/// asset paths like `"models/house.obj"`.
+pub trait AssetIo: Send + Sync {
+    /// Reads the whole file, or `Ok(None)` if this source doesn't have it so
+    /// the next one can be tried.
+    fn read(&self, path: &str) -> io::Result<Option<Vec<u8>>>;
+
+    fn exists(&self, path: &str) -> bool {
+        matches!(self.read(path), Ok(Some(_)))
+    }
+
+    /// When the file last changed, if the source can tell. Used to hot
+    /// reload assets.
+    fn modified(&self, _path: &str) -> Option<SystemTime> {
+        None
+    }
+
+    /// Size of the file in bytes without reading it, if the source can tell.
+    fn size(&self, _path: &str) -> Option<u64> {
+        None
+    }
+}
+
+/// Loose files below a directory.
+pub struct FileAssetIo {
+    root: PathBuf,
+}
+
+impl FileAssetIo {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn full_path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+}
+
+impl AssetIo for FileAssetIo {
+    fn read(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.full_path(path)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.full_path(path).is_file()
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        std::fs::metadata(self.full_path(path)).ok()?.modified().ok()
+    }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        Some(std::fs::metadata(self.full_path(path)).ok()?.len())
+    }
+}
+
+/// Files kept in memory, handy for tests and for generated or downloaded content.
+#[derive(Default)]
+pub struct MemoryAssetIo {
+    files: RwLock<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryAssetIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file<D: Into<Vec<u8>>>(self, path: &str, data: D) -> Self {
+        self.insert(path, data);
+        self
+    }
+
+    pub fn insert<D: Into<Vec<u8>>>(&self, path: &str, data: D) {
+        self.files.write().unwrap().insert(path.to_string(), data.into());
+    }
+
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.write().unwrap().remove(path)
+    }
+}
+
+impl AssetIo for MemoryAssetIo {
+    fn read(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.files.read().unwrap().get(path).cloned())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.read().unwrap().contains_key(path)
+    }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        self.files.read().unwrap().get(path).map(|data| data.len() as u64)
+    }
+}
+
+/// The asset root plus any number of overlays mounted on top of it. Overlays
+/// are searched newest first, so a mod mounted later replaces files from the
+/// base game without touching them.
+pub struct AssetSources {
+    /// Directory on native, URL path relative to the page's origin on the web
+    pub root: PathBuf,
+    overlays: Vec<Box<dyn AssetIo>>,
+}
+
+impl AssetSources {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            overlays: Vec::new(),
+        }
+    }
+
+    /// Picks the root at runtime: `BONES_ASSET_ROOT` if set, otherwise a `res`
+    /// directory next to the executable, otherwise `res` in the working directory.
+    pub fn default_root() -> PathBuf {
+        if cfg!(target_arch = "wasm32") {
+            return PathBuf::from(option_env!("RES_PATH").unwrap_or("res"));
+        }
+        if let Some(root) = std::env::var_os("BONES_ASSET_ROOT") {
+            return PathBuf::from(root);
+        }
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.parent()?.join("res")))
+            .filter(|dir| dir.is_dir())
+            .unwrap_or_else(|| PathBuf::from("res"))
+    }
+
+    pub fn mount<I: AssetIo + 'static>(&mut self, io: I) {
+        self.overlays.push(Box::new(io));
+    }
+
+    /// Adds another directory searched before the root, e.g. a mod folder.
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, path: P) {
+        self.mount(FileAssetIo::new(path));
+    }
+
+    /// Reads from the overlays only. The root is read separately since on the
+    /// web that needs an asynchronous fetch.
+    pub fn read_overlays(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        for io in self.overlays.iter().rev() {
+            if let Some(data) = io.read(path)? {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Default for AssetSources {
+    fn default() -> Self {
+        Self::new(Self::default_root())
+    }
+}
+
+impl AssetIo for AssetSources {
+    fn read(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        if let Some(data) = self.read_overlays(path)? {
+            return Ok(Some(data));
+        }
+        FileAssetIo::new(&self.root).read(path)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        match self.overlays.iter().rev().find(|io| io.exists(path)) {
+            Some(io) => io.modified(path),
+            None => FileAssetIo::new(&self.root).modified(path),
+        }
+    }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        match self.overlays.iter().rev().find(|io| io.exists(path)) {
+            Some(io) => io.size(path),
+            None => FileAssetIo::new(&self.root).size(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_files_can_be_added_and_removed() {
+        let io = MemoryAssetIo::new().with_file("a.txt", "first");
+        assert!(io.exists("a.txt"));
+        assert_eq!(io.read("a.txt").unwrap().as_deref(), Some(&b"first"[..]));
+        assert_eq!(io.read("b.txt").unwrap(), None);
+
+        io.insert("a.txt", "second");
+        assert_eq!(io.read("a.txt").unwrap().as_deref(), Some(&b"second"[..]));
+        assert_eq!(io.remove("a.txt").as_deref(), Some(&b"second"[..]));
+        assert!(!io.exists("a.txt"));
+    }
+
+    #[test]
+    fn later_overlays_win_over_earlier_ones_and_the_root() {
+        let root = std::env::temp_dir().join(format!("bones-asset-io-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("shared.txt"), "root").unwrap();
+        std::fs::write(root.join("root.txt"), "root").unwrap();
+
+        let mut sources = AssetSources::new(&root);
+        sources.mount(MemoryAssetIo::new().with_file("shared.txt", "base").with_file("base.txt", "base"));
+        sources.mount(MemoryAssetIo::new().with_file("shared.txt", "mod"));
+        let read = |path| sources.read(path).unwrap().map(|data| String::from_utf8(data).unwrap());
+
+        assert_eq!(read("shared.txt").as_deref(), Some("mod"));
+        assert_eq!(read("base.txt").as_deref(), Some("base"));
+        assert_eq!(read("root.txt").as_deref(), Some("root"));
+        assert_eq!(read("missing.txt"), None);
+        assert!(sources.modified("root.txt").is_some());
+        assert_eq!(sources.size("shared.txt"), Some(3));
+        assert_eq!(sources.size("root.txt"), Some(4));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
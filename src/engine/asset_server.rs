@@ -0,0 +1,597 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::engine::{animation, gltf_loader, model, resources, texture};
+use crate::engine::asset_error::AssetError;
+use crate::engine::gpu_image::ImageData;
+
+/// What uploading a loaded asset to the GPU needs.
+pub struct UploadContext<'a> {
+    pub device: &'a wgpu::Device,
+    pub queue: &'a wgpu::Queue,
+    pub texture_bind_group_layout: &'a wgpu::BindGroupLayout,
+}
+
+/// Something the `AssetServer` can load. Loading happens in two steps:
+/// `load_data` runs in the background and must not touch the GPU, `upload`
+/// runs on the render thread once the data is ready.
+pub trait Asset: Sized + 'static {
+    type Data: Send + 'static;
+
+    fn load_data(path: &str) -> impl Future<Output = Result<Self::Data, AssetError>>;
+
+    fn upload(data: Self::Data, path: &str, ctx: &UploadContext) -> Result<Self, AssetError>;
+}
+
+impl Asset for model::Model {
+    type Data = model::ModelData;
+
+    fn load_data(path: &str) -> impl Future<Output = Result<Self::Data, AssetError>> {
+        resources::load_model_data(path)
+    }
+
+    fn upload(data: Self::Data, path: &str, ctx: &UploadContext) -> Result<Self, AssetError> {
+        data.upload(ctx.device, ctx.queue, ctx.texture_bind_group_layout, path)
+    }
+}
+
+impl Asset for texture::Texture {
+    type Data = ImageData;
+
+    fn load_data(path: &str) -> impl Future<Output = Result<Self::Data, AssetError>> {
+        resources::load_texture_image(path)
+    }
+
+    fn upload(data: Self::Data, path: &str, ctx: &UploadContext) -> Result<Self, AssetError> {
+        model::TextureData { label: path.to_string(), image: data, encoded: None }.upload(ctx.device, ctx.queue, false, &Default::default())
+    }
+}
+
+impl Asset for animation::AnimationClip {
+    type Data = Self;
+
+    fn load_data(path: &str) -> impl Future<Output = Result<Self::Data, AssetError>> {
+        gltf_loader::load_animation(path)
+    }
+
+    fn upload(data: Self::Data, _path: &str, _ctx: &UploadContext) -> Result<Self, AssetError> {
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HandleId(u64);
+
+/// A cheap reference to an asset that may still be loading. Add it to an
+/// entity as a component, or look the asset up with `AssetServer::get`.
+pub struct Handle<T> {
+    pub id: HandleId,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    fn new(id: HandleId) -> Self {
+        Self { id, marker: PhantomData }
+    }
+}
+
+// Implemented by hand so `T` doesn't need to be Clone/Eq/...
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl<T> std::fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Handle<{}>({})", std::any::type_name::<T>(), self.id.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadState {
+    /// The handle isn't known to this server
+    NotLoaded,
+    Loading,
+    Loaded,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssetEvent {
+    Loaded { id: HandleId, path: String },
+    /// A loaded asset was replaced after one of its files changed
+    Modified { id: HandleId, path: String },
+    /// Failed reloads leave the previous version in place
+    Failed { id: HandleId, path: String, error: String },
+}
+
+type Uploader = fn(&mut AssetServer, HandleId, Box<dyn Any + Send>, &UploadContext);
+type Loader = fn(&AssetServer, HandleId, String);
+
+struct Entry {
+    path: String,
+    state: LoadState,
+    uploader: Uploader,
+    loader: Loader,
+    // Every file the asset was built from, the first one is `path`
+    dependencies: Vec<String>,
+}
+
+struct Loaded {
+    id: HandleId,
+    data: Box<dyn Any + Send>,
+    dependencies: Vec<String>,
+}
+
+// Loads run on a few threads shared by every asset, not one thread each
+#[cfg(not(target_arch = "wasm32"))]
+const WORKERS: usize = 4;
+
+#[cfg(not(target_arch = "wasm32"))]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads taking loads off a shared queue. They stop once the pool is
+/// dropped and the queue runs dry.
+#[cfg(not(target_arch = "wasm32"))]
+struct WorkerPool {
+    jobs: Sender<Job>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WorkerPool {
+    fn new() -> Self {
+        let (jobs, receiver) = channel::<Job>();
+        let receiver = std::sync::Arc::new(std::sync::Mutex::new(receiver));
+        for i in 0..WORKERS {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("asset loader {}", i))
+                .spawn(move || loop {
+                    // The lock is only held while waiting, not while loading
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to spawn an asset loader thread");
+        }
+        Self { jobs }
+    }
+}
+
+/// Loads assets in the background and hands out handles right away. Loading
+/// the same path as the same asset type twice returns the same handle.
+pub struct AssetServer {
+    next_id: u64,
+    // Keyed by type too, a .glb is both a model and an animation clip
+    paths: HashMap<(TypeId, String), HandleId>,
+    entries: HashMap<HandleId, Entry>,
+    // One `HashMap<HandleId, T>` per asset type
+    storages: Vec<Box<dyn Any>>,
+    sender: Sender<Loaded>,
+    receiver: Receiver<Loaded>,
+    events: Vec<AssetEvent>,
+    // Started with the first load
+    #[cfg(not(target_arch = "wasm32"))]
+    workers: std::cell::OnceCell<WorkerPool>,
+}
+
+impl Default for AssetServer {
+    fn default() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            next_id: 0,
+            paths: HashMap::new(),
+            entries: HashMap::new(),
+            storages: Vec::new(),
+            sender,
+            receiver,
+            events: Vec::new(),
+            #[cfg(not(target_arch = "wasm32"))]
+            workers: std::cell::OnceCell::new(),
+        }
+    }
+}
+
+impl AssetServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts loading `path` unless it is already loaded or loading. Paths
+    /// that failed to load are tried again.
+    pub fn load<T: Asset>(&mut self, path: &str) -> Handle<T> {
+        let key = (TypeId::of::<T>(), path.to_string());
+        if let Some(&id) = self.paths.get(&key) {
+            if matches!(self.entries[&id].state, LoadState::Failed(_)) {
+                self.reload_id(id);
+            }
+            return Handle::new(id);
+        }
+
+        let id = HandleId(self.next_id);
+        self.next_id += 1;
+        self.paths.insert(key, id);
+        self.entries.insert(id, Entry {
+            path: path.to_string(),
+            state: LoadState::Loading,
+            uploader: upload_erased::<T>,
+            loader: AssetServer::spawn_load::<T>,
+            dependencies: Vec::new(),
+        });
+        self.spawn_load::<T>(id, path.to_string());
+
+        Handle::new(id)
+    }
+
+    pub fn load_model(&mut self, path: &str) -> Handle<model::Model> {
+        self.load(path)
+    }
+
+    /// Adds an asset that was created in code, e.g. a procedurally generated mesh.
+    pub fn add<T: Asset>(&mut self, asset: T) -> Handle<T> {
+        let id = HandleId(self.next_id);
+        self.next_id += 1;
+        self.entries.insert(id, Entry {
+            path: String::new(),
+            state: LoadState::Loaded,
+            uploader: upload_erased::<T>,
+            loader: AssetServer::spawn_load::<T>,
+            dependencies: Vec::new(),
+        });
+        self.storage_mut::<T>().insert(id, asset);
+        Handle::new(id)
+    }
+
+    pub fn get<T: Asset>(&self, handle: &Handle<T>) -> Option<&T> {
+        self.storage::<T>()?.get(&handle.id)
+    }
+
+    pub fn get_mut<T: Asset>(&mut self, handle: &Handle<T>) -> Option<&mut T> {
+        self.storage_mut::<T>().get_mut(&handle.id)
+    }
+
+    pub fn load_state<T>(&self, handle: &Handle<T>) -> LoadState {
+        self.entries
+            .get(&handle.id)
+            .map(|entry| entry.state.clone())
+            .unwrap_or(LoadState::NotLoaded)
+    }
+
+    pub fn path<T>(&self, handle: &Handle<T>) -> Option<&str> {
+        self.entries.get(&handle.id).map(|entry| entry.path.as_str())
+    }
+
+    /// `(finished, total)` over everything requested so far, failures count
+    /// as finished. Handy for loading screens.
+    pub fn progress(&self) -> (usize, usize) {
+        let finished = self.entries
+            .values()
+            .filter(|entry| entry.state != LoadState::Loading)
+            .count();
+        (finished, self.entries.len())
+    }
+
+    pub fn is_idle(&self) -> bool {
+        let (finished, total) = self.progress();
+        finished == total
+    }
+
+    /// Events produced by the last update.
+    pub fn events(&self) -> &[AssetEvent] {
+        &self.events
+    }
+
+    /// Loads every asset loaded from `path` again, keeping the current
+    /// versions until the new ones are ready.
+    pub fn reload(&mut self, path: &str) {
+        let ids = self.paths
+            .iter()
+            .filter(|((_, loaded), _)| loaded == path)
+            .map(|(_, &id)| id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            self.reload_id(id);
+        }
+    }
+
+    fn reload_id(&mut self, id: HandleId) {
+        let entry = self.entries.get_mut(&id).unwrap();
+        for dependency in &entry.dependencies {
+            resources::forget_preloaded(dependency);
+        }
+        // Failed assets get another try, loaded ones stay usable meanwhile
+        if entry.state != LoadState::Loaded {
+            entry.state = LoadState::Loading;
+        }
+        let (loader, path) = (entry.loader, entry.path.clone());
+        loader(self, id, path);
+    }
+
+    /// Every file a loaded asset depends on.
+    pub(crate) fn watched_files(&self) -> Vec<String> {
+        let mut files = Vec::new();
+        for entry in self.entries.values() {
+            for dependency in &entry.dependencies {
+                if !files.contains(dependency) {
+                    files.push(dependency.clone());
+                }
+            }
+        }
+        files
+    }
+
+    /// Reloads every asset built from one of `files`.
+    pub(crate) fn reload_changed(&mut self, files: &[String]) {
+        let ids = self.entries
+            .iter()
+            .filter(|(_, entry)| entry.state != LoadState::Loading)
+            .filter(|(_, entry)| entry.dependencies.iter().any(|dependency| files.contains(dependency)))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            log::info!("Reloading {}", self.entries[&id].path);
+            self.reload_id(id);
+        }
+    }
+
+    /// Uploads everything that finished loading since the last call. Called
+    /// once per frame by the engine.
+    pub fn update(&mut self, ctx: &UploadContext) {
+        self.events.clear();
+        while let Ok(loaded) = self.receiver.try_recv() {
+            let Some(entry) = self.entries.get_mut(&loaded.id) else {
+                continue;
+            };
+            if !loaded.dependencies.is_empty() {
+                entry.dependencies = loaded.dependencies;
+            }
+            let uploader = entry.uploader;
+            uploader(self, loaded.id, loaded.data, ctx);
+        }
+    }
+
+    fn finish(&mut self, id: HandleId, result: Result<(), AssetError>) {
+        let Some(entry) = self.entries.get_mut(&id) else {
+            return;
+        };
+        let path = entry.path.clone();
+        let reloaded = entry.state == LoadState::Loaded;
+        match result {
+            Ok(()) if reloaded => {
+                self.events.push(AssetEvent::Modified { id, path });
+            }
+            Ok(()) => {
+                entry.state = LoadState::Loaded;
+                self.events.push(AssetEvent::Loaded { id, path });
+            }
+            Err(e) => {
+                let error = e.to_string();
+                log::error!("Failed to load {}: {}", path, error);
+                if !reloaded {
+                    entry.state = LoadState::Failed(error.clone());
+                }
+                self.events.push(AssetEvent::Failed { id, path, error });
+            }
+        }
+    }
+
+    fn spawn_load<T: Asset>(&self, id: HandleId, path: String) {
+        let sender = self.sender.clone();
+        // The future is created where it runs, so it doesn't have to be Send
+        let load = move || async move { T::load_data(&path).await };
+        // The server may have been dropped in the meantime, so ignore send errors
+        let send = move |result: Result<T::Data, AssetError>, dependencies| {
+            sender.send(Loaded { id, data: Box::new(result), dependencies }).ok();
+        };
+
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                wasm_bindgen_futures::spawn_local(async move {
+                    send(load().await, Vec::new());
+                });
+            } else {
+                let job = move || {
+                    let (result, dependencies) = resources::record_reads(|| pollster::block_on(load()));
+                    send(result, dependencies);
+                };
+                self.workers.get_or_init(WorkerPool::new).jobs.send(Box::new(job)).ok();
+            }
+        }
+    }
+
+    fn storage<T: Asset>(&self) -> Option<&HashMap<HandleId, T>> {
+        self.storages
+            .iter()
+            .find_map(|storage| storage.downcast_ref::<HashMap<HandleId, T>>())
+    }
+
+    fn storage_mut<T: Asset>(&mut self) -> &mut HashMap<HandleId, T> {
+        let index = match self.storages.iter().position(|storage| storage.is::<HashMap<HandleId, T>>()) {
+            Some(index) => index,
+            None => {
+                self.storages.push(Box::new(HashMap::<HandleId, T>::new()));
+                self.storages.len() - 1
+            }
+        };
+        self.storages[index].downcast_mut().unwrap()
+    }
+}
+
+fn upload_erased<T: Asset>(server: &mut AssetServer, id: HandleId, data: Box<dyn Any + Send>, ctx: &UploadContext) {
+    let result = match data.downcast::<Result<T::Data, AssetError>>() {
+        Ok(result) => *result,
+        Err(_) => return,
+    };
+    let path = server.entries[&id].path.clone();
+    let result = result.and_then(|data| T::upload(data, &path, ctx)).map(|asset| {
+        server.storage_mut::<T>().insert(id, asset);
+    });
+    server.finish(id, result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FLAKY_LOADS: AtomicUsize = AtomicUsize::new(0);
+
+    // Paths starting with "missing" fail, "flaky" fails the first time only
+    struct Text(String);
+
+    impl Asset for Text {
+        type Data = String;
+
+        async fn load_data(path: &str) -> Result<String, AssetError> {
+            let fails = path.starts_with("missing")
+                || (path == "flaky" && FLAKY_LOADS.fetch_add(1, Ordering::SeqCst) == 0);
+            if fails {
+                return Err(AssetError::NotFound { path: path.to_string() });
+            }
+            Ok(path.to_uppercase())
+        }
+
+        fn upload(data: String, _path: &str, _ctx: &UploadContext) -> Result<Self, AssetError> {
+            Ok(Text(data))
+        }
+    }
+
+    // Runs updates until nothing is loading any more
+    fn finish_loading(server: &mut AssetServer, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor { label: None, entries: &[] });
+        let ctx = UploadContext { device, queue, texture_bind_group_layout: &layout };
+        let start = std::time::Instant::now();
+        while !server.is_idle() {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10), "loads didn't finish");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            server.update(&ctx);
+        }
+    }
+
+    #[test]
+    fn same_path_gives_the_same_handle() {
+        let mut server = AssetServer::new();
+        let a = server.load::<Text>("a");
+        let b = server.load::<Text>("b");
+        assert_eq!(server.load::<Text>("a"), a);
+        assert_ne!(a, b);
+        assert_eq!(server.path(&a), Some("a"));
+        assert_eq!(server.progress().1, 2);
+    }
+
+    // Same loader as Text, but another asset type
+    struct Shout(String);
+
+    impl Asset for Shout {
+        type Data = String;
+
+        async fn load_data(path: &str) -> Result<String, AssetError> {
+            Ok(format!("{}!", path.to_uppercase()))
+        }
+
+        fn upload(data: String, _path: &str, _ctx: &UploadContext) -> Result<Self, AssetError> {
+            Ok(Shout(data))
+        }
+    }
+
+    #[test]
+    fn one_path_loads_as_several_types() {
+        let Some((device, queue)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let mut server = AssetServer::new();
+        let text = server.load::<Text>("walker");
+        let shout = server.load::<Shout>("walker");
+        assert_ne!(text.id, shout.id);
+        assert_eq!(server.load::<Shout>("walker"), shout);
+        finish_loading(&mut server, &device, &queue);
+
+        assert_eq!(server.get(&text).unwrap().0, "WALKER");
+        assert_eq!(server.get(&shout).unwrap().0, "WALKER!");
+
+        // Reloading the path reloads both, they stay loaded meanwhile
+        server.reload("walker");
+        assert_eq!(server.progress(), (2, 2));
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor { label: None, entries: &[] });
+        let ctx = UploadContext { device: &device, queue: &queue, texture_bind_group_layout: &layout };
+        let mut modified = Vec::new();
+        let start = std::time::Instant::now();
+        while modified.len() < 2 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(10), "reloads didn't finish");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            server.update(&ctx);
+            for event in server.events() {
+                if let AssetEvent::Modified { id, .. } = event {
+                    modified.push(*id);
+                }
+            }
+        }
+        modified.sort();
+        assert_eq!(modified, [text.id, shout.id]);
+    }
+
+    #[test]
+    fn load_states_follow_the_loads() {
+        let Some((device, queue)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let mut server = AssetServer::new();
+        let unknown = Handle::<Text>::new(HandleId(99));
+        assert_eq!(server.load_state(&unknown), LoadState::NotLoaded);
+
+        let handles = (0..20).map(|i| server.load::<Text>(&format!("text{}", i))).collect::<Vec<_>>();
+        let missing = server.load::<Text>("missing");
+        assert_eq!(server.load_state(&missing), LoadState::Loading);
+        finish_loading(&mut server, &device, &queue);
+
+        for (i, handle) in handles.iter().enumerate() {
+            assert_eq!(server.load_state(handle), LoadState::Loaded);
+            assert_eq!(server.get(handle).unwrap().0, format!("TEXT{}", i));
+        }
+        assert!(matches!(server.load_state(&missing), LoadState::Failed(_)));
+        assert!(server.get(&missing).is_none());
+    }
+
+    #[test]
+    fn failed_loads_are_retried() {
+        let Some((device, queue)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let mut server = AssetServer::new();
+        let flaky = server.load::<Text>("flaky");
+        finish_loading(&mut server, &device, &queue);
+        assert!(matches!(server.load_state(&flaky), LoadState::Failed(_)));
+
+        assert_eq!(server.load::<Text>("flaky"), flaky);
+        assert_eq!(server.load_state(&flaky), LoadState::Loading);
+        finish_loading(&mut server, &device, &queue);
+        assert_eq!(server.load_state(&flaky), LoadState::Loaded);
+        assert!(server.events().contains(&AssetEvent::Loaded { id: flaky.id, path: String::from("flaky") }));
+    }
+}
@@ -44,6 +44,16 @@ impl Projection {
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
     }
+
+    /// Like `calc_matrix`, but only covering the depths between `znear`
+    /// and `zfar`, e.g. one shadow cascade.
+    pub fn calc_matrix_between(&self, znear: f32, zfar: f32) -> Matrix4<f32> {
+        OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, znear, zfar)
+    }
+
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
 }
 
 #[derive(Debug)]
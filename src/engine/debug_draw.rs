@@ -0,0 +1,368 @@
+use cgmath::prelude::*;
+use cgmath::Vector3;
+
+/// Lines drawn on top of the scene for one frame, from any system through
+/// the `DebugDraw` resource. Everything is cleared after every redraw, even
+/// one that couldn't present, so call these every frame for as long as they
+/// should show.
+pub struct DebugDraw {
+    /// Nothing is drawn while this is off, the calls are still cheap
+    pub enabled: bool,
+    /// Draws the point light's cube and markers for every `DirectionalLight`
+    /// and `SpotLight`
+    pub show_lights: bool,
+    vertices: Vec<DebugVertex>,
+}
+
+impl Default for DebugDraw {
+    /// Enabled in debug builds.
+    fn default() -> Self {
+        Self {
+            enabled: cfg!(debug_assertions),
+            show_lights: true,
+            vertices: Vec::new(),
+        }
+    }
+}
+
+impl DebugDraw {
+    pub fn line(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: [f32; 3]) {
+        if !self.enabled {
+            return;
+        }
+        self.vertices.push(DebugVertex { position: from.into(), color });
+        self.vertices.push(DebugVertex { position: to.into(), color });
+    }
+
+    /// The box between two opposite corners, aligned to the axes.
+    pub fn aabb(&mut self, min: Vector3<f32>, max: Vector3<f32>, color: [f32; 3]) {
+        let corner = |i: usize| {
+            Vector3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // Every pair of corners differing in exactly one axis
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corner(i), corner(i | axis), color);
+                }
+            }
+        }
+    }
+
+    pub fn circle(&mut self, center: Vector3<f32>, normal: Vector3<f32>, radius: f32, color: [f32; 3]) {
+        const SEGMENTS: usize = 32;
+        let (u, v) = perpendicular(normal.normalize());
+        let point = |i: usize| {
+            let angle = i as f32 / SEGMENTS as f32 * std::f32::consts::TAU;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Three circles around the axes.
+    pub fn sphere(&mut self, center: Vector3<f32>, radius: f32, color: [f32; 3]) {
+        self.circle(center, Vector3::unit_x(), radius, color);
+        self.circle(center, Vector3::unit_y(), radius, color);
+        self.circle(center, Vector3::unit_z(), radius, color);
+    }
+
+    pub fn arrow(&mut self, from: Vector3<f32>, to: Vector3<f32>, color: [f32; 3]) {
+        self.line(from, to, color);
+        let length = (to - from).magnitude();
+        if length <= f32::EPSILON {
+            return;
+        }
+        let direction = (to - from) / length;
+        let (u, v) = perpendicular(direction);
+        let head = length.min(1.0) * 0.2;
+        let base = to - direction * head;
+        for side in [u, -u, v, -v] {
+            self.line(to, base + side * head * 0.5, color);
+        }
+    }
+
+    /// A cone from its tip to the circle at its base.
+    pub fn cone(&mut self, apex: Vector3<f32>, base: Vector3<f32>, radius: f32, color: [f32; 3]) {
+        let axis = base - apex;
+        if axis.magnitude2() <= f32::EPSILON {
+            return;
+        }
+        self.circle(base, axis, radius, color);
+        let (u, v) = perpendicular(axis.normalize());
+        for side in [u, -u, v, -v] {
+            self.line(apex, base + side * radius, color);
+        }
+    }
+
+    /// A flat grid of `cells` by `cells` squares on the XZ plane, `size`
+    /// wide in total.
+    pub fn grid(&mut self, center: Vector3<f32>, size: f32, cells: u32, color: [f32; 3]) {
+        let half = size / 2.0;
+        for i in 0..=cells {
+            let offset = -half + size * i as f32 / cells.max(1) as f32;
+            self.line(center + Vector3::new(offset, 0.0, -half), center + Vector3::new(offset, 0.0, half), color);
+            self.line(center + Vector3::new(-half, 0.0, offset), center + Vector3::new(half, 0.0, offset), color);
+        }
+    }
+
+    /// A small star marking a light or any other point of interest.
+    pub fn light(&mut self, position: Vector3<f32>, color: [f32; 3]) {
+        const SIZE: f32 = 0.25;
+        for axis in [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()] {
+            self.line(position - axis * SIZE, position + axis * SIZE, color);
+        }
+        self.sphere(position, SIZE * 0.5, color);
+    }
+
+    /// Throws away everything drawn so far, done after every frame.
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    pub(crate) fn vertices(&self) -> &[DebugVertex] {
+        &self.vertices
+    }
+}
+
+// Two unit vectors perpendicular to `direction` and each other
+fn perpendicular(direction: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let other = if direction.y.abs() < 0.99 { Vector3::unit_y() } else { Vector3::unit_x() };
+    let u = direction.cross(other).normalize();
+    (u, direction.cross(u))
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 3],
+}
+
+impl DebugVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBS: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x3];
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &ATTRIBS,
+        }
+    }
+}
+
+// Room for a few hundred lines before the buffer has to grow
+const INITIAL_VERTICES: u64 = 1024;
+
+/// Draws all debug lines of a frame with a single line list draw call.
+pub(crate) struct DebugRenderer {
+    layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+    pipeline: wgpu::RenderPipeline,
+    // Reused every frame, replaced by a bigger one when the lines don't fit
+    buffer: wgpu::Buffer,
+    vertex_count: u32,
+}
+
+impl DebugRenderer {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug Pipeline Layout"),
+            bind_group_layouts: &[camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("debug.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("debug.wgsl").into()),
+        });
+        let pipeline = create_pipeline(device, &layout, &shader, color_format, depth_format, sample_count);
+        let buffer = create_buffer(device, INITIAL_VERTICES);
+        Self { layout, shader, color_format, depth_format, pipeline, buffer, vertex_count: 0 }
+    }
+
+    /// Rebuilds the pipeline for render targets with `sample_count` samples.
+    pub(crate) fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        self.pipeline = create_pipeline(
+            device,
+            &self.layout,
+            &self.shader,
+            self.color_format,
+            self.depth_format,
+            sample_count,
+        );
+    }
+
+    /// Copies the lines to draw next into the buffer, growing it if needed.
+    pub(crate) fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, vertices: &[DebugVertex]) {
+        let count = vertices.len() as u64;
+        if count * VERTEX_SIZE > self.buffer.size() {
+            self.buffer = create_buffer(device, count.next_power_of_two());
+        }
+        if !vertices.is_empty() {
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(vertices));
+        }
+        self.vertex_count = vertices.len() as u32;
+    }
+
+    /// Draws what was last uploaded, if anything.
+    pub(crate) fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if self.vertex_count == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..self.vertex_count as u64 * VERTEX_SIZE));
+        render_pass.draw(0..self.vertex_count, 0..1);
+    }
+}
+
+const VERTEX_SIZE: wgpu::BufferAddress = std::mem::size_of::<DebugVertex>() as wgpu::BufferAddress;
+
+fn create_buffer(device: &wgpu::Device, vertices: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Debug Line Buffer"),
+        size: vertices * VERTEX_SIZE,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Debug Pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[DebugVertex::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: color_format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::LineList,
+            ..Default::default()
+        },
+        // Hidden behind the scene like everything else, but lines don't
+        // hide each other
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: depth_format,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            ..Default::default()
+        },
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [f32; 3] = [1.0, 0.0, 0.0];
+
+    fn enabled() -> DebugDraw {
+        DebugDraw { enabled: true, ..Default::default() }
+    }
+
+    fn positions(debug: &DebugDraw) -> Vec<Vector3<f32>> {
+        debug.vertices().iter().map(|v| v.position.into()).collect()
+    }
+
+    #[test]
+    fn lines_are_vertex_pairs() {
+        let mut debug = enabled();
+        debug.line(Vector3::new(1.0, 2.0, 3.0), Vector3::new(-1.0, 0.0, 4.0), RED);
+        assert_eq!(positions(&debug), [Vector3::new(1.0, 2.0, 3.0), Vector3::new(-1.0, 0.0, 4.0)]);
+        assert!(debug.vertices().iter().all(|v| v.color == RED));
+
+        let mut disabled = DebugDraw { enabled: false, ..Default::default() };
+        disabled.line(Vector3::zero(), Vector3::unit_x(), RED);
+        disabled.light(Vector3::zero(), RED);
+        assert!(disabled.vertices().is_empty());
+    }
+
+    #[test]
+    fn boxes_have_twelve_edges_along_the_axes() {
+        let (min, max) = (Vector3::new(-1.0, 0.0, 2.0), Vector3::new(3.0, 1.0, 4.0));
+        let mut debug = enabled();
+        debug.aabb(min, max, RED);
+        let positions = positions(&debug);
+        assert_eq!(positions.len(), 24);
+        let is_corner = |p: &Vector3<f32>| (0..3).all(|axis| p[axis] == min[axis] || p[axis] == max[axis]);
+        assert!(positions.iter().all(is_corner));
+        for edge in positions.chunks_exact(2) {
+            let changed: Vec<_> = (0..3).filter(|&axis| edge[0][axis] != edge[1][axis]).collect();
+            assert_eq!(changed.len(), 1, "{:?}", edge);
+        }
+    }
+
+    #[test]
+    fn spheres_are_three_circles_at_the_radius() {
+        let center = Vector3::new(0.0, 5.0, -2.0);
+        let mut debug = enabled();
+        debug.sphere(center, 2.0, RED);
+        let positions = positions(&debug);
+        assert_eq!(positions.len(), 3 * 32 * 2);
+        assert!(positions.iter().all(|p| ((p - center).magnitude() - 2.0).abs() < 1e-5));
+        // One circle per axis, flat across it
+        for (circle, axis) in positions.chunks_exact(64).zip(0..3) {
+            assert!(circle.iter().all(|p| (p[axis] - center[axis]).abs() < 1e-5), "axis {}", axis);
+        }
+    }
+
+    #[test]
+    fn lights_are_a_star_in_a_sphere() {
+        let position = Vector3::new(2.0, 3.0, 4.0);
+        let mut debug = enabled();
+        debug.light(position, RED);
+        let positions = positions(&debug);
+        assert_eq!(positions.len(), 3 * 2 + 3 * 32 * 2);
+        for (line, axis) in positions[..6].chunks_exact(2).zip([Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()]) {
+            assert_eq!(line, [position - axis * 0.25, position + axis * 0.25]);
+        }
+        assert!(positions[6..].iter().all(|p| ((p - position).magnitude() - 0.125).abs() < 1e-5));
+    }
+
+    #[test]
+    fn clear_empties_the_buffer() {
+        let mut debug = enabled();
+        debug.aabb(Vector3::zero(), Vector3::new(1.0, 1.0, 1.0), RED);
+        debug.grid(Vector3::zero(), 10.0, 4, RED);
+        assert!(!debug.vertices().is_empty());
+        debug.clear();
+        assert!(debug.vertices().is_empty());
+        debug.line(Vector3::zero(), Vector3::unit_y(), RED);
+        assert_eq!(debug.vertices().len(), 2);
+    }
+}
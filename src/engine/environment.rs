@@ -0,0 +1,217 @@
+use anyhow::*;
+
+use crate::engine::texture::{self, Texture, TextureSettings};
+use crate::engine::sampler::SamplerSettings;
+
+/// The light coming from everywhere around the scene, used for the ambient
+/// part of the PBR shader: diffuse light from spherical harmonics and
+/// reflections from the cubemap's mip chain.
+pub struct Environment {
+    /// Sampled for reflections, rougher surfaces read from smaller mips
+    pub cube: Texture,
+    /// Irradiance as 9 RGB spherical harmonics coefficients, already
+    /// convolved with the cosine lobe and divided by pi
+    pub sh: [[f32; 3]; 9],
+    /// Scales everything the environment adds
+    pub intensity: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct EnvironmentUniform {
+    sh: [[f32; 4]; 9],
+    intensity: f32,
+    mip_count: f32,
+    // Uniforms are sized in multiples of 16 bytes
+    _padding: [f32; 2],
+}
+
+impl Environment {
+    /// An environment from an equirectangular panorama, usually an HDR
+    /// file. `face_size` is the size of the cubemap it's projected onto.
+    pub fn from_equirectangular(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        face_size: u32,
+    ) -> Result<Self> {
+        let faces = texture::equirectangular_to_cube_faces(img, face_size);
+        let sh = project_sh(&faces);
+        let cube = Texture::from_cube_faces(device, queue, &faces, Some("environment"), &Self::settings())?;
+        Ok(Self { cube, sh, intensity: 1.0 })
+    }
+
+    /// The same linear color from every direction, what the scene gets
+    /// until another environment is set.
+    pub fn uniform_color(device: &wgpu::Device, queue: &wgpu::Queue, color: [f32; 3]) -> Result<Self> {
+        let face = image::DynamicImage::ImageRgba32F(image::Rgba32FImage::from_pixel(
+            1,
+            1,
+            image::Rgba([color[0], color[1], color[2], 1.0]),
+        ));
+        let faces = std::array::from_fn(|_| face.clone());
+        let cube = Texture::from_cube_faces(device, queue, &faces, Some("environment"), &Self::settings())?;
+        // Only the constant band, which the shader multiplies by Y00 again
+        let mut sh = [[0.0; 3]; 9];
+        sh[0] = color.map(|c| c / SH_BASIS[0]);
+        Ok(Self { cube, sh, intensity: 1.0 })
+    }
+
+    fn settings() -> TextureSettings {
+        TextureSettings {
+            sampler: SamplerSettings::default().with_address_mode(wgpu::AddressMode::ClampToEdge),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn uniform(&self) -> EnvironmentUniform {
+        EnvironmentUniform {
+            sh: self.sh.map(|[r, g, b]| [r, g, b, 0.0]),
+            intensity: self.intensity,
+            mip_count: self.cube.texture.mip_level_count() as f32,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+// Constant factors of the first three bands of real spherical harmonics,
+// the shader repeats them
+const SH_BASIS: [f32; 9] = [
+    0.282095, 0.488603, 0.488603, 0.488603, 1.092548, 1.092548, 0.315392, 1.092548, 0.546274,
+];
+
+fn sh_basis([x, y, z]: [f32; 3]) -> [f32; 9] {
+    [
+        SH_BASIS[0],
+        SH_BASIS[1] * y,
+        SH_BASIS[2] * z,
+        SH_BASIS[3] * x,
+        SH_BASIS[4] * x * y,
+        SH_BASIS[5] * y * z,
+        SH_BASIS[6] * (3.0 * z * z - 1.0),
+        SH_BASIS[7] * x * z,
+        SH_BASIS[8] * (x * x - y * y),
+    ]
+}
+
+/// Projects the radiance of a cubemap onto spherical harmonics and turns it
+/// into irradiance (Ramamoorthi and Hanrahan). Big faces are subsampled,
+/// the low frequencies don't need every texel.
+fn project_sh(faces: &[image::DynamicImage; 6]) -> [[f32; 3]; 9] {
+    use std::f32::consts::PI;
+
+    let mut sh = [[0.0; 3]; 9];
+    let mut total_weight = 0.0;
+    for (face, img) in faces.iter().enumerate() {
+        let is_float = matches!(img.color(), image::ColorType::Rgb32F | image::ColorType::Rgba32F);
+        let pixels = img.to_rgba32f();
+        let size = pixels.width();
+        let step = (size / 64).max(1);
+        for y in (0..size).step_by(step as usize) {
+            for x in (0..size).step_by(step as usize) {
+                let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+                // Same orientation as equirectangular_to_cube_faces
+                let [dx, dy, dz] = match face {
+                    0 => [1.0, -v, -u],
+                    1 => [-1.0, -v, u],
+                    2 => [u, 1.0, v],
+                    3 => [u, -1.0, -v],
+                    4 => [u, -v, 1.0],
+                    _ => [-u, -v, -1.0],
+                };
+                let length = (dx * dx + dy * dy + dz * dz).sqrt();
+                // Solid angle of the texel, smaller towards the face's corners
+                let weight = (step * step) as f32 / (1.0 + u * u + v * v).powf(1.5);
+                total_weight += weight;
+
+                let texel = pixels.get_pixel(x, y).0;
+                let radiance: [f32; 3] = std::array::from_fn(|i| {
+                    // LDR faces hold sRGB colors
+                    if is_float { texel[i] } else { srgb_to_linear(texel[i]) }
+                });
+                for (coefficient, basis) in sh.iter_mut().zip(sh_basis([dx / length, dy / length, dz / length])) {
+                    for i in 0..3 {
+                        coefficient[i] += radiance[i] * basis * weight;
+                    }
+                }
+            }
+        }
+    }
+
+    // Normalize to the full sphere, then apply the cosine lobe per band and
+    // divide by pi so the shader can multiply with the albedo right away
+    let bands = [1.0, 2.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0, 0.25, 0.25, 0.25, 0.25, 0.25];
+    let scale = 4.0 * PI / total_weight;
+    for (coefficient, band) in sh.iter_mut().zip(bands) {
+        for c in coefficient.iter_mut() {
+            *c *= scale * band;
+        }
+    }
+    sh
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Irradiance over pi towards `normal`, what the shader computes
+    fn irradiance(sh: &[[f32; 3]; 9], normal: [f32; 3]) -> [f32; 3] {
+        let basis = sh_basis(normal);
+        std::array::from_fn(|i| sh.iter().zip(basis).map(|(coefficient, basis)| coefficient[i] * basis).sum())
+    }
+
+    fn close(a: [f32; 3], b: [f32; 3]) -> bool {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() < 0.01)
+    }
+
+    const NORMALS: [[f32; 3]; 7] = [
+        [1.0, 0.0, 0.0],
+        [-1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, -1.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [0.0, 0.0, -1.0],
+        [0.57735, 0.57735, 0.57735],
+    ];
+
+    #[test]
+    fn constant_environments_reproduce_their_color() {
+        let color = [0.2, 0.5, 3.0];
+        let face = image::DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(8, 8, image::Rgb(color)));
+        let sh = project_sh(&std::array::from_fn(|_| face.clone()));
+        for normal in NORMALS {
+            assert!(close(irradiance(&sh, normal), color), "{:?} gives {:?}", normal, irradiance(&sh, normal));
+        }
+        // Nothing in the higher bands
+        assert!(sh[1..].iter().flatten().all(|c| c.abs() < 0.01), "{:?}", sh);
+    }
+
+    #[test]
+    fn ldr_faces_are_linearized() {
+        // 188 in sRGB is about half in linear light
+        let face = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(130, 130, image::Rgba([188, 188, 188, 255])));
+        let sh = project_sh(&std::array::from_fn(|_| face.clone()));
+        assert!(close(irradiance(&sh, [0.0, 1.0, 0.0]), [0.5; 3]), "{:?}", irradiance(&sh, [0.0, 1.0, 0.0]));
+    }
+
+    #[test]
+    fn light_from_above_lights_upward_normals() {
+        let black = image::DynamicImage::ImageRgb32F(image::Rgb32FImage::new(8, 8));
+        let white = image::DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(8, 8, image::Rgb([1.0; 3])));
+        let sh = project_sh(&std::array::from_fn(|face| if face == 2 { white.clone() } else { black.clone() }));
+        let up = irradiance(&sh, [0.0, 1.0, 0.0])[0];
+        let side = irradiance(&sh, [1.0, 0.0, 0.0])[0];
+        let down = irradiance(&sh, [0.0, -1.0, 0.0])[0];
+        assert!(up > side && side > down, "up {} side {} down {}", up, side, down);
+        assert!(down.abs() < 0.05);
+    }
+}
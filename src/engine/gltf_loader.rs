@@ -0,0 +1,785 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::Engine;
+use cgmath::prelude::*;
+
+use crate::engine::animation::{AnimationClip, Bone, Channel, Interpolation, Keyframes, Skin, Transform, MAX_JOINTS};
+use crate::engine::model::{self, MaterialData, MaterialUniform, MeshData, ModelData, ModelVertex, TextureData};
+use crate::engine::{gpu_image, resources};
+use crate::engine::sampler::SamplerSettings;
+use crate::engine::pipeline::{AlphaMode, MaterialPipeline};
+use crate::engine::asset_error::AssetError;
+
+/// A node of the glTF scene graph. Transforms are relative to the parent.
+pub struct NodeData {
+    pub name: String,
+    /// Index into `SceneData::nodes`, parents always come before their children
+    pub parent: Option<usize>,
+    pub translation: cgmath::Vector3<f32>,
+    pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
+    /// Index into `SceneData::meshes`
+    pub mesh: Option<usize>,
+    /// Index into `SceneData::skins`, skinned meshes ignore the node's transform
+    pub skin: Option<usize>,
+}
+
+impl NodeData {
+    fn matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.translation)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+/// A glTF file with its node hierarchy intact. Meshes are in their own space,
+/// `flatten` bakes the node transforms in to get a single `ModelData`.
+pub struct SceneData {
+    /// One entry per glTF mesh, with one `MeshData` per primitive
+    pub meshes: Vec<Vec<MeshData>>,
+    pub materials: Vec<MaterialData>,
+    pub nodes: Vec<NodeData>,
+    pub skins: Vec<Skin>,
+}
+
+impl SceneData {
+    /// Each node's transform relative to the scene root.
+    pub fn world_matrices(&self) -> Vec<cgmath::Matrix4<f32>> {
+        let mut matrices: Vec<cgmath::Matrix4<f32>> = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let parent = node.parent.map(|p| matrices[p]).unwrap_or(cgmath::Matrix4::identity());
+            matrices.push(parent * node.matrix());
+        }
+        matrices
+    }
+
+    /// Merges every mesh in the scene into one model, placed where its node
+    /// puts it. A model only has one skin, meshes using any other skin are
+    /// left out, `World::spawn_gltf` keeps them apart instead.
+    pub fn flatten(mut self) -> ModelData {
+        let matrices = self.world_matrices();
+        let skin = self.nodes.iter().find_map(|node| node.skin);
+        let mut meshes = Vec::new();
+        for (node, matrix) in self.nodes.iter().zip(matrices) {
+            let Some(mesh) = node.mesh else {
+                continue;
+            };
+            if node.skin.is_some() && node.skin != skin {
+                log::warn!("Leaving out {}, it uses a second skin", node.name);
+                continue;
+            }
+            for primitive in &self.meshes[mesh] {
+                let mut primitive = primitive.clone();
+                // Joints already place skinned meshes
+                if node.skin.is_none() {
+                    primitive.transform(matrix);
+                }
+                primitive.compute_tangents();
+                meshes.push(primitive);
+            }
+        }
+        ModelData {
+            meshes,
+            materials: self.materials,
+            skin: skin.map(|skin| self.skins.swap_remove(skin)),
+        }
+    }
+
+    /// Uploads one model per node that has a mesh, all sharing the same
+    /// materials. The node's rotation and translation end up in the model's
+    /// instance so it can be moved around, scale is baked into the vertices.
+    pub fn upload_nodes(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        label: &str,
+    ) -> Result<Vec<Option<model::Model>>, AssetError> {
+        let materials = model::upload_materials(&self.materials, device, queue, layout)?;
+
+        // Instances can't hold a full matrix, so compose the parts. Exact
+        // unless a parent has non uniform scale and a rotated child.
+        let mut world: Vec<(cgmath::Vector3<f32>, cgmath::Quaternion<f32>, cgmath::Vector3<f32>)> = Vec::new();
+        let mut models = Vec::new();
+        for node in &self.nodes {
+            let (translation, rotation, scale) = match node.parent {
+                Some(p) => {
+                    let (t, r, s) = world[p];
+                    (
+                        t + r.rotate_vector(s.mul_element_wise(node.translation)),
+                        r * node.rotation,
+                        s.mul_element_wise(node.scale),
+                    )
+                }
+                None => (node.translation, node.rotation, node.scale),
+            };
+            world.push((translation, rotation, scale));
+
+            let Some(mesh) = node.mesh else {
+                models.push(None);
+                continue;
+            };
+            let model = match node.skin {
+                Some(skin) => {
+                    let meshes = gpu_meshes(&self.meshes[mesh], cgmath::Vector3::new(1.0, 1.0, 1.0), device, label)?;
+                    let mut model = model::Model::new(device, meshes, materials.clone(), vec![model::Instance::default()]);
+                    model.skin = Some(self.skins[skin].clone());
+                    model
+                }
+                None => {
+                    let meshes = gpu_meshes(&self.meshes[mesh], scale, device, label)?;
+                    let instance = model::Instance { position: translation, rotation };
+                    model::Model::new(device, meshes, materials.clone(), vec![instance])
+                }
+            };
+            models.push(Some(model));
+        }
+        Ok(models)
+    }
+}
+
+fn gpu_meshes(
+    primitives: &[MeshData],
+    scale: cgmath::Vector3<f32>,
+    device: &wgpu::Device,
+    label: &str,
+) -> Result<Vec<model::Mesh>, AssetError> {
+    let scale = cgmath::Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
+    crate::engine::asset_error::gpu_scope(device, label, || {
+        primitives
+            .iter()
+            .map(|primitive| {
+                let mut primitive = primitive.clone();
+                primitive.transform(scale);
+                primitive.compute_tangents();
+                primitive.upload(device, label)
+            })
+            .collect()
+    })
+}
+
+// The parsed file and the contents of all its buffers
+async fn load_document(file_name: &str) -> Result<(gltf::Gltf, Vec<Vec<u8>>), AssetError> {
+    let data = resources::load_binary(file_name).await?;
+    let gltf = gltf::Gltf::from_slice(&data).map_err(|e| gltf_error(file_name, e))?;
+
+    let mut buffers = Vec::new();
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.clone().ok_or_else(|| {
+                AssetError::parse(file_name, None, "buffer refers to a missing GLB binary chunk")
+            })?,
+            gltf::buffer::Source::Uri(uri) => load_uri(file_name, uri).await?,
+        };
+        if data.len() < buffer.length() {
+            return Err(AssetError::parse(
+                file_name,
+                None,
+                format!("buffer {} is {} bytes, expected {}", buffer.index(), data.len(), buffer.length()),
+            ));
+        }
+        buffers.push(data);
+    }
+    Ok((gltf, buffers))
+}
+
+/// Loads a `.gltf` (with external or embedded buffers) or `.glb` file.
+pub async fn load_gltf_scene(file_name: &str) -> Result<SceneData, AssetError> {
+    let (gltf, buffers) = load_document(file_name).await?;
+
+    // Images are only fetched here, they get decoded once a material uses them
+    let mut images = Vec::new();
+    for image in gltf.images() {
+        let (label, data, embedded) = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let name = image.name().map(String::from).unwrap_or_else(|| image.index().to_string());
+                let label = format!("{}#{}", file_name, name);
+                let data = buffers[view.buffer().index()]
+                    .get(view.offset()..view.offset() + view.length())
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| AssetError::parse(&label, None, "image data is outside of its buffer"));
+                (label, data, true)
+            }
+            gltf::image::Source::Uri { uri, .. } => (relative_path(file_name, uri), load_uri(file_name, uri).await, false),
+        };
+        images.push((label, Some(data), embedded));
+    }
+
+    let mut decoded = HashMap::new();
+    let mut texture = |material: &gltf::Material, texture: gltf::Texture| -> Result<TextureData, AssetError> {
+        let index = texture.source().index();
+        let (label, data, embedded) = &mut images[index];
+        if let Some(data) = data.take() {
+            let embedded = *embedded;
+            let image = data
+                .and_then(|data| {
+                    let image = gpu_image::decode(label, &data)?;
+                    Ok((image, embedded.then(|| Arc::from(data))))
+                })
+                .map_err(|e| AssetError::MissingTexture {
+                    path: file_name.to_string(),
+                    material: material.name().unwrap_or_default().to_string(),
+                    texture: label.clone(),
+                    source: Box::new(e),
+                })?;
+            decoded.insert(index, image);
+        }
+        let (image, encoded) = &decoded[&index];
+        Ok(TextureData {
+            label: label.clone(),
+            image: image.clone(),
+            encoded: encoded.clone(),
+        })
+    };
+
+    let mut materials = Vec::new();
+    for material in gltf.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let base_color_texture = pbr
+            .base_color_texture()
+            .map(|info| texture(&material, info.texture()))
+            .transpose()?;
+        let metallic_roughness_texture = pbr
+            .metallic_roughness_texture()
+            .map(|info| texture(&material, info.texture()))
+            .transpose()?;
+        let normal_texture = material
+            .normal_texture()
+            .map(|info| texture(&material, info.texture()))
+            .transpose()?;
+        let occlusion_texture = material
+            .occlusion_texture()
+            .map(|info| texture(&material, info.texture()))
+            .transpose()?;
+        let emissive_texture = material
+            .emissive_texture()
+            .map(|info| texture(&material, info.texture()))
+            .transpose()?;
+
+        // One sampler per material, the base color's matters most
+        let sampler = pbr
+            .base_color_texture()
+            .map(|info| info.texture())
+            .or_else(|| material.normal_texture().map(|info| info.texture()))
+            .map(|texture| sampler_settings(&texture.sampler()))
+            .unwrap_or_default();
+
+        materials.push(MaterialData {
+            name: material
+                .name()
+                .map(String::from)
+                .unwrap_or_else(|| format!("material{}", material.index().unwrap_or_default())),
+            base_color_texture,
+            metallic_roughness_texture,
+            normal_texture,
+            occlusion_texture,
+            emissive_texture,
+            uniform: MaterialUniform {
+                base_color: pbr.base_color_factor(),
+                emissive: material.emissive_factor(),
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                occlusion_strength: material.occlusion_texture().map_or(1.0, |info| info.strength()),
+                normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+                alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+            },
+            sampler,
+            pipeline: {
+                let pipeline = MaterialPipeline::default().with_alpha_mode(match material.alpha_mode() {
+                    gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                    gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                    gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+                });
+                if material.double_sided() {
+                    pipeline.double_sided()
+                } else {
+                    pipeline
+                }
+            },
+        });
+    }
+
+    // Primitives without a material share a plain white one
+    let material_count = materials.len();
+    let mut uses_fallback = false;
+
+    let mut meshes = Vec::new();
+    for mesh in gltf.meshes() {
+        let mut primitives = Vec::new();
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!("Skipping {:?} primitive in {}, only triangles are supported", primitive.mode(), file_name);
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let Some(positions) = reader.read_positions() else {
+                continue;
+            };
+
+            let mut vertices = positions
+                .map(|position| ModelVertex {
+                    position,
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>();
+            if let Some(tex_coords) = reader.read_tex_coords(0) {
+                for (v, tex_coords) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+                    v.tex_coords = tex_coords;
+                }
+            }
+            let has_normals = match reader.read_normals() {
+                Some(normals) => {
+                    for (v, normal) in vertices.iter_mut().zip(normals) {
+                        v.normal = normal;
+                    }
+                    true
+                }
+                None => false,
+            };
+            if let (Some(joints), Some(weights)) = (reader.read_joints(0), reader.read_weights(0)) {
+                for ((v, joints), weights) in vertices.iter_mut().zip(joints.into_u16()).zip(weights.into_f32()) {
+                    v.joints = joints.map(u32::from);
+                    v.weights = weights;
+                }
+                if vertices.iter().any(|v| v.joints.iter().any(|&joint| joint as usize >= MAX_JOINTS)) {
+                    return Err(AssetError::UnsupportedFormat {
+                        path: file_name.to_string(),
+                        reason: format!("mesh {} uses more than {} joints", mesh.index(), MAX_JOINTS),
+                    });
+                }
+            }
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..vertices.len() as u32).collect(),
+            };
+            if let Some(&index) = indices.iter().find(|&&index| index as usize >= vertices.len()) {
+                return Err(AssetError::parse(
+                    file_name,
+                    None,
+                    format!("mesh {} references vertex {} but only has {}", mesh.index(), index, vertices.len()),
+                ));
+            }
+
+            let material = match primitive.material().index() {
+                Some(index) if index < material_count => index,
+                _ => {
+                    uses_fallback = true;
+                    material_count
+                }
+            };
+
+            let mut mesh_data = MeshData {
+                name: mesh.name().map(String::from).unwrap_or_else(|| file_name.to_string()),
+                vertices,
+                indices,
+                material,
+            };
+            if !has_normals {
+                mesh_data.compute_flat_normals();
+            }
+            primitives.push(mesh_data);
+        }
+        meshes.push(primitives);
+    }
+
+    if uses_fallback {
+        materials.push(MaterialData::fallback());
+    }
+
+    // Only the nodes of the scene that would be shown, parents first
+    let mut nodes = Vec::new();
+    let scene = gltf.default_scene().or_else(|| gltf.scenes().next());
+    let mut stack = scene
+        .map(|scene| scene.nodes().map(|node| (node, None)).collect::<Vec<_>>())
+        .unwrap_or_default();
+    // Popped from the back, so reverse to keep the file's order
+    stack.reverse();
+    while let Some((node, parent)) = stack.pop() {
+        let (translation, rotation, scale) = node.transform().decomposed();
+        let index = nodes.len();
+        nodes.push(NodeData {
+            name: node_name(&node),
+            parent,
+            translation: translation.into(),
+            rotation: quaternion(rotation),
+            scale: scale.into(),
+            mesh: node.mesh().map(|mesh| mesh.index()),
+            skin: node.skin().map(|skin| skin.index()),
+        });
+        let children = node.children().map(|child| (child, Some(index))).collect::<Vec<_>>();
+        stack.extend(children.into_iter().rev());
+    }
+
+    // Animations are loaded on their own, see `load_animation`
+    let skins = load_skins(file_name, &gltf, &buffers)?;
+
+    Ok(SceneData { meshes, materials, nodes, skins })
+}
+
+fn node_name(node: &gltf::Node) -> String {
+    node.name().map(String::from).unwrap_or_else(|| format!("node{}", node.index()))
+}
+
+fn load_skins(file_name: &str, gltf: &gltf::Gltf, buffers: &[Vec<u8>]) -> Result<Vec<Skin>, AssetError> {
+    let mut parents = HashMap::new();
+    for node in gltf.nodes() {
+        for child in node.children() {
+            parents.insert(child.index(), node.index());
+        }
+    }
+    let depth = |mut node: usize| {
+        let mut depth = 0;
+        while let Some(&parent) = parents.get(&node) {
+            node = parent;
+            depth += 1;
+        }
+        depth
+    };
+
+    let nodes = gltf.nodes().collect::<Vec<_>>();
+    let mut skins = Vec::new();
+    for skin in gltf.skins() {
+        let joints = skin.joints().map(|joint| joint.index()).collect::<Vec<_>>();
+        if joints.len() > MAX_JOINTS {
+            return Err(AssetError::UnsupportedFormat {
+                path: file_name.to_string(),
+                reason: format!("skin {} has {} joints, at most {} are supported", skin.index(), joints.len(), MAX_JOINTS),
+            });
+        }
+
+        // The joints and everything above them, sorted so parents come first
+        let mut bone_nodes = Vec::new();
+        for &joint in &joints {
+            let mut node = Some(joint);
+            while let Some(index) = node.filter(|index| !bone_nodes.contains(index)) {
+                bone_nodes.push(index);
+                node = parents.get(&index).copied();
+            }
+        }
+        bone_nodes.sort_by_key(|&node| depth(node));
+
+        let bones = bone_nodes
+            .iter()
+            .map(|&index| {
+                let (translation, rotation, scale) = nodes[index].transform().decomposed();
+                Bone {
+                    name: node_name(&nodes[index]),
+                    parent: parents.get(&index).and_then(|parent| bone_nodes.iter().position(|node| node == parent)),
+                    transform: Transform {
+                        translation: translation.into(),
+                        rotation: quaternion(rotation),
+                        scale: scale.into(),
+                    },
+                }
+            })
+            .collect();
+
+        let mut inverse_bind_matrices = skin
+            .reader(|buffer| Some(&buffers[buffer.index()]))
+            .read_inverse_bind_matrices()
+            .map(|matrices| matrices.map(cgmath::Matrix4::from).collect::<Vec<_>>())
+            .unwrap_or_default();
+        inverse_bind_matrices.resize(joints.len(), cgmath::Matrix4::identity());
+
+        skins.push(Skin {
+            joints: joints
+                .iter()
+                .map(|joint| bone_nodes.iter().position(|node| node == joint).unwrap_or_default())
+                .collect(),
+            bones,
+            inverse_bind_matrices,
+        });
+    }
+    Ok(skins)
+}
+
+fn load_animations(gltf: &gltf::Gltf, buffers: &[Vec<u8>]) -> Vec<AnimationClip> {
+    use gltf::animation::util::ReadOutputs;
+
+    gltf.animations()
+        .map(|animation| {
+            let mut duration: f32 = 0.0;
+            let mut channels = Vec::new();
+            for channel in animation.channels() {
+                let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                let (Some(inputs), Some(outputs)) = (reader.read_inputs(), reader.read_outputs()) else {
+                    continue;
+                };
+                let keyframes = match outputs {
+                    ReadOutputs::Translations(values) => Keyframes::Translation(values.map(Into::into).collect()),
+                    ReadOutputs::Rotations(values) => Keyframes::Rotation(values.into_f32().map(quaternion).collect()),
+                    ReadOutputs::Scales(values) => Keyframes::Scale(values.map(Into::into).collect()),
+                    ReadOutputs::MorphTargetWeights(_) => {
+                        log::warn!("Skipping morph target channel of animation {}, morph targets aren't supported", animation.index());
+                        continue;
+                    }
+                };
+                let times = inputs.collect::<Vec<_>>();
+                duration = times.last().copied().unwrap_or_default().max(duration);
+                channels.push(Channel {
+                    target: node_name(&channel.target().node()),
+                    interpolation: match channel.sampler().interpolation() {
+                        gltf::animation::Interpolation::Step => Interpolation::Step,
+                        gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                        gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+                    },
+                    times,
+                    keyframes,
+                });
+            }
+            AnimationClip {
+                name: animation.name().map(String::from).unwrap_or_else(|| animation.index().to_string()),
+                duration,
+                channels,
+            }
+        })
+        .collect()
+}
+
+/// Loads one clip from a glTF file, `path` is `"file.glb#name"` or
+/// `"file.glb#index"`. Without a `#` the first clip is used.
+pub async fn load_animation(path: &str) -> Result<AnimationClip, AssetError> {
+    let (file_name, clip) = path.split_once('#').unwrap_or((path, "0"));
+    let (gltf, buffers) = load_document(file_name).await?;
+    let mut animations = load_animations(&gltf, &buffers);
+    let index = animations
+        .iter()
+        .position(|animation| animation.name == clip)
+        .or_else(|| clip.parse::<usize>().ok().filter(|&index| index < animations.len()))
+        .ok_or_else(|| AssetError::NotFound { path: path.to_string() })?;
+    Ok(animations.swap_remove(index))
+}
+
+// glTF stores quaternions as xyzw, cgmath wants w first
+fn quaternion(q: [f32; 4]) -> cgmath::Quaternion<f32> {
+    cgmath::Quaternion::new(q[3], q[0], q[1], q[2])
+}
+
+pub async fn load_gltf_data(file_name: &str) -> Result<ModelData, AssetError> {
+    Ok(load_gltf_scene(file_name).await?.flatten())
+}
+
+fn sampler_settings(sampler: &gltf::texture::Sampler) -> SamplerSettings {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    };
+    let mut settings = SamplerSettings {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        ..Default::default()
+    };
+    // Filters left out are up to the renderer, keep ours then
+    if let Some(MagFilter::Nearest) = sampler.mag_filter() {
+        settings.mag_filter = wgpu::FilterMode::Nearest;
+    }
+    if let Some(filter) = sampler.min_filter() {
+        use wgpu::FilterMode::{Linear, Nearest};
+        (settings.min_filter, settings.mipmap_filter) = match filter {
+            MinFilter::Nearest | MinFilter::NearestMipmapNearest => (Nearest, Nearest),
+            MinFilter::Linear | MinFilter::LinearMipmapNearest => (Linear, Nearest),
+            MinFilter::NearestMipmapLinear => (Nearest, Linear),
+            MinFilter::LinearMipmapLinear => (Linear, Linear),
+        };
+    }
+    settings
+}
+
+fn gltf_error(file_name: &str, error: gltf::Error) -> AssetError {
+    match error {
+        gltf::Error::Io(e) => AssetError::io(file_name, e),
+        e => AssetError::parse(file_name, None, e),
+    }
+}
+
+// Buffers and images are either embedded as base64 data URIs or stored next
+// to the glTF file
+async fn load_uri(file_name: &str, uri: &str) -> Result<Vec<u8>, AssetError> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let Some((_, base64)) = data.split_once(";base64,") else {
+            return Err(AssetError::UnsupportedFormat {
+                path: file_name.to_string(),
+                reason: String::from("only base64 data URIs are supported"),
+            });
+        };
+        return base64::engine::general_purpose::STANDARD
+            .decode(base64)
+            .map_err(|e| AssetError::parse(file_name, None, e));
+    }
+    resources::load_binary(&relative_path(file_name, uri)).await
+}
+
+fn relative_path(file_name: &str, uri: &str) -> String {
+    let uri = percent_decode(uri);
+    match file_name.rfind('/') {
+        Some(end) => format!("{}/{}", &file_name[..end], uri),
+        None => uri,
+    }
+}
+
+// URIs may escape spaces and such as %20
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::asset_io::MemoryAssetIo;
+
+    // A triangle in the XY plane, then u16 indices for it
+    fn triangle_buffer() -> Vec<u8> {
+        let positions: [f32; 9] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        let mut buffer = bytemuck::cast_slice::<f32, u8>(&positions).to_vec();
+        buffer.extend(bytemuck::cast_slice::<u16, u8>(&[0, 1, 2, 0]));
+        buffer
+    }
+
+    // Two roots, the first with a child that has a child of its own and a
+    // second child after it. The buffer sits next to the file, with a space
+    // in its name.
+    const SCENE: &str = r#"{
+        "asset": { "version": "2.0" },
+        "buffers": [{ "uri": "bin%20files/triangle.bin", "byteLength": 44 }],
+        "bufferViews": [
+            { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 6 }
+        ],
+        "accessors": [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR" }
+        ],
+        "materials": [
+            { "name": "leaf", "alphaMode": "MASK", "alphaCutoff": 0.3, "doubleSided": true },
+            {
+                "name": "glass",
+                "alphaMode": "BLEND",
+                "pbrMetallicRoughness": { "baseColorFactor": [1, 1, 1, 0.5], "metallicFactor": 0.2, "roughnessFactor": 0.7 }
+            },
+            {}
+        ],
+        "meshes": [
+            {
+                "name": "pair",
+                "primitives": [
+                    { "attributes": { "POSITION": 0 }, "indices": 1, "material": 0 },
+                    { "attributes": { "POSITION": 0 }, "material": 1 }
+                ]
+            },
+            { "primitives": [{ "attributes": { "POSITION": 0 } }] }
+        ],
+        "nodes": [
+            { "name": "root", "translation": [1, 0, 0], "children": [1, 2] },
+            { "name": "child", "translation": [0, 2, 0], "mesh": 0, "children": [4] },
+            { "name": "sibling" },
+            { "mesh": 1 },
+            { "name": "grandchild" }
+        ],
+        "scenes": [{ "nodes": [0, 3] }],
+        "scene": 0
+    }"#;
+
+    fn load_scene() -> SceneData {
+        resources::mount(
+            MemoryAssetIo::new()
+                .with_file("gltf_tests/scene.gltf", SCENE)
+                .with_file("gltf_tests/bin files/triangle.bin", triangle_buffer()),
+        );
+        pollster::block_on(load_gltf_scene("gltf_tests/scene.gltf")).unwrap()
+    }
+
+    #[test]
+    fn nodes_come_parents_first() {
+        let scene = load_scene();
+        let nodes = scene.nodes.iter().map(|node| (node.name.as_str(), node.parent)).collect::<Vec<_>>();
+        assert_eq!(
+            nodes,
+            [("root", None), ("child", Some(0)), ("grandchild", Some(1)), ("sibling", Some(0)), ("node3", None)]
+        );
+        assert_eq!(scene.nodes[1].mesh, Some(0));
+        assert_eq!(scene.nodes[4].mesh, Some(1));
+
+        let matrices = scene.world_matrices();
+        assert_eq!(matrices[2].w.truncate(), cgmath::vec3(1.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn every_primitive_becomes_a_mesh() {
+        let scene = load_scene();
+        assert_eq!(scene.meshes.len(), 2);
+        let pair = &scene.meshes[0];
+        assert_eq!(pair.len(), 2);
+        assert_eq!(pair.iter().map(|mesh| mesh.material).collect::<Vec<_>>(), [0, 1]);
+        // Without indices the vertices are used in order
+        assert_eq!(pair[1].indices, [0, 1, 2]);
+        // Without normals the triangle's own is used
+        assert!(pair[0].vertices.iter().all(|v| v.normal == [0.0, 0.0, 1.0]));
+
+        // The mesh without a material gets the fallback, added at the end
+        assert_eq!(scene.meshes[1][0].material, 3);
+        assert_eq!(scene.materials.len(), 4);
+
+        // Flattening places the meshes where their nodes are
+        let model = scene.flatten();
+        assert_eq!(model.meshes.len(), 3);
+        assert_eq!(model.meshes[0].vertices[0].position, [1.0, 2.0, 0.0]);
+        assert_eq!(model.meshes[2].vertices[0].position, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn materials_keep_their_alpha_modes() {
+        let scene = load_scene();
+        let [leaf, glass, plain, _] = &scene.materials[..] else {
+            panic!("expected 4 materials");
+        };
+        assert_eq!(leaf.name, "leaf");
+        assert_eq!(leaf.pipeline.alpha_mode, AlphaMode::Mask);
+        assert_eq!(leaf.pipeline.cull_mode, None);
+        assert_eq!(leaf.uniform.alpha_cutoff, 0.3);
+
+        assert_eq!(glass.pipeline.alpha_mode, AlphaMode::Blend);
+        assert_eq!(glass.pipeline.cull_mode, Some(wgpu::Face::Back));
+        assert_eq!(glass.uniform.base_color, [1.0, 1.0, 1.0, 0.5]);
+        assert_eq!((glass.uniform.metallic, glass.uniform.roughness), (0.2, 0.7));
+
+        assert_eq!(plain.name, "material2");
+        assert_eq!(plain.pipeline.alpha_mode, AlphaMode::Opaque);
+        assert_eq!(plain.uniform.alpha_cutoff, 0.5);
+    }
+
+    #[test]
+    fn uris_are_relative_and_percent_decoded() {
+        assert_eq!(percent_decode("my%20model%2Fbody.bin"), "my model/body.bin");
+        assert_eq!(percent_decode("plain.bin"), "plain.bin");
+        // Broken escapes are left as they are
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%C3%A9t%C3%A9.png"), "été.png");
+
+        assert_eq!(relative_path("models/tree.gltf", "bark%20color.png"), "models/bark color.png");
+        assert_eq!(relative_path("tree.gltf", "bark.png"), "bark.png");
+        assert_eq!(relative_path("a/b/tree.gltf", "textures/bark.png"), "a/b/textures/bark.png");
+    }
+}
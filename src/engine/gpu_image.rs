@@ -0,0 +1,474 @@
+use std::io::Read;
+
+use image::RgbaImage;
+
+use crate::engine::asset_error::AssetError;
+
+/// Image data that's already in a GPU format, as stored in KTX2 and DDS
+/// files. Compressed formats the device can't sample are decompressed on
+/// the CPU when uploading, see `Texture::from_gpu_image`.
+#[derive(Debug, Clone)]
+pub struct GpuImage {
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    /// Mip levels, largest first
+    pub levels: Vec<Vec<u8>>,
+}
+
+/// A texture's pixels, either decoded by the `image` crate or kept in the
+/// format they were stored in.
+#[derive(Debug, Clone)]
+pub enum ImageData {
+    Decoded(image::DynamicImage),
+    Gpu(GpuImage),
+}
+
+impl ImageData {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            ImageData::Decoded(image) => (image.width(), image.height()),
+            ImageData::Gpu(image) => (image.width, image.height),
+        }
+    }
+}
+
+impl From<image::DynamicImage> for ImageData {
+    fn from(image: image::DynamicImage) -> Self {
+        ImageData::Decoded(image)
+    }
+}
+
+const KTX2_MAGIC: &[u8] = b"\xABKTX 20\xBB\r\n\x1A\n";
+const DDS_MAGIC: &[u8] = b"DDS ";
+
+/// Decodes any supported texture file, telling KTX2 and DDS apart from
+/// everything the `image` crate handles by their magic bytes.
+pub fn decode(label: &str, data: &[u8]) -> Result<ImageData, AssetError> {
+    if data.starts_with(KTX2_MAGIC) {
+        GpuImage::from_ktx2(label, data).map(ImageData::Gpu)
+    } else if data.starts_with(DDS_MAGIC) {
+        GpuImage::from_dds(label, data).map(ImageData::Gpu)
+    } else {
+        image::load_from_memory(data)
+            .map(ImageData::Decoded)
+            .map_err(|e| AssetError::image(label, e))
+    }
+}
+
+impl GpuImage {
+    pub fn from_ktx2(label: &str, data: &[u8]) -> Result<Self, AssetError> {
+        let reader = ktx2::Reader::new(data).map_err(|e| AssetError::parse(label, None, e))?;
+        let header = reader.header();
+        let unsupported = |reason: String| AssetError::UnsupportedFormat {
+            path: label.to_string(),
+            reason,
+        };
+
+        if header.pixel_depth > 1 || header.layer_count > 1 || header.face_count > 1 {
+            return Err(unsupported(String::from("only 2D textures with a single layer are supported")));
+        }
+        let format = header
+            .format
+            .and_then(ktx2_format)
+            .ok_or_else(|| unsupported(format!("texture format {:?} isn't supported", header.format)))?;
+
+        let mut levels = Vec::new();
+        for level in reader.levels() {
+            let data = match header.supercompression_scheme {
+                None => level.data.to_vec(),
+                Some(ktx2::SupercompressionScheme::Zstandard) => {
+                    // Not sized from the header, `new` checks the levels have enough data
+                    let mut data = Vec::new();
+                    ruzstd::decoding::StreamingDecoder::new(level.data)
+                        .map_err(|e| AssetError::parse(label, None, e))?
+                        .read_to_end(&mut data)
+                        .map_err(|e| AssetError::parse(label, None, e))?;
+                    data
+                }
+                Some(scheme) => return Err(unsupported(format!("supercompression {:?} isn't supported", scheme))),
+            };
+            levels.push(data);
+        }
+
+        Self::new(label, format, header.pixel_width, header.pixel_height.max(1), levels)
+    }
+
+    pub fn from_dds(label: &str, data: &[u8]) -> Result<Self, AssetError> {
+        let dds = ddsfile::Dds::read(data).map_err(|e| AssetError::parse(label, None, e))?;
+        let unsupported = |reason: String| AssetError::UnsupportedFormat {
+            path: label.to_string(),
+            reason,
+        };
+
+        if dds.get_depth() > 1 || dds.get_num_array_layers() > 1 {
+            return Err(unsupported(String::from("only 2D textures with a single layer are supported")));
+        }
+        let format = match (dds.get_dxgi_format(), dds.get_d3d_format()) {
+            (Some(format), _) => dxgi_format(format),
+            (None, Some(format)) => d3d_format(format),
+            (None, None) => None,
+        }
+        .ok_or_else(|| unsupported(String::from("texture format isn't supported")))?;
+
+        // All levels are stored back to back
+        let (width, height) = (dds.get_width(), dds.get_height());
+        let mut data = dds.get_data(0).map_err(|e| AssetError::parse(label, None, e))?;
+        let mut levels = Vec::new();
+        for level in 0..dds.get_num_mipmap_levels().max(1) {
+            let size = level_byte_size(format, width, height, level);
+            if data.len() < size {
+                break;
+            }
+            levels.push(data[..size].to_vec());
+            data = &data[size..];
+        }
+
+        Self::new(label, format, width, height, levels)
+    }
+
+    /// Checks that every level has the data its size needs. Float formats
+    /// that can't be filtered are converted to `Rgba16Float`.
+    fn new(label: &str, format: wgpu::TextureFormat, width: u32, height: u32, mut levels: Vec<Vec<u8>>) -> Result<Self, AssetError> {
+        if width == 0 || levels.is_empty() {
+            return Err(AssetError::parse(label, None, "texture is empty"));
+        }
+        for (level, data) in levels.iter().enumerate() {
+            if data.len() < level_byte_size(format, width, height, level as u32) {
+                return Err(AssetError::parse(label, None, format!("mip level {} is missing data", level)));
+            }
+        }
+
+        let mut format = format;
+        if format == wgpu::TextureFormat::Rgba32Float {
+            for data in &mut levels {
+                *data = data
+                    .chunks_exact(4)
+                    .flat_map(|value| half::f16::from_f32(f32::from_le_bytes(value.try_into().unwrap())).to_le_bytes())
+                    .collect();
+            }
+            format = wgpu::TextureFormat::Rgba16Float;
+        }
+
+        Ok(Self { format, width, height, levels })
+    }
+
+    /// Size of mip level `level` in pixels.
+    pub fn level_size(&self, level: u32) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    /// Whether the device can sample the format as is.
+    pub fn is_supported(&self, device: &wgpu::Device) -> bool {
+        let info = self.format.describe();
+        let (block_width, block_height) = info.block_dimensions;
+        // Compressed textures also have to be whole blocks in size
+        device.features().contains(info.required_features)
+            && self.width.is_multiple_of(block_width as u32)
+            && self.height.is_multiple_of(block_height as u32)
+    }
+
+    /// Decompresses every level to RGBA8, for devices that can't sample the
+    /// format. Use `format.describe().srgb` for the color space.
+    pub fn decompress(&self, label: &str) -> Result<Vec<RgbaImage>, AssetError> {
+        let mut images = Vec::new();
+        for (level, data) in self.levels.iter().enumerate() {
+            let (width, height) = self.level_size(level as u32);
+            let mut pixels = vec![0u32; (width * height) as usize];
+            decode_level(self.format, data, width as usize, height as usize, &mut pixels)
+                .ok_or_else(|| AssetError::UnsupportedFormat {
+                    path: label.to_string(),
+                    reason: format!("{:?} can't be decompressed on the CPU", self.format),
+                })?
+                .map_err(|e| AssetError::parse(label, None, e))?;
+
+            // The decoders write BGRA
+            let rgba = pixels
+                .iter()
+                .flat_map(|pixel| {
+                    let [b, g, r, a] = pixel.to_le_bytes();
+                    [r, g, b, a]
+                })
+                .collect();
+            images.push(RgbaImage::from_raw(width, height, rgba).unwrap());
+        }
+        Ok(images)
+    }
+}
+
+// None if there's no CPU decoder for the format
+fn decode_level(format: wgpu::TextureFormat, data: &[u8], width: usize, height: usize, pixels: &mut [u32]) -> Option<Result<(), &'static str>> {
+    use texture2ddecoder::*;
+    use wgpu::TextureFormat as F;
+
+    Some(match format {
+        F::Bc1RgbaUnorm | F::Bc1RgbaUnormSrgb => decode_bc1a(data, width, height, pixels),
+        F::Bc2RgbaUnorm | F::Bc2RgbaUnormSrgb => decode_bc2(data, width, height, pixels),
+        F::Bc3RgbaUnorm | F::Bc3RgbaUnormSrgb => decode_bc3(data, width, height, pixels),
+        F::Bc4RUnorm => decode_bc4(data, width, height, pixels),
+        F::Bc5RgUnorm => decode_bc5(data, width, height, pixels),
+        // Clamped to 0..1, there's no HDR left once it's RGBA8
+        F::Bc6hRgbUfloat => decode_bc6(data, width, height, pixels, false),
+        F::Bc6hRgbSfloat => decode_bc6(data, width, height, pixels, true),
+        F::Bc7RgbaUnorm | F::Bc7RgbaUnormSrgb => decode_bc7(data, width, height, pixels),
+        F::Etc2Rgb8Unorm | F::Etc2Rgb8UnormSrgb => decode_etc2_rgb(data, width, height, pixels),
+        F::Etc2Rgb8A1Unorm | F::Etc2Rgb8A1UnormSrgb => decode_etc2_rgba1(data, width, height, pixels),
+        F::Etc2Rgba8Unorm | F::Etc2Rgba8UnormSrgb => decode_etc2_rgba8(data, width, height, pixels),
+        F::EacR11Unorm => decode_eacr(data, width, height, pixels),
+        F::EacRg11Unorm => decode_eacrg(data, width, height, pixels),
+        F::Astc { .. } => {
+            let (block_width, block_height) = format.describe().block_dimensions;
+            decode_astc(data, width, height, block_width as usize, block_height as usize, pixels)
+        }
+        _ => return None,
+    })
+}
+
+/// Bytes a mip level takes up, compressed formats round up to whole blocks.
+fn level_byte_size(format: wgpu::TextureFormat, width: u32, height: u32, level: u32) -> usize {
+    let info = format.describe();
+    let (block_width, block_height) = info.block_dimensions;
+    let width = (width >> level).max(1).div_ceil(block_width as u32);
+    let height = (height >> level).max(1).div_ceil(block_height as u32);
+    (width * height) as usize * info.block_size as usize
+}
+
+const ASTC_BLOCKS: [wgpu::AstcBlock; 14] = [
+    wgpu::AstcBlock::B4x4,
+    wgpu::AstcBlock::B5x4,
+    wgpu::AstcBlock::B5x5,
+    wgpu::AstcBlock::B6x5,
+    wgpu::AstcBlock::B6x6,
+    wgpu::AstcBlock::B8x5,
+    wgpu::AstcBlock::B8x6,
+    wgpu::AstcBlock::B8x8,
+    wgpu::AstcBlock::B10x5,
+    wgpu::AstcBlock::B10x6,
+    wgpu::AstcBlock::B10x8,
+    wgpu::AstcBlock::B10x10,
+    wgpu::AstcBlock::B12x10,
+    wgpu::AstcBlock::B12x12,
+];
+
+fn ktx2_format(format: ktx2::Format) -> Option<wgpu::TextureFormat> {
+    use ktx2::Format as K;
+    use wgpu::TextureFormat as F;
+
+    // Vulkan numbers the ASTC formats in the same block order as wgpu,
+    // unorm and sRGB alternating, the HDR ones in a separate range
+    let value = format.value();
+    if (K::ASTC_4x4_UNORM_BLOCK.value()..=K::ASTC_12x12_SRGB_BLOCK.value()).contains(&value) {
+        let index = value - K::ASTC_4x4_UNORM_BLOCK.value();
+        let channel = if index.is_multiple_of(2) { wgpu::AstcChannel::Unorm } else { wgpu::AstcChannel::UnormSrgb };
+        return Some(F::Astc { block: ASTC_BLOCKS[index as usize / 2], channel });
+    }
+    if (K::ASTC_4x4_SFLOAT_BLOCK.value()..=K::ASTC_12x12_SFLOAT_BLOCK.value()).contains(&value) {
+        let index = value - K::ASTC_4x4_SFLOAT_BLOCK.value();
+        return Some(F::Astc { block: ASTC_BLOCKS[index as usize], channel: wgpu::AstcChannel::Hdr });
+    }
+
+    Some(match format {
+        K::R8G8B8A8_UNORM => F::Rgba8Unorm,
+        K::R8G8B8A8_SRGB => F::Rgba8UnormSrgb,
+        K::R16G16B16A16_SFLOAT => F::Rgba16Float,
+        K::R32G32B32A32_SFLOAT => F::Rgba32Float,
+        K::BC1_RGB_UNORM_BLOCK | K::BC1_RGBA_UNORM_BLOCK => F::Bc1RgbaUnorm,
+        K::BC1_RGB_SRGB_BLOCK | K::BC1_RGBA_SRGB_BLOCK => F::Bc1RgbaUnormSrgb,
+        K::BC2_UNORM_BLOCK => F::Bc2RgbaUnorm,
+        K::BC2_SRGB_BLOCK => F::Bc2RgbaUnormSrgb,
+        K::BC3_UNORM_BLOCK => F::Bc3RgbaUnorm,
+        K::BC3_SRGB_BLOCK => F::Bc3RgbaUnormSrgb,
+        K::BC4_UNORM_BLOCK => F::Bc4RUnorm,
+        K::BC4_SNORM_BLOCK => F::Bc4RSnorm,
+        K::BC5_UNORM_BLOCK => F::Bc5RgUnorm,
+        K::BC5_SNORM_BLOCK => F::Bc5RgSnorm,
+        K::BC6H_UFLOAT_BLOCK => F::Bc6hRgbUfloat,
+        K::BC6H_SFLOAT_BLOCK => F::Bc6hRgbSfloat,
+        K::BC7_UNORM_BLOCK => F::Bc7RgbaUnorm,
+        K::BC7_SRGB_BLOCK => F::Bc7RgbaUnormSrgb,
+        K::ETC2_R8G8B8_UNORM_BLOCK => F::Etc2Rgb8Unorm,
+        K::ETC2_R8G8B8_SRGB_BLOCK => F::Etc2Rgb8UnormSrgb,
+        K::ETC2_R8G8B8A1_UNORM_BLOCK => F::Etc2Rgb8A1Unorm,
+        K::ETC2_R8G8B8A1_SRGB_BLOCK => F::Etc2Rgb8A1UnormSrgb,
+        K::ETC2_R8G8B8A8_UNORM_BLOCK => F::Etc2Rgba8Unorm,
+        K::ETC2_R8G8B8A8_SRGB_BLOCK => F::Etc2Rgba8UnormSrgb,
+        K::EAC_R11_UNORM_BLOCK => F::EacR11Unorm,
+        K::EAC_R11_SNORM_BLOCK => F::EacR11Snorm,
+        K::EAC_R11G11_UNORM_BLOCK => F::EacRg11Unorm,
+        K::EAC_R11G11_SNORM_BLOCK => F::EacRg11Snorm,
+        _ => return None,
+    })
+}
+
+fn dxgi_format(format: ddsfile::DxgiFormat) -> Option<wgpu::TextureFormat> {
+    use ddsfile::DxgiFormat as D;
+    use wgpu::TextureFormat as F;
+
+    Some(match format {
+        D::R8G8B8A8_UNorm => F::Rgba8Unorm,
+        D::R8G8B8A8_UNorm_sRGB => F::Rgba8UnormSrgb,
+        D::R16G16B16A16_Float => F::Rgba16Float,
+        D::R32G32B32A32_Float => F::Rgba32Float,
+        D::BC1_UNorm => F::Bc1RgbaUnorm,
+        D::BC1_UNorm_sRGB => F::Bc1RgbaUnormSrgb,
+        D::BC2_UNorm => F::Bc2RgbaUnorm,
+        D::BC2_UNorm_sRGB => F::Bc2RgbaUnormSrgb,
+        D::BC3_UNorm => F::Bc3RgbaUnorm,
+        D::BC3_UNorm_sRGB => F::Bc3RgbaUnormSrgb,
+        D::BC4_UNorm => F::Bc4RUnorm,
+        D::BC4_SNorm => F::Bc4RSnorm,
+        D::BC5_UNorm => F::Bc5RgUnorm,
+        D::BC5_SNorm => F::Bc5RgSnorm,
+        D::BC6H_UF16 => F::Bc6hRgbUfloat,
+        D::BC6H_SF16 => F::Bc6hRgbSfloat,
+        D::BC7_UNorm => F::Bc7RgbaUnorm,
+        D::BC7_UNorm_sRGB => F::Bc7RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+fn d3d_format(format: ddsfile::D3DFormat) -> Option<wgpu::TextureFormat> {
+    use ddsfile::D3DFormat as D;
+    use wgpu::TextureFormat as F;
+
+    Some(match format {
+        D::A8B8G8R8 => F::Rgba8Unorm,
+        D::A16B16G16R16F => F::Rgba16Float,
+        D::A32B32G32R32F => F::Rgba32Float,
+        D::DXT1 => F::Bc1RgbaUnorm,
+        D::DXT3 => F::Bc2RgbaUnorm,
+        D::DXT5 => F::Bc3RgbaUnorm,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BC1 block with both end points pure red, so every texel is red
+    const RED_BC1: [u8; 8] = [0x00, 0xf8, 0x00, 0xf8, 0, 0, 0, 0];
+    const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+
+    /// A minimal KTX2 file: no key/value data and an empty data format
+    /// descriptor, which the reader accepts.
+    fn ktx2(format: u32, width: u32, height: u32, supercompression: u32, levels: &[(Vec<u8>, usize)]) -> Vec<u8> {
+        let index_size = levels.len() * 24;
+        let dfd_offset = 80 + index_size;
+        let mut data = KTX2_MAGIC.to_vec();
+        for value in [format, 1, width, height, 0, 0, 1, levels.len() as u32, supercompression] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [dfd_offset as u32, 4, 0, 0] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 16]);
+
+        let mut offset = dfd_offset + 4;
+        for (level, uncompressed) in levels {
+            for value in [offset, level.len(), *uncompressed] {
+                data.extend_from_slice(&(value as u64).to_le_bytes());
+            }
+            offset += level.len();
+        }
+        data.extend_from_slice(&4u32.to_le_bytes());
+        for (level, _) in levels {
+            data.extend_from_slice(level);
+        }
+        data
+    }
+
+    fn dds(format: ddsfile::DxgiFormat, width: u32, height: u32, levels: u32, data: &[u8]) -> Vec<u8> {
+        let mut dds = ddsfile::Dds::new_dxgi(ddsfile::NewDxgiParams {
+            height,
+            width,
+            depth: None,
+            format,
+            mipmap_levels: Some(levels),
+            array_layers: None,
+            caps2: None,
+            is_cubemap: false,
+            resource_dimension: ddsfile::D3D10ResourceDimension::Texture2D,
+            alpha_mode: ddsfile::AlphaMode::Unknown,
+        })
+        .unwrap();
+        dds.data = data.to_vec();
+        let mut file = Vec::new();
+        dds.write(&mut file).unwrap();
+        file
+    }
+
+    fn gpu(image: ImageData) -> GpuImage {
+        match image {
+            ImageData::Gpu(image) => image,
+            ImageData::Decoded(_) => panic!("expected a GPU image"),
+        }
+    }
+
+    #[test]
+    fn ktx2_files_keep_their_levels() {
+        let base = (0..16).collect::<Vec<u8>>();
+        let file = ktx2(VK_FORMAT_R8G8B8A8_UNORM, 2, 2, 0, &[(base.clone(), 16), (vec![9; 4], 4)]);
+        let image = gpu(decode("rgba.ktx2", &file).unwrap());
+        assert_eq!(image.format, wgpu::TextureFormat::Rgba8Unorm);
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.levels, [base, vec![9; 4]]);
+        assert_eq!(image.level_size(1), (1, 1));
+    }
+
+    #[test]
+    fn zstd_ktx2_levels_are_inflated() {
+        let base = vec![7; 64];
+        let compressed = ruzstd::encoding::compress_to_vec(&base[..], ruzstd::encoding::CompressionLevel::Fastest);
+        // The uncompressed length in the index isn't trusted, `new` checks the data
+        let file = ktx2(VK_FORMAT_R8G8B8A8_UNORM, 4, 4, 2, &[(compressed, usize::MAX)]);
+        assert_eq!(gpu(decode("zstd.ktx2", &file).unwrap()).levels, [base]);
+    }
+
+    #[test]
+    fn short_or_unsupported_ktx2_files_are_rejected() {
+        let short = ktx2(VK_FORMAT_R8G8B8A8_UNORM, 4, 4, 0, &[(vec![0; 16], 16)]);
+        assert!(decode("short.ktx2", &short).is_err());
+        let mut truncated = ktx2(VK_FORMAT_R8G8B8A8_UNORM, 2, 2, 0, &[(vec![0; 16], 16)]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(decode("truncated.ktx2", &truncated).is_err());
+        // VK_FORMAT_R4G4_UNORM_PACK8 has no wgpu equivalent
+        let unsupported = ktx2(1, 2, 2, 0, &[(vec![0; 4], 4)]);
+        assert!(matches!(decode("r4g4.ktx2", &unsupported), Err(AssetError::UnsupportedFormat { .. })));
+    }
+
+    #[test]
+    fn dds_files_split_into_levels() {
+        // 8x8 with four blocks, then 4x4 and the two smallest levels with one block each
+        let data = RED_BC1.repeat(4 + 1 + 1 + 1);
+        let image = gpu(decode("red.dds", &dds(ddsfile::DxgiFormat::BC1_UNorm, 8, 8, 4, &data)).unwrap());
+        assert_eq!(image.format, wgpu::TextureFormat::Bc1RgbaUnorm);
+        assert_eq!(image.levels.iter().map(Vec::len).collect::<Vec<_>>(), [32, 8, 8, 8]);
+
+        let missing = dds(ddsfile::DxgiFormat::BC1_UNorm, 8, 8, 1, &RED_BC1);
+        assert!(decode("missing.dds", &missing).is_err());
+    }
+
+    #[test]
+    fn other_files_go_to_the_image_crate() {
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(RgbaImage::new(3, 2))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(matches!(decode("image.png", &png).unwrap(), ImageData::Decoded(image) if image.width() == 3));
+        assert!(decode("garbage.png", b"not an image").is_err());
+    }
+
+    #[test]
+    fn block_compressed_levels_decompress() {
+        let image = GpuImage::new("red", wgpu::TextureFormat::Bc1RgbaUnormSrgb, 8, 4, vec![RED_BC1.repeat(2), RED_BC1.to_vec()]).unwrap();
+        let levels = image.decompress("red").unwrap();
+        assert_eq!(levels.iter().map(RgbaImage::dimensions).collect::<Vec<_>>(), [(8, 4), (4, 2)]);
+        for level in &levels {
+            assert!(level.pixels().all(|pixel| pixel.0 == [255, 0, 0, 255]), "{:?}", level.get_pixel(0, 0));
+        }
+
+        let plain = GpuImage::new("plain", wgpu::TextureFormat::Rgba8Unorm, 1, 1, vec![vec![0; 4]]).unwrap();
+        assert!(matches!(plain.decompress("plain"), Err(AssetError::UnsupportedFormat { .. })));
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::engine::asset_server::AssetServer;
+use crate::engine::resources;
+
+// Polling a handful of files twice a second is plenty for editing by hand
+const POLL_INTERVAL: instant::Duration = instant::Duration::from_millis(500);
+
+/// What changed since the last poll.
+#[derive(Default)]
+pub(crate) struct Changes {
+    /// File names in the shader directory, e.g. `"shader.wgsl"`
+    pub shaders: Vec<String>,
+    /// Asset paths as passed to `load_binary`, shader files included
+    pub assets: Vec<String>,
+}
+
+/// Watches the shader directory, every file the asset server loaded and
+/// shaders added from the assets by checking modification times.
+pub(crate) struct HotReload {
+    pub shader_dir: PathBuf,
+    last_poll: instant::Instant,
+    shaders: HashMap<String, Option<SystemTime>>,
+    assets: HashMap<String, Option<SystemTime>>,
+}
+
+impl HotReload {
+    pub fn new(shader_dir: PathBuf) -> Self {
+        let mut hot_reload = Self {
+            shader_dir,
+            last_poll: instant::Instant::now(),
+            shaders: HashMap::new(),
+            assets: HashMap::new(),
+        };
+        hot_reload.shaders = hot_reload.shader_times();
+        hot_reload
+    }
+
+    /// Picks the shader directory at runtime: `BONES_SHADER_DIR` if set,
+    /// otherwise a `shaders` directory in the asset root, otherwise
+    /// `src/engine` in the working directory, which is where `cargo run`
+    /// starts from.
+    pub fn default_shader_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("BONES_SHADER_DIR") {
+            return PathBuf::from(dir);
+        }
+        Some(resources::asset_root().join("shaders"))
+            .filter(|dir| dir.is_dir())
+            .unwrap_or_else(|| Path::new("src").join("engine"))
+    }
+
+    /// Reads a shader from the shader directory.
+    pub fn read_shader(&self, name: &str) -> std::io::Result<String> {
+        std::fs::read_to_string(self.shader_dir.join(name))
+    }
+
+    /// `shader_files` are asset paths of shaders that don't live in the
+    /// shader directory.
+    pub fn poll(&mut self, asset_server: &AssetServer, shader_files: &[String]) -> Option<Changes> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = instant::Instant::now();
+
+        let mut changes = Changes::default();
+        let shaders = self.shader_times();
+        for (name, modified) in &shaders {
+            if self.shaders.get(name).is_some_and(|old| old != modified) {
+                changes.shaders.push(name.clone());
+            }
+        }
+        self.shaders = shaders;
+
+        for file in asset_server.watched_files().into_iter().chain(shader_files.iter().cloned()) {
+            let modified = resources::modified(&file);
+            match self.assets.insert(file.clone(), modified) {
+                // Files seen for the first time just get remembered
+                Some(old) if old != modified => changes.assets.push(file),
+                _ => {}
+            }
+        }
+
+        Some(changes)
+    }
+
+    fn shader_times(&self) -> HashMap<String, Option<SystemTime>> {
+        let Ok(entries) = std::fs::read_dir(&self.shader_dir) else {
+            return HashMap::new();
+        };
+        entries
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "wgsl"))
+            .map(|entry| {
+                let modified = entry.metadata().ok().and_then(|metadata| metadata.modified().ok());
+                (entry.file_name().to_string_lossy().into_owned(), modified)
+            })
+            .collect()
+    }
+}
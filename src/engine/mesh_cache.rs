@@ -0,0 +1,841 @@
+use std::io::Cursor;
+use std::ops::Range;
+
+use crate::engine::animation::{Bone, Skin, Transform};
+use crate::engine::asset_error::AssetError;
+use crate::engine::gpu_image::ImageData;
+use crate::engine::model::{MaterialData, MaterialUniform, MeshData, ModelData, ModelVertex, TextureData};
+use crate::engine::{gpu_image, resources};
+use crate::engine::sampler::SamplerSettings;
+use crate::engine::pipeline::{AlphaMode, MaterialPipeline};
+
+// Layout of a mesh cache, all numbers little endian, strings as a u16 length
+// followed by utf-8:
+//
+//   magic "BMSH", version: u32, vertex size: u32
+//   dependencies: count u32, per file path, size: u64, modification time in
+//     nanoseconds since the Unix epoch: u64 (0 if unknown), FNV-1a hash: u64
+//   materials: count u32, per material name, base color, metallic
+//     roughness, normal, occlusion and emissive texture (kind u8: 0 none,
+//     1 file path, 2 label + u32 length + PNG data, 3 label + u32 length +
+//     the original file),
+//     the raw MaterialUniform, 8 bytes of SamplerSettings, the cull mode
+//     (u8: 0 none, 1 front, 2 back) and the alpha mode (u8: 0 opaque,
+//     1 mask, 2 blend, 3 premultiplied, 4 additive)
+//   bounds: min [f32; 3], max [f32; 3]
+//   submeshes: count u32, per submesh name, material u32, first vertex u32,
+//     vertex count u32, first index u32, index count u32, bounds
+//   skin: present u8, then bones (name, parent i32 or -1, translation,
+//     rotation xyzw, scale), joints u32 and inverse bind matrices
+//   vertices: count u32, raw ModelVertex data
+//   indices: count u32, u32 each, relative to their submesh's first vertex
+//
+// Bump VERSION whenever any of this or ModelVertex changes, old caches are
+// then ignored and the source file is parsed again.
+const MAGIC: &[u8; 4] = b"BMSH";
+const VERSION: u32 = 7;
+
+/// Where the cache for the model `file_name` lives, next to the source.
+pub fn cache_path(file_name: &str) -> String {
+    format!("{}.mesh", file_name)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+}
+
+impl Bounds {
+    pub fn of(vertices: &[ModelVertex]) -> Self {
+        let mut bounds = Bounds {
+            min: [f32::MAX; 3],
+            max: [f32::MIN; 3],
+        };
+        for vertex in vertices {
+            bounds.add(&Bounds { min: vertex.position, max: vertex.position });
+        }
+        bounds
+    }
+
+    /// Grows the bounds to also enclose `other`.
+    pub fn add(&mut self, other: &Bounds) {
+        for axis in 0..3 {
+            self.min[axis] = self.min[axis].min(other.min[axis]);
+            self.max[axis] = self.max[axis].max(other.max[axis]);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.min[axis] > self.max[axis])
+    }
+}
+
+/// A file a cache was built from, as it was at the time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub path: String,
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch, if the source could tell
+    pub modified: Option<u64>,
+    pub hash: u64,
+}
+
+fn modified(file_name: &str) -> Option<u64> {
+    let modified = resources::modified(file_name)?.duration_since(std::time::UNIX_EPOCH).ok()?;
+    u64::try_from(modified.as_nanos()).ok()
+}
+
+#[derive(Debug, Clone)]
+pub enum TextureRef {
+    /// Loaded through the resource loader like any other image
+    File(String),
+    /// Images that only exist inside the source, e.g. in a `.glb`
+    Embedded { label: String, png: Vec<u8> },
+    /// Same, but kept as the file they were in, for formats that can't be
+    /// written back as PNG like KTX2, DDS, HDR or EXR
+    Encoded { label: String, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedMaterial {
+    pub name: String,
+    /// In the order `MaterialData::textures` lists them
+    pub textures: [Option<TextureRef>; 5],
+    pub uniform: MaterialUniform,
+    pub sampler: SamplerSettings,
+    /// The only parts of the pipeline loaders set, from glTF's double
+    /// sided and alpha mode
+    pub cull_mode: Option<wgpu::Face>,
+    pub alpha_mode: AlphaMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct Submesh {
+    pub name: String,
+    pub material: usize,
+    pub vertices: Range<u32>,
+    pub indices: Range<u32>,
+    pub bounds: Bounds,
+}
+
+/// A model ready to upload, without parsing the source or computing
+/// normals and tangents again. Made offline with `MeshCache::import`.
+pub struct MeshCache {
+    /// The files the model was built from, textures excluded since those
+    /// are loaded from the cache by path
+    pub dependencies: Vec<Dependency>,
+    pub materials: Vec<CachedMaterial>,
+    pub bounds: Bounds,
+    pub submeshes: Vec<Submesh>,
+    pub skin: Option<Skin>,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshCache {
+    /// Builds a cache from already loaded model data. `reads` are the files
+    /// loading it touched, see `resources::record_reads`.
+    pub async fn from_model(data: &ModelData, reads: &[String]) -> Result<Self, AssetError> {
+        let texture_ref = |texture: &Option<TextureData>| -> Result<Option<TextureRef>, AssetError> {
+            let Some(texture) = texture else {
+                return Ok(None);
+            };
+            if reads.contains(&texture.label) {
+                return Ok(Some(TextureRef::File(texture.label.clone())));
+            }
+            if let Some(data) = &texture.encoded {
+                return Ok(Some(TextureRef::Encoded { label: texture.label.clone(), data: data.to_vec() }));
+            }
+            let ImageData::Decoded(image) = &texture.image else {
+                return Err(AssetError::UnsupportedFormat {
+                    path: texture.label.clone(),
+                    reason: String::from("compressed textures can only be cached as separate files"),
+                });
+            };
+            let mut png = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+                .map_err(|e| AssetError::image(&texture.label, e))?;
+            Ok(Some(TextureRef::Embedded { label: texture.label.clone(), png }))
+        };
+
+        let mut materials = Vec::new();
+        let mut textures = Vec::new();
+        for material in &data.materials {
+            let mut refs: [Option<TextureRef>; 5] = Default::default();
+            for (texture_ref_out, (_, texture)) in refs.iter_mut().zip(material.textures()) {
+                if let Some(texture) = texture {
+                    textures.push(texture.label.clone());
+                }
+                *texture_ref_out = texture_ref(texture)?;
+            }
+            materials.push(CachedMaterial {
+                name: material.name.clone(),
+                textures: refs,
+                uniform: material.uniform,
+                sampler: material.sampler,
+                cull_mode: material.pipeline.cull_mode,
+                alpha_mode: material.pipeline.alpha_mode,
+            });
+        }
+
+        let mut dependencies = Vec::new();
+        for file_name in reads {
+            if textures.contains(file_name) || dependencies.iter().any(|dependency: &Dependency| dependency.path == *file_name) {
+                continue;
+            }
+            let data = resources::load_binary(file_name).await?;
+            dependencies.push(Dependency {
+                path: file_name.clone(),
+                size: data.len() as u64,
+                modified: modified(file_name),
+                hash: hash(&data),
+            });
+        }
+
+        let mut cache = MeshCache {
+            dependencies,
+            materials,
+            bounds: Bounds::of(&[]),
+            submeshes: Vec::new(),
+            skin: data.skin.clone(),
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        };
+        for mesh in &data.meshes {
+            let bounds = Bounds::of(&mesh.vertices);
+            cache.bounds.add(&bounds);
+            let first_vertex = cache.vertices.len() as u32;
+            let first_index = cache.indices.len() as u32;
+            cache.vertices.extend_from_slice(&mesh.vertices);
+            cache.indices.extend_from_slice(&mesh.indices);
+            cache.submeshes.push(Submesh {
+                name: mesh.name.clone(),
+                material: mesh.material,
+                vertices: first_vertex..cache.vertices.len() as u32,
+                indices: first_index..cache.indices.len() as u32,
+                bounds,
+            });
+        }
+        Ok(cache)
+    }
+
+    /// Parses the model `file_name` from its source and builds a cache of it.
+    /// Meant as an offline step, so native only.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn import(file_name: &str) -> Result<Self, AssetError> {
+        let (data, reads) = resources::record_reads(|| pollster::block_on(resources::load_source_model_data(file_name)));
+        pollster::block_on(Self::from_model(&data?, &reads))
+    }
+
+    /// Imports `file_name` and writes the cache next to it in the asset root,
+    /// returning where it went.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn import_to_file(file_name: &str) -> Result<std::path::PathBuf, AssetError> {
+        let cache = Self::import(file_name)?;
+        let path = resources::asset_root().join(cache_path(file_name));
+        std::fs::write(&path, cache.to_bytes()).map_err(|e| AssetError::io(&cache_path(file_name), e))?;
+        Ok(path)
+    }
+
+    /// True if none of the files the cache was made from changed since.
+    /// Files that can't be found count as unchanged, so a game can ship the
+    /// caches without their sources. Files are only read and hashed when
+    /// their size matches but the modification time doesn't.
+    pub async fn is_up_to_date(&self) -> bool {
+        for dependency in &self.dependencies {
+            match resources::size(&dependency.path) {
+                Some(size) if size != dependency.size => return false,
+                Some(_) if dependency.modified.is_some() && modified(&dependency.path) == dependency.modified => continue,
+                _ => {}
+            }
+            match resources::load_binary(&dependency.path).await {
+                Ok(data) if hash(&data) != dependency.hash => return false,
+                Ok(_) | Err(AssetError::NotFound { .. }) => {}
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Turns the cache back into model data, loading the textures it uses.
+    pub async fn load(&self, file_name: &str) -> Result<ModelData, AssetError> {
+        let mut materials = Vec::new();
+        for material in &self.materials {
+            let texture = |texture: &Option<TextureRef>| {
+                let texture = texture.clone();
+                async move {
+                    let (label, image) = match texture {
+                        None => return Ok(None),
+                        Some(TextureRef::File(path)) => {
+                            let image = resources::load_texture_image(&path).await;
+                            (path, image)
+                        }
+                        Some(TextureRef::Embedded { label, png: data }) | Some(TextureRef::Encoded { label, data }) => {
+                            let image = gpu_image::decode(&label, &data);
+                            (label, image)
+                        }
+                    };
+                    let image = image.map_err(|e| AssetError::MissingTexture {
+                        path: file_name.to_string(),
+                        material: material.name.clone(),
+                        texture: label.clone(),
+                        source: Box::new(e),
+                    })?;
+                    Ok::<_, AssetError>(Some(TextureData { label, image, encoded: None }))
+                }
+            };
+            let [base_color, metallic_roughness, normal, occlusion, emissive] = &material.textures;
+            materials.push(MaterialData {
+                name: material.name.clone(),
+                base_color_texture: texture(base_color).await?,
+                metallic_roughness_texture: texture(metallic_roughness).await?,
+                normal_texture: texture(normal).await?,
+                occlusion_texture: texture(occlusion).await?,
+                emissive_texture: texture(emissive).await?,
+                uniform: material.uniform,
+                sampler: material.sampler,
+                pipeline: MaterialPipeline {
+                    cull_mode: material.cull_mode,
+                    ..Default::default()
+                }
+                .with_alpha_mode(material.alpha_mode),
+            });
+        }
+
+        let meshes = self
+            .submeshes
+            .iter()
+            .map(|submesh| MeshData {
+                name: submesh.name.clone(),
+                vertices: self.vertices[submesh.vertices.start as usize..submesh.vertices.end as usize].to_vec(),
+                indices: self.indices[submesh.indices.start as usize..submesh.indices.end as usize].to_vec(),
+                material: submesh.material,
+            })
+            .collect();
+
+        Ok(ModelData {
+            meshes,
+            materials,
+            skin: self.skin.clone(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer(Vec::new());
+        out.0.extend_from_slice(MAGIC);
+        out.u32(VERSION);
+        out.u32(std::mem::size_of::<ModelVertex>() as u32);
+
+        out.u32(self.dependencies.len() as u32);
+        for dependency in &self.dependencies {
+            out.string(&dependency.path);
+            out.u64(dependency.size);
+            out.u64(dependency.modified.unwrap_or(0));
+            out.u64(dependency.hash);
+        }
+
+        out.u32(self.materials.len() as u32);
+        for material in &self.materials {
+            out.string(&material.name);
+            for texture in &material.textures {
+                match texture {
+                    None => out.0.push(0),
+                    Some(TextureRef::File(path)) => {
+                        out.0.push(1);
+                        out.string(path);
+                    }
+                    Some(TextureRef::Embedded { label, png }) => {
+                        out.0.push(2);
+                        out.string(label);
+                        out.u32(png.len() as u32);
+                        out.0.extend_from_slice(png);
+                    }
+                    Some(TextureRef::Encoded { label, data }) => {
+                        out.0.push(3);
+                        out.string(label);
+                        out.u32(data.len() as u32);
+                        out.0.extend_from_slice(data);
+                    }
+                }
+            }
+            out.0.extend_from_slice(bytemuck::bytes_of(&material.uniform));
+            out.0.extend_from_slice(&material.sampler.to_bytes());
+            out.0.push(match material.cull_mode {
+                None => 0,
+                Some(wgpu::Face::Front) => 1,
+                Some(wgpu::Face::Back) => 2,
+            });
+            out.0.push(match material.alpha_mode {
+                AlphaMode::Opaque => 0,
+                AlphaMode::Mask => 1,
+                AlphaMode::Blend => 2,
+                AlphaMode::Premultiplied => 3,
+                AlphaMode::Additive => 4,
+            });
+        }
+
+        out.bounds(&self.bounds);
+        out.u32(self.submeshes.len() as u32);
+        for submesh in &self.submeshes {
+            out.string(&submesh.name);
+            out.u32(submesh.material as u32);
+            out.u32(submesh.vertices.start);
+            out.u32(submesh.vertices.len() as u32);
+            out.u32(submesh.indices.start);
+            out.u32(submesh.indices.len() as u32);
+            out.bounds(&submesh.bounds);
+        }
+
+        match &self.skin {
+            None => out.0.push(0),
+            Some(skin) => {
+                out.0.push(1);
+                out.u32(skin.bones.len() as u32);
+                for bone in &skin.bones {
+                    out.string(&bone.name);
+                    out.0.extend_from_slice(&bone.parent.map_or(-1, |parent| parent as i32).to_le_bytes());
+                    let Transform { translation, rotation, scale } = bone.transform;
+                    out.floats(&[translation.x, translation.y, translation.z]);
+                    out.floats(&[rotation.v.x, rotation.v.y, rotation.v.z, rotation.s]);
+                    out.floats(&[scale.x, scale.y, scale.z]);
+                }
+                out.u32(skin.joints.len() as u32);
+                for (&joint, matrix) in skin.joints.iter().zip(&skin.inverse_bind_matrices) {
+                    out.u32(joint as u32);
+                    let matrix: &[f32; 16] = matrix.as_ref();
+                    out.floats(matrix);
+                }
+            }
+        }
+
+        // Every platform we run on is little endian, so the vertices can be
+        // written as they are in memory
+        out.u32(self.vertices.len() as u32);
+        out.0.extend_from_slice(bytemuck::cast_slice(&self.vertices));
+        out.u32(self.indices.len() as u32);
+        for &index in &self.indices {
+            out.u32(index);
+        }
+        out.0
+    }
+
+    /// Parses a cache, `file_name` is only used for errors.
+    pub fn from_bytes(file_name: &str, data: &[u8]) -> Result<Self, AssetError> {
+        Self::read(&mut Reader { data, position: 0 }).map_err(|message| AssetError::parse(file_name, None, message))
+    }
+
+    fn read(input: &mut Reader) -> Result<Self, String> {
+        if input.bytes(4)? != MAGIC {
+            return Err(String::from("not a mesh cache"));
+        }
+        let version = input.u32()?;
+        if version != VERSION {
+            return Err(format!("mesh cache version {} isn't supported, expected {}", version, VERSION));
+        }
+        let vertex_size = input.u32()?;
+        if vertex_size as usize != std::mem::size_of::<ModelVertex>() {
+            return Err(format!("vertices are {} bytes, expected {}", vertex_size, std::mem::size_of::<ModelVertex>()));
+        }
+
+        let mut dependencies = Vec::new();
+        for _ in 0..input.u32()? {
+            dependencies.push(Dependency {
+                path: input.string()?,
+                size: input.u64()?,
+                modified: Some(input.u64()?).filter(|&modified| modified != 0),
+                hash: input.u64()?,
+            });
+        }
+
+        let mut materials = Vec::new();
+        for _ in 0..input.u32()? {
+            let name = input.string()?;
+            let mut texture = || -> Result<Option<TextureRef>, String> {
+                Ok(match input.u8()? {
+                    0 => None,
+                    1 => Some(TextureRef::File(input.string()?)),
+                    2 => {
+                        let label = input.string()?;
+                        let length = input.u32()? as usize;
+                        let png = input.bytes(length)?.to_vec();
+                        Some(TextureRef::Embedded { label, png })
+                    }
+                    3 => {
+                        let label = input.string()?;
+                        let length = input.u32()? as usize;
+                        let data = input.bytes(length)?.to_vec();
+                        Some(TextureRef::Encoded { label, data })
+                    }
+                    kind => return Err(format!("unknown texture kind {}", kind)),
+                })
+            };
+            let textures = [texture()?, texture()?, texture()?, texture()?, texture()?];
+            let uniform = bytemuck::pod_read_unaligned(input.bytes(std::mem::size_of::<MaterialUniform>())?);
+            let sampler = SamplerSettings::from_bytes(input.array()?)?;
+            let cull_mode = match input.u8()? {
+                0 => None,
+                1 => Some(wgpu::Face::Front),
+                2 => Some(wgpu::Face::Back),
+                value => return Err(format!("unknown cull mode {}", value)),
+            };
+            let alpha_mode = match input.u8()? {
+                0 => AlphaMode::Opaque,
+                1 => AlphaMode::Mask,
+                2 => AlphaMode::Blend,
+                3 => AlphaMode::Premultiplied,
+                4 => AlphaMode::Additive,
+                value => return Err(format!("unknown alpha mode {}", value)),
+            };
+            materials.push(CachedMaterial {
+                name,
+                textures,
+                uniform,
+                sampler,
+                cull_mode,
+                alpha_mode,
+            });
+        }
+
+        let bounds = input.bounds()?;
+        let mut submeshes = Vec::new();
+        for _ in 0..input.u32()? {
+            let name = input.string()?;
+            let material = input.u32()? as usize;
+            let first_vertex = input.u32()?;
+            let vertex_count = input.u32()?;
+            let first_index = input.u32()?;
+            let index_count = input.u32()?;
+            submeshes.push(Submesh {
+                name,
+                material,
+                vertices: first_vertex..first_vertex.saturating_add(vertex_count),
+                indices: first_index..first_index.saturating_add(index_count),
+                bounds: input.bounds()?,
+            });
+        }
+
+        let skin = match input.u8()? {
+            0 => None,
+            _ => {
+                let mut bones = Vec::new();
+                for _ in 0..input.u32()? {
+                    let name = input.string()?;
+                    let parent = i32::from_le_bytes(input.array()?);
+                    let [x, y, z] = input.floats()?;
+                    let [rx, ry, rz, rw] = input.floats()?;
+                    let [sx, sy, sz] = input.floats()?;
+                    bones.push(Bone {
+                        name,
+                        parent: usize::try_from(parent).ok(),
+                        transform: Transform {
+                            translation: cgmath::vec3(x, y, z),
+                            rotation: cgmath::Quaternion::new(rw, rx, ry, rz),
+                            scale: cgmath::vec3(sx, sy, sz),
+                        },
+                    });
+                }
+                let mut joints = Vec::new();
+                let mut inverse_bind_matrices = Vec::new();
+                for _ in 0..input.u32()? {
+                    joints.push(input.u32()? as usize);
+                    let matrix: [f32; 16] = input.floats()?;
+                    let matrix: &cgmath::Matrix4<f32> = (&matrix).into();
+                    inverse_bind_matrices.push(*matrix);
+                }
+                Some(Skin { bones, joints, inverse_bind_matrices })
+            }
+        };
+
+        let vertex_count = input.u32()? as usize;
+        let vertices = input.bytes(vertex_count * std::mem::size_of::<ModelVertex>())?;
+        let vertices = vertices
+            .chunks_exact(std::mem::size_of::<ModelVertex>())
+            .map(bytemuck::pod_read_unaligned)
+            .collect::<Vec<ModelVertex>>();
+        let index_count = input.u32()? as usize;
+        let indices = (0..index_count).map(|_| input.u32()).collect::<Result<Vec<_>, _>>()?;
+
+        // Everything below indexes into these, so check it once here
+        for submesh in &submeshes {
+            if submesh.vertices.end as usize > vertices.len() || submesh.indices.end as usize > indices.len() {
+                return Err(format!("submesh {} is out of range", submesh.name));
+            }
+            if submesh.material >= materials.len() {
+                return Err(format!("submesh {} uses missing material {}", submesh.name, submesh.material));
+            }
+            let vertex_count = submesh.vertices.len() as u32;
+            if indices[submesh.indices.start as usize..submesh.indices.end as usize].iter().any(|&index| index >= vertex_count) {
+                return Err(format!("submesh {} references a vertex it doesn't have", submesh.name));
+            }
+        }
+        // Posing walks bones parents first and looks joints up by index
+        if let Some(skin) = &skin {
+            for (i, bone) in skin.bones.iter().enumerate() {
+                if bone.parent.is_some_and(|parent| parent >= i) {
+                    return Err(format!("bone {} doesn't come after its parent", bone.name));
+                }
+            }
+            if let Some(joint) = skin.joints.iter().find(|&&joint| joint >= skin.bones.len()) {
+                return Err(format!("a joint uses missing bone {}", joint));
+            }
+        }
+
+        Ok(MeshCache {
+            dependencies,
+            materials,
+            bounds,
+            submeshes,
+            skin,
+            vertices,
+            indices,
+        })
+    }
+}
+
+/// Loads the cache for `file_name` if there is one and it's up to date.
+/// Broken or stale caches are skipped with a warning, so the caller falls
+/// back to the source.
+pub async fn load_cached(file_name: &str) -> Result<Option<ModelData>, AssetError> {
+    let path = cache_path(file_name);
+    let data = match resources::load_binary(&path).await {
+        Ok(data) => data,
+        Err(AssetError::NotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let cache = match MeshCache::from_bytes(&path, &data) {
+        Ok(cache) => cache,
+        Err(e) => {
+            log::warn!("Ignoring mesh cache: {}", e);
+            return Ok(None);
+        }
+    };
+    if !cache.is_up_to_date().await {
+        log::warn!("{} is out of date, loading {} instead", path, file_name);
+        return Ok(None);
+    }
+    cache.load(file_name).await.map(Some)
+}
+
+/// FNV-1a, stable across platforms and Rust versions unlike `DefaultHasher`.
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        // Names longer than this are cut off rather than failing the import
+        let bytes = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+        self.0.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn floats(&mut self, values: &[f32]) {
+        for value in values {
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn bounds(&mut self, bounds: &Bounds) {
+        self.floats(&bounds.min);
+        self.floats(&bounds.max);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .position
+            .checked_add(length)
+            .and_then(|end| self.data.get(self.position..end))
+            .ok_or_else(|| String::from("unexpected end of file"))?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = u16::from_le_bytes(self.array()?) as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn floats<const N: usize>(&mut self) -> Result<[f32; N], String> {
+        let mut values = [0.0; N];
+        for value in &mut values {
+            *value = f32::from_le_bytes(self.array()?);
+        }
+        Ok(values)
+    }
+
+    fn bounds(&mut self) -> Result<Bounds, String> {
+        Ok(Bounds {
+            min: self.floats()?,
+            max: self.floats()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(x: f32) -> ModelVertex {
+        ModelVertex {
+            position: [x, 0.0, -x],
+            weights: [1.0, 0.0, 0.0, 0.0],
+            ..Default::default()
+        }
+    }
+
+    fn cache() -> MeshCache {
+        let vertices = vec![vertex(0.0), vertex(1.0), vertex(2.0), vertex(-1.0), vertex(-2.0), vertex(-3.0)];
+        MeshCache {
+            dependencies: vec![
+                Dependency { path: String::from("cube.obj"), size: 120, modified: Some(1_700_000_000_000_000_000), hash: hash(b"cube") },
+                Dependency { path: String::from("cube.mtl"), size: 40, modified: None, hash: hash(b"mtl") },
+            ],
+            materials: vec![CachedMaterial {
+                name: String::from("leaves"),
+                textures: [
+                    Some(TextureRef::File(String::from("leaves.png"))),
+                    None,
+                    Some(TextureRef::Encoded { label: String::from("tree.glb#normal"), data: b"KTX2 file".to_vec() }),
+                    None,
+                    None,
+                ],
+                uniform: MaterialUniform::default(),
+                sampler: SamplerSettings::default(),
+                cull_mode: None,
+                alpha_mode: AlphaMode::Mask,
+            }],
+            bounds: Bounds::of(&vertices),
+            submeshes: vec![
+                Submesh { name: String::from("a"), material: 0, vertices: 0..3, indices: 0..3, bounds: Bounds::of(&vertices[..3]) },
+                Submesh { name: String::from("b"), material: 0, vertices: 3..6, indices: 3..6, bounds: Bounds::of(&vertices[3..]) },
+            ],
+            skin: Some(Skin {
+                bones: vec![Bone { name: String::from("root"), parent: None, transform: Transform::default() }],
+                joints: vec![0],
+                inverse_bind_matrices: vec![cgmath::Matrix4::from_scale(2.0)],
+            }),
+            vertices,
+            indices: vec![0, 1, 2, 2, 1, 0],
+        }
+    }
+
+    #[test]
+    fn caches_round_trip() {
+        let cache = cache();
+        let read = MeshCache::from_bytes("cube.obj.mesh", &cache.to_bytes()).unwrap();
+
+        assert_eq!(read.dependencies, cache.dependencies);
+        assert_eq!(read.materials.len(), 1);
+        let material = &read.materials[0];
+        assert_eq!(material.name, "leaves");
+        assert!(matches!(&material.textures[0], Some(TextureRef::File(path)) if path == "leaves.png"));
+        assert!(matches!(&material.textures[2], Some(TextureRef::Encoded { label, data }) if label == "tree.glb#normal" && data == b"KTX2 file"));
+        assert!(material.textures[1].is_none() && material.textures[3..].iter().all(Option::is_none));
+        assert_eq!(material.sampler, cache.materials[0].sampler);
+        assert_eq!(material.cull_mode, None);
+        assert_eq!(material.alpha_mode, AlphaMode::Mask);
+        assert_eq!(bytemuck::bytes_of(&material.uniform), bytemuck::bytes_of(&cache.materials[0].uniform));
+
+        assert_eq!(read.bounds, cache.bounds);
+        assert_eq!(read.submeshes.len(), 2);
+        assert_eq!(read.submeshes[1].name, "b");
+        assert_eq!(read.submeshes[1].vertices, 3..6);
+        assert_eq!(read.submeshes[1].indices, 3..6);
+        assert_eq!(read.submeshes[1].bounds, cache.submeshes[1].bounds);
+
+        let skin = read.skin.unwrap();
+        assert_eq!(skin.bones[0].name, "root");
+        assert_eq!(skin.bones[0].parent, None);
+        assert_eq!(skin.joints, vec![0]);
+        assert_eq!(skin.inverse_bind_matrices, cache.skin.unwrap().inverse_bind_matrices);
+
+        assert_eq!(bytemuck::cast_slice::<_, u8>(&read.vertices), bytemuck::cast_slice::<_, u8>(&cache.vertices));
+        assert_eq!(read.indices, cache.indices);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut data = cache().to_bytes();
+        data[4..8].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        let Err(error) = MeshCache::from_bytes("cube.obj.mesh", &data) else {
+            panic!("read a cache of another version");
+        };
+        assert!(error.to_string().contains("version"), "{}", error);
+
+        assert!(MeshCache::from_bytes("cube.obj.mesh", b"BMSH").is_err());
+        assert!(MeshCache::from_bytes("cube.obj.mesh", b"not a cache").is_err());
+    }
+
+    #[test]
+    fn truncated_caches_are_rejected() {
+        let data = cache().to_bytes();
+        for length in (0..data.len()).step_by(7) {
+            assert!(MeshCache::from_bytes("cube.obj.mesh", &data[..length]).is_err(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn out_of_range_submeshes_are_rejected() {
+        let mut cache = cache();
+        cache.indices[5] = 3;
+        assert!(MeshCache::from_bytes("cube.obj.mesh", &cache.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn corrupt_skins_are_rejected() {
+        let child = Bone { name: String::from("child"), parent: Some(0), transform: Transform::default() };
+
+        let mut valid = cache();
+        valid.skin.as_mut().unwrap().bones.push(child.clone());
+        assert!(MeshCache::from_bytes("cube.obj.mesh", &valid.to_bytes()).is_ok());
+
+        // Parents have to come first, a bone can't be its own parent either
+        for parent in [1, 2] {
+            let mut cache = cache();
+            cache.skin.as_mut().unwrap().bones.push(Bone { parent: Some(parent), ..child.clone() });
+            assert!(MeshCache::from_bytes("cube.obj.mesh", &cache.to_bytes()).is_err(), "parent {}", parent);
+        }
+
+        let mut cache = cache();
+        cache.skin.as_mut().unwrap().joints[0] = 1;
+        let Err(error) = MeshCache::from_bytes("cube.obj.mesh", &cache.to_bytes()) else {
+            panic!("read a joint without a bone");
+        };
+        assert!(error.to_string().contains("missing bone"), "{}", error);
+    }
+}
@@ -0,0 +1,183 @@
+use std::sync::OnceLock;
+
+use image::{Rgba32FImage, RgbaImage};
+
+/// How many levels a full mip chain for a `width` x `height` texture has,
+/// down to 1x1.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Downsamples `image` into a full mip chain, level 0 being a copy of the
+/// image itself. Color textures are averaged in linear space so they don't
+/// get darker with distance, normal maps are renormalized after averaging.
+pub fn generate_mipmaps(image: &RgbaImage, is_normal_map: bool) -> Vec<RgbaImage> {
+    let decode = if is_normal_map { decode_normal } else { decode_color };
+    let encode = if is_normal_map { encode_normal } else { encode_color };
+
+    let (mut width, mut height) = image.dimensions();
+    let mut level = image.pixels().map(|pixel| decode(pixel.0)).collect::<Vec<_>>();
+    let mut levels = vec![image.clone()];
+
+    for _ in 1..mip_level_count(width, height) {
+        (width, height, level) = downsample(&level, width, height, is_normal_map);
+        let pixels = level.iter().flat_map(|&texel| encode(texel)).collect();
+        levels.push(RgbaImage::from_raw(width, height, pixels).unwrap());
+    }
+    levels
+}
+
+/// Same as `generate_mipmaps` for float textures like HDR environment maps,
+/// which are linear already.
+pub fn generate_float_mipmaps(image: &Rgba32FImage) -> Vec<Rgba32FImage> {
+    let (mut width, mut height) = image.dimensions();
+    let mut level = image.pixels().map(|pixel| pixel.0).collect::<Vec<_>>();
+    let mut levels = vec![image.clone()];
+
+    for _ in 1..mip_level_count(width, height) {
+        (width, height, level) = downsample(&level, width, height, false);
+        let pixels = level.iter().flatten().copied().collect();
+        levels.push(Rgba32FImage::from_raw(width, height, pixels).unwrap());
+    }
+    levels
+}
+
+fn downsample(level: &[[f32; 4]], width: u32, height: u32, is_normal_map: bool) -> (u32, u32, Vec<[f32; 4]>) {
+    let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut next = Vec::with_capacity((next_width * next_height) as usize);
+    for y in 0..next_height {
+        for x in 0..next_width {
+            // 2x2 box filter, odd edges just reuse their last row or column
+            let mut sum = [0.0; 4];
+            for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let sx = (x * 2 + sx).min(width - 1);
+                let sy = (y * 2 + sy).min(height - 1);
+                let texel = level[(sy * width + sx) as usize];
+                for (sum, value) in sum.iter_mut().zip(texel) {
+                    *sum += value / 4.0;
+                }
+            }
+            if is_normal_map {
+                sum = renormalize(sum);
+            }
+            next.push(sum);
+        }
+    }
+    (next_width, next_height, next)
+}
+
+fn decode_color([r, g, b, a]: [u8; 4]) -> [f32; 4] {
+    // Only 256 possible inputs, cheaper than a powf for every texel
+    static LINEAR: OnceLock<[f32; 256]> = OnceLock::new();
+    let linear = LINEAR.get_or_init(|| {
+        std::array::from_fn(|value| {
+            let value = value as f32 / 255.0;
+            if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    });
+    [linear[r as usize], linear[g as usize], linear[b as usize], a as f32 / 255.0]
+}
+
+fn encode_color([r, g, b, a]: [f32; 4]) -> [u8; 4] {
+    let srgb = |value: f32| {
+        if value <= 0.0031308 {
+            value * 12.92
+        } else {
+            1.055 * value.powf(1.0 / 2.4) - 0.055
+        }
+    };
+    [to_u8(srgb(r)), to_u8(srgb(g)), to_u8(srgb(b)), to_u8(a)]
+}
+
+fn decode_normal([x, y, z, a]: [u8; 4]) -> [f32; 4] {
+    let signed = |value: u8| value as f32 / 255.0 * 2.0 - 1.0;
+    [signed(x), signed(y), signed(z), a as f32 / 255.0]
+}
+
+fn encode_normal([x, y, z, a]: [f32; 4]) -> [u8; 4] {
+    let unsigned = |value: f32| to_u8(value * 0.5 + 0.5);
+    [unsigned(x), unsigned(y), unsigned(z), to_u8(a)]
+}
+
+fn renormalize([x, y, z, a]: [f32; 4]) -> [f32; 4] {
+    let length = (x * x + y * y + z * z).sqrt();
+    if length > f32::EPSILON {
+        [x / length, y / length, z / length, a]
+    } else {
+        // Opposite normals cancelled out, fall back to facing straight up
+        [0.0, 0.0, 1.0, a]
+    }
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: [u8; 4], b: [u8; 4]) -> bool {
+        a.iter().zip(b).all(|(&a, b)| a.abs_diff(b) <= 1)
+    }
+
+    #[test]
+    fn level_counts() {
+        assert_eq!(mip_level_count(0, 0), 1);
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(256, 256), 9);
+        assert_eq!(mip_level_count(5, 3), 3);
+        assert_eq!(mip_level_count(1, 1024), 11);
+    }
+
+    #[test]
+    fn colors_are_averaged_in_linear_space() {
+        let image = RgbaImage::from_fn(2, 2, |x, y| {
+            if (x + y) % 2 == 0 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([255, 255, 255, 255]) }
+        });
+        let levels = generate_mipmaps(&image, false);
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[0], image);
+        // Half way in linear light is 188 in sRGB, not 128, alpha is linear already
+        assert!(close(levels[1].get_pixel(0, 0).0, [188, 188, 188, 128]), "{:?}", levels[1].get_pixel(0, 0));
+    }
+
+    #[test]
+    fn normals_are_renormalized() {
+        let right = image::Rgba([255, 128, 128, 255]);
+        let up = image::Rgba([128, 128, 255, 255]);
+        let image = RgbaImage::from_fn(2, 2, |x, _| if x == 0 { right } else { up });
+        let mip = generate_mipmaps(&image, true)[1].get_pixel(0, 0).0;
+        // Half way between the two at unit length, not the shorter average
+        assert!(close(mip, [218, 128, 218, 255]), "{:?}", mip);
+
+        // Opposite normals cancel out and fall back to facing up
+        let (a, b) = (image::Rgba([255, 0, 255, 255]), image::Rgba([0, 255, 0, 255]));
+        let image = RgbaImage::from_fn(2, 2, |x, _| if x == 0 { a } else { b });
+        let mip = generate_mipmaps(&image, true)[1].get_pixel(0, 0).0;
+        assert!(close(mip, [128, 128, 255, 255]), "{:?}", mip);
+    }
+
+    #[test]
+    fn odd_sizes_reach_one_by_one() {
+        let gray = image::Rgba([90, 140, 200, 77]);
+        let image = RgbaImage::from_pixel(5, 3, gray);
+        let levels = generate_mipmaps(&image, false);
+        let sizes = levels.iter().map(RgbaImage::dimensions).collect::<Vec<_>>();
+        assert_eq!(sizes, [(5, 3), (2, 1), (1, 1)]);
+        // A flat color stays flat, the clamped edges don't bleed anything in
+        for level in &levels {
+            assert!(level.pixels().all(|pixel| close(pixel.0, gray.0)));
+        }
+
+        let image = Rgba32FImage::from_pixel(1, 6, image::Rgba([2.0, 0.5, 0.0, 1.0]));
+        let levels = generate_float_mipmaps(&image);
+        let sizes = levels.iter().map(Rgba32FImage::dimensions).collect::<Vec<_>>();
+        assert_eq!(sizes, [(1, 6), (1, 3), (1, 1)]);
+        assert_eq!(levels[2].get_pixel(0, 0).0, [2.0, 0.5, 0.0, 1.0]);
+    }
+}
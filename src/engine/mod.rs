@@ -1,16 +1,37 @@
-use std::cell::{RefCell, RefMut};
+use std::cell::{Ref, RefCell, RefMut};
+use std::sync::Arc;
 use wgpu::util::DeviceExt;
 use winit::{
     window::Window,
     event::*
 };
 use cgmath::prelude::*;
+use anyhow::Context;
 use crate::ecs::component::*;
 
-mod texture;
+pub mod texture;
+pub mod sampler;
+pub mod pipeline;
+pub mod environment;
+pub mod shadow;
+pub mod debug_draw;
+pub mod gpu_image;
 pub mod model;
 pub mod resources;
+pub mod gltf_loader;
+pub mod mesh_cache;
+pub mod mipmap;
+pub mod animation;
+mod hot_reload;
+pub mod asset_io;
+pub mod pack;
+pub mod asset_error;
+pub mod asset_server;
+pub mod render_target;
+pub mod settings;
 mod camera;
+#[cfg(target_arch = "wasm32")]
+pub(crate) mod web;
 
 use model::Vertex;
 
@@ -53,11 +74,14 @@ struct LightUniform {
 pub struct State {
     // Window settings
     surface: wgpu::Surface,
+    pub adapter: wgpu::Adapter,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
     window: Window,
+    window_settings: settings::WindowSettings,
+    present_modes: Vec<wgpu::PresentMode>,
     clear_color: wgpu::Color,
 
     // Camera
@@ -70,25 +94,68 @@ pub struct State {
 
     // object (instanced)
     pub texture_bind_group_layout: wgpu::BindGroupLayout,
-    depth_texture: texture::Texture,
+    pub render_targets: render_target::RenderTargets,
+    depth_target: render_target::TargetId,
+    // The scene is drawn into this and resolved to the surface with MSAA on
+    // Only there while MSAA is on
+    msaa_target: Option<render_target::TargetId>,
+    msaa_settings: settings::MsaaSettings,
+    sample_count: u32,
+    supported_sample_counts: Vec<u32>,
+    // Drawn into for render targets whose sample count doesn't match, kept
+    // around since screenshots tend to come in series of the same size
+    offscreen_scratch: Option<render_target::RenderTarget>,
+    // The light gizmo
     obj_model: model::Model,
-    render_pipeline: wgpu::RenderPipeline,
+    // Filled in while drawing, which only borrows the state
+    pipelines: RefCell<pipeline::PipelineCache>,
 
     // light
     light_uniform: LightUniform,
     light_buffer: wgpu::Buffer,
     light_bind_group: wgpu::BindGroup,
+    unshadowed_light_bind_group: wgpu::BindGroup,
+    light_bind_group_layout: wgpu::BindGroupLayout,
+    environment: environment::Environment,
+    environment_buffer: wgpu::Buffer,
+    lights_uniform: shadow::LightsUniform,
+    lights_buffer: wgpu::Buffer,
+    unshadowed_lights_buffer: wgpu::Buffer,
+
+    // shadows
+    shadow_settings: settings::ShadowSettings,
+    shadow_maps: shadow::ShadowMaps,
+    // Shared models already warned about for conflicting Shadows
+    shadow_conflicts: RefCell<Vec<asset_server::Handle<model::Model>>>,
+    light_pipeline_layout: wgpu::PipelineLayout,
     light_render_pipeline: wgpu::RenderPipeline,
+    // The last light.wgsl that compiled, to rebuild the pipeline with
+    light_shader_source: String,
+    debug_renderer: debug_draw::DebugRenderer,
+
+    // animation
+    joint_bind_group_layout: wgpu::BindGroupLayout,
+    default_joints: animation::JointBuffer,
 
     pub mouse_pressed: bool,
     // ECS
     pub entity_count: usize,
     pub component_vecs: Vec<Box<dyn ComponentVec>>,
+    pub resources: Vec<Box<dyn std::any::Any>>,
+    pub asset_server: asset_server::AssetServer,
+    hot_reload: Option<hot_reload::HotReload>,
+    // (asset path, name) of shaders added with load_shader, hot reloaded
+    // from there instead of the shader directory
+    shader_files: Vec<(String, String)>,
 }
 
 impl State {
     // Creating some of the wgpu types requires async code
-    pub async fn new(window: Window) -> Self {
+    pub async fn new(
+        window: Window,
+        settings: &settings::RendererSettings,
+        window_settings: &settings::WindowSettings,
+    ) -> anyhow::Result<Self> {
         let size = window.inner_size();
         let clear_color = wgpu::Color {
             r: 0.1,
@@ -98,11 +165,9 @@ impl State {
         };
 
         // The instance is a handle to our GPU
-        // Backends::all => Vulkan + Metal + DX12 + Browser WebGPU
+        let backends = settings.backends();
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            // Workaround for https://github.com/gfx-rs/wgpu/issues/2540,
-            // set backend to Vulkan for now
-            backends: wgpu::Backends::from_bits_truncate(1 << wgpu::Backend::Vulkan as u32),
+            backends,
             dx12_shader_compiler: Default::default(),
         });
         
@@ -110,30 +175,73 @@ impl State {
         //
         // The surface needs to live as long as the window that created it.
         // State owns the window so this should be safe.
-        let surface = unsafe { instance.create_surface(&window) }.unwrap();
+        let surface = unsafe { instance.create_surface(&window) }
+            .context("Failed to create a surface for the window")?;
 
+        let power_preference = settings.power_preference();
         let adapter = instance.request_adapter(
             &wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
+                power_preference,
                 compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
+                force_fallback_adapter: settings.force_fallback_adapter,
             },
-        ).await.unwrap();
+        ).await;
+        let adapter = match adapter {
+            Some(adapter) => adapter,
+            None => {
+                #[cfg(not(target_arch = "wasm32"))]
+                let available = instance
+                    .enumerate_adapters(wgpu::Backends::all())
+                    .map(|a| {
+                        let info = a.get_info();
+                        format!("{} ({:?}, {:?})", info.name, info.backend, info.device_type)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                #[cfg(target_arch = "wasm32")]
+                let available = String::from("unknown");
+
+                anyhow::bail!(
+                    "No graphics adapter matches backends {:?}, power preference {:?} and force_fallback_adapter = {}. Available adapters: [{}]",
+                    backends,
+                    power_preference,
+                    settings.force_fallback_adapter,
+                    available,
+                );
+            }
+        };
+        let adapter_info = adapter.get_info();
+        log::info!("Using adapter {} ({:?}, {:?})", adapter_info.name, adapter_info.backend, adapter_info.device_type);
+
+        let missing_features = settings.required_features - adapter.features();
+        if !missing_features.is_empty() {
+            anyhow::bail!("Adapter {} is missing required features {:?}", adapter_info.name, missing_features);
+        }
+        let features = settings.required_features | (settings.optional_features & adapter.features());
+
+        let limits = settings.limits();
+        if !limits.check_limits(&adapter.limits()) {
+            anyhow::bail!(
+                "Adapter {} doesn't support the requested limits, it supports {:?}",
+                adapter_info.name,
+                adapter.limits(),
+            );
+        }
     
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                // WebGL doesn't support all of wgpu's features, so if
-                // we're building for the web we'll have to disable some.
-                limits: if cfg!(target_arch = "wasm32") {
-                    wgpu::Limits::downlevel_webgl2_defaults()
-                } else {
-                    wgpu::Limits::default()
-                },
+                features,
+                limits,
                 label: None,
             },
             None, // Trace path
-        ).await.unwrap();
+        ).await
+        .with_context(|| format!("Failed to create a device on adapter {}", adapter_info.name))?;
+
+        // gpu_scope can't catch errors on the web, log them instead of the
+        // default panic so a broken asset doesn't take the page down
+        #[cfg(target_arch = "wasm32")]
+        device.on_uncaptured_error(Box::new(|error| log::error!("{}", error)));
 
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
@@ -141,8 +249,7 @@ impl State {
         // sRGB surfaces, you'll need to account for that when drawing to the frame.
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.describe().srgb)
-            .next()
+            .find(|f| f.describe().srgb)
             .unwrap_or(surface_caps.formats[0]);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -150,52 +257,13 @@ impl State {
             // make sure width and height are not 0
             width: size.width,
             height: size.height,
-            present_mode: surface_caps.present_modes[0],
+            present_mode: select_present_mode(window_settings.present_mode, &surface_caps.present_modes),
             alpha_mode: surface_caps.alpha_modes[0],
             view_formats: vec![],
         };
         surface.configure(&device, &config);
 
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        // This should match the filterable field of the
-                        // corresponding Texture entry above.
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: Some("texture_bind_group_layout"),
-            });
+        let texture_bind_group_layout = model::Material::create_bind_group_layout(&device);
 
         let camera = camera::Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0));
         let projection = camera::Projection::new(config.width, config.height, cgmath::Deg(45.0), 0.1, 100.0);
@@ -239,7 +307,20 @@ impl State {
             label: Some("camera_bind_group"),
         });
 
-        let depth_texture = texture::Texture::create_depth_texture_non_comparison_sampler(&device, &config, "depth_texture");
+        let supported_sample_counts = supported_sample_counts(&adapter, &device, config.format, texture::Texture::DEPTH_FORMAT);
+        let msaa_settings = settings::MsaaSettings::default();
+        let sample_count = select_sample_count(msaa_settings.samples, &supported_sample_counts);
+
+        let mut render_targets = render_target::RenderTargets::new(config.width, config.height);
+        let depth_target = render_targets.register(
+            &device,
+            render_target::TargetDescriptor {
+                sample_count,
+                ..render_target::TargetDescriptor::depth("depth_texture")
+            },
+        );
+        let msaa_target = (sample_count > 1)
+            .then(|| render_targets.register(&device, msaa_target_descriptor(config.format, sample_count)));
 
         let light_uniform = LightUniform {
             position: [2.0, 2.0, 2.0],
@@ -257,11 +338,93 @@ impl State {
             }
         );
         
+        // The environment lights the scene from everywhere else, see
+        // environment::Environment
         let light_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::Cube,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // The sun and spot lights with their shadow maps, see
+                    // shadow::LightsUniform
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
+                            sample_type: wgpu::TextureSampleType::Depth,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                        count: None,
+                    },
+                ],
+                label: None,
+            });
+
+        // A dim grey sky until the game sets its own
+        let environment = environment::Environment::uniform_color(&device, &queue, [0.1; 3])?;
+        let environment_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Environment Buffer"),
+                contents: bytemuck::cast_slice(&[environment.uniform()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let joint_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::VERTEX,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -269,17 +432,54 @@ impl State {
                     },
                     count: None,
                 }],
-                label: None,
+                label: Some("joint_bind_group_layout"),
             });
+        // Bound for models without a skin, the shader doesn't read it for them
+        let default_joints = animation::JointBuffer::new(&device, &joint_bind_group_layout, &[]);
 
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-            label: None,
-        });
+        // Filled in every update from the light components
+        let shadow_settings = settings::ShadowSettings::default();
+        let shadow_maps = shadow::ShadowMaps::new(
+            &device,
+            shadow_settings.map_size,
+            &joint_bind_group_layout,
+            &texture_bind_group_layout,
+        );
+        let lights_uniform = shadow::LightsUniform::new(None, &[], &camera, &projection, &shadow_settings);
+        let lights_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Lights Buffer"),
+                contents: bytemuck::cast_slice(&[lights_uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        // The same lights with shadows turned off, for models that don't
+        // receive them
+        let unshadowed_lights_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Unshadowed Lights Buffer"),
+                contents: bytemuck::cast_slice(&[lights_uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+        let light_bind_group = create_light_bind_group(
+            &device,
+            &light_bind_group_layout,
+            &light_buffer,
+            &lights_buffer,
+            &environment,
+            &environment_buffer,
+            &shadow_maps,
+        );
+        let unshadowed_light_bind_group = create_light_bind_group(
+            &device,
+            &light_bind_group_layout,
+            &light_buffer,
+            &unshadowed_lights_buffer,
+            &environment,
+            &environment_buffer,
+            &shadow_maps,
+        );
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -288,62 +488,76 @@ impl State {
                     &texture_bind_group_layout,
                     &camera_bind_group_layout,
                     &light_bind_group_layout,
+                    &joint_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
-            };
-            create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[
-                    model::ModelVertex::desc(),
-                    model::InstanceRaw::desc()
-                ],
-                shader,
-            )
-        };
+        let pipelines = pipeline::PipelineCache::new(
+            &device,
+            render_pipeline_layout,
+            config.format,
+            texture::Texture::DEPTH_FORMAT,
+            sample_count,
+        );
 
+        let light_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Light Pipeline Layout"),
+            bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
+            push_constant_ranges: &[],
+        });
         let light_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
-                push_constant_ranges: &[],
-            });
             let shader = wgpu::ShaderModuleDescriptor {
                 label: Some("Light Shader"),
                 source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
             };
             create_render_pipeline(
                 &device,
-                &layout,
+                &light_pipeline_layout,
                 config.format,
                 Some(texture::Texture::DEPTH_FORMAT),
+                sample_count,
                 &[model::ModelVertex::desc()],
                 shader,
             )
         };
 
+        let debug_renderer = debug_draw::DebugRenderer::new(
+            &device,
+            &camera_bind_group_layout,
+            config.format,
+            texture::Texture::DEPTH_FORMAT,
+            sample_count,
+        );
+
         let obj_model =
             resources::load_model("cube.obj", &device, &queue, &texture_bind_group_layout)
-                .await
-                .unwrap();
+                .await?;
 
-        Self {
+        // The builder already took care of everything but these
+        let applied_settings = settings::WindowSettings {
+            mode: match window_settings.mode {
+                settings::WindowMode::Fullscreen => settings::WindowMode::Windowed,
+                mode => mode,
+            },
+            cursor_grab: false,
+            cursor_visible: true,
+            icon: None,
+            ..window_settings.clone()
+        };
+
+        let mut state = Self {
             window,
+            window_settings: applied_settings,
+            present_modes: surface_caps.present_modes,
             surface,
+            adapter,
             device,
             queue,
             config,
             size,
             clear_color,
-            render_pipeline,
+            pipelines: RefCell::new(pipelines),
             camera,
             projection,
             camera_controller,
@@ -353,29 +567,146 @@ impl State {
             // instances,
             // instance_buffer,
             texture_bind_group_layout,
-            depth_texture,
+            render_targets,
+            depth_target,
+            msaa_target,
+            msaa_settings,
+            sample_count,
+            supported_sample_counts,
+            offscreen_scratch: None,
             obj_model,
             light_uniform,
             light_buffer,
             light_bind_group,
+            unshadowed_light_bind_group,
+            light_bind_group_layout,
+            environment,
+            environment_buffer,
+            lights_uniform,
+            lights_buffer,
+            unshadowed_lights_buffer,
+            shadow_settings,
+            shadow_maps,
+            shadow_conflicts: RefCell::new(Vec::new()),
+            light_pipeline_layout,
             light_render_pipeline,
+            light_shader_source: include_str!("light.wgsl").to_string(),
+            debug_renderer,
+            joint_bind_group_layout,
+            default_joints,
             #[allow(dead_code)]
             mouse_pressed: false,
             entity_count: 0,
             component_vecs: Vec::new(),
-        }
+            resources: Vec::new(),
+            asset_server: asset_server::AssetServer::new(),
+            hot_reload: None,
+            shader_files: Vec::new(),
+        };
+        state.apply_window_settings(window_settings);
+
+        Ok(state)
     }
 
     pub fn window(&self) -> &Window {
         &self.window
     }
 
+    /// Applies whatever differs between `settings` and the current window.
+    pub fn apply_window_settings(&mut self, settings: &settings::WindowSettings) {
+        use winit::dpi::LogicalSize;
+        use winit::window::{CursorGrabMode, Fullscreen};
+
+        if *settings == self.window_settings {
+            return;
+        }
+        let current = std::mem::replace(&mut self.window_settings, settings.clone());
+
+        if settings.title != current.title {
+            self.window.set_title(&settings.title);
+        }
+        if (settings.width, settings.height) != (current.width, current.height) {
+            self.window.set_inner_size(LogicalSize::new(settings.width, settings.height));
+        }
+        if settings.min_size != current.min_size {
+            self.window.set_min_inner_size(settings.min_size.map(|(w, h)| LogicalSize::new(w, h)));
+        }
+        if settings.resizable != current.resizable {
+            self.window.set_resizable(settings.resizable);
+        }
+        if settings.mode != current.mode {
+            let fullscreen = match settings.mode {
+                settings::WindowMode::Windowed => None,
+                settings::WindowMode::BorderlessFullscreen => Some(Fullscreen::Borderless(None)),
+                settings::WindowMode::Fullscreen => {
+                    let video_mode = self.window.current_monitor().and_then(|monitor| {
+                        monitor.video_modes().max_by_key(|mode| {
+                            let size = mode.size();
+                            (size.width * size.height, mode.refresh_rate_millihertz())
+                        })
+                    });
+                    match video_mode {
+                        Some(video_mode) => Some(Fullscreen::Exclusive(video_mode)),
+                        None => {
+                            log::warn!("No video mode available for exclusive fullscreen, using borderless");
+                            Some(Fullscreen::Borderless(None))
+                        }
+                    }
+                }
+            };
+            self.window.set_fullscreen(fullscreen);
+        }
+        if settings.cursor_grab != current.cursor_grab {
+            let result = if settings.cursor_grab {
+                // Not every platform supports both modes
+                self.window.set_cursor_grab(CursorGrabMode::Confined)
+                    .or_else(|_| self.window.set_cursor_grab(CursorGrabMode::Locked))
+            } else {
+                self.window.set_cursor_grab(CursorGrabMode::None)
+            };
+            if let Err(e) = result {
+                log::warn!("Couldn't change cursor grab: {}", e);
+            }
+        }
+        if settings.cursor_visible != current.cursor_visible {
+            self.window.set_cursor_visible(settings.cursor_visible);
+        }
+        // Browsers don't have window icons and can't block on loading one
+        #[cfg(not(target_arch = "wasm32"))]
+        if settings.icon != current.icon {
+            let icon = settings.icon.as_ref().and_then(|file_name| {
+                match pollster::block_on(resources::load_window_icon(file_name)) {
+                    Ok(icon) => Some(icon),
+                    Err(e) => {
+                        log::warn!("Couldn't load window icon {}: {}", file_name, e);
+                        None
+                    }
+                }
+            });
+            self.window.set_window_icon(icon);
+        }
+        if settings.present_mode != current.present_mode {
+            self.config.present_mode = select_present_mode(settings.present_mode, &self.present_modes);
+            self.surface.configure(&self.device, &self.config);
+        }
+    }
+
+    /// Keeps the stored window size in sync when the user resizes the window.
+    pub(crate) fn window_resized(&mut self, new_size: winit::dpi::PhysicalSize<u32>) -> (u32, u32) {
+        let logical = new_size.to_logical::<u32>(self.window.scale_factor());
+        self.window_settings.width = logical.width;
+        self.window_settings.height = logical.height;
+        self.resize(new_size);
+        (logical.width, logical.height)
+    }
+
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
             self.surface.configure(&self.device, &self.config);
+            self.render_targets.resize(&self.device, new_size.width, new_size.height);
             self.projection.resize(new_size.width, new_size.height);
         }
     }
@@ -410,7 +741,265 @@ impl State {
         }
     }
 
+    /// Development mode: picks up changes to the WGSL shaders and to every
+    /// file loaded through the asset server while running. Shaders are
+    /// watched in `shader_dir`, or `HotReload::default_shader_dir` if `None`.
+    pub fn enable_hot_reload(&mut self, shader_dir: Option<std::path::PathBuf>) {
+        if cfg!(target_arch = "wasm32") {
+            log::warn!("Hot reloading needs a file system and isn't available on the web");
+            return;
+        }
+        let hot_reload = hot_reload::HotReload::new(shader_dir.unwrap_or_else(hot_reload::HotReload::default_shader_dir));
+        log::info!("Hot reloading shaders from {:?} and assets from {:?}", hot_reload.shader_dir, resources::asset_root());
+        self.hot_reload = Some(hot_reload);
+    }
+
+    fn poll_hot_reload(&mut self) {
+        let shader_files = self.shader_files.iter().map(|(file, _)| file.clone()).collect::<Vec<_>>();
+        let Some(changes) = self
+            .hot_reload
+            .as_mut()
+            .and_then(|hot_reload| hot_reload.poll(&self.asset_server, &shader_files))
+        else {
+            return;
+        };
+        for shader in &changes.shaders {
+            self.reload_shader(shader);
+        }
+        let changed_files = self
+            .shader_files
+            .iter()
+            .filter(|(file, _)| changes.assets.contains(file))
+            .cloned()
+            .collect::<Vec<_>>();
+        for (file, name) in changed_files {
+            self.reload_shader_file(&name, &file);
+        }
+        self.asset_server.reload_changed(&changes.assets);
+    }
+
+    /// Compiles the shader `name` again from the asset `file_name`, keeping
+    /// the old pipelines if it doesn't compile.
+    fn reload_shader_file(&mut self, name: &str, file_name: &str) {
+        resources::forget_preloaded(file_name);
+        let source = match pollster::block_on(resources::load_string(file_name)) {
+            Ok(source) => source,
+            Err(e) => {
+                log::error!("Couldn't read {}: {}", file_name, e);
+                return;
+            }
+        };
+        match self.pipelines.get_mut().add_shader(&self.device, name, &source) {
+            Ok(()) => log::info!("Reloaded {} from {}", name, file_name),
+            Err(e) => log::error!("Keeping the previous {}, the new one failed to compile: {}", name, e),
+        }
+    }
+
+    /// Rebuilds the pipelines using `name` from the shader directory. If the
+    /// new shader doesn't compile the old pipelines stay in use.
+    fn reload_shader(&mut self, name: &str) {
+        let Some(hot_reload) = &self.hot_reload else {
+            return;
+        };
+        if name != "light.wgsl" && !self.pipelines.get_mut().has_shader(name) {
+            return;
+        }
+        // Those come from their own file, see reload_shader_file
+        if self.shader_files.iter().any(|(_, shader)| shader == name) {
+            return;
+        }
+        let source = match hot_reload.read_shader(name) {
+            Ok(source) => source,
+            Err(e) => {
+                log::error!("Couldn't read {}: {}", name, e);
+                return;
+            }
+        };
+
+        if name != "light.wgsl" {
+            match self.pipelines.get_mut().add_shader(&self.device, name, &source) {
+                Ok(()) => log::info!("Reloaded {}", name),
+                Err(e) => log::error!("Keeping the previous {}, the new one failed to compile: {}", name, e),
+            }
+            return;
+        }
+
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let pipeline = create_render_pipeline(
+            &self.device,
+            &self.light_pipeline_layout,
+            self.config.format,
+            Some(texture::Texture::DEPTH_FORMAT),
+            self.sample_count,
+            &[model::ModelVertex::desc()],
+            wgpu::ShaderModuleDescriptor {
+                label: Some(name),
+                source: wgpu::ShaderSource::Wgsl(source.as_str().into()),
+            },
+        );
+        if let Some(error) = pollster::block_on(self.device.pop_error_scope()) {
+            log::error!("Keeping the previous {}, the new one failed to compile: {}", name, error);
+            return;
+        }
+
+        log::info!("Reloaded {}", name);
+        self.light_render_pipeline = pipeline;
+        self.light_shader_source = source;
+    }
+
+    /// Makes a WGSL shader available to materials as `name`, see
+    /// `MaterialPipeline::shader`. It's drawn with the same bind groups
+    /// (material, camera, light, joints) and vertex buffers as `shader.wgsl`
+    /// and needs `vs_main` and `fs_main` entry points. Adding a shader under
+    /// an existing name replaces it.
+    pub fn add_shader(&mut self, name: &str, source: &str) -> Result<(), asset_error::AssetError> {
+        self.pipelines.get_mut().add_shader(&self.device, name, source)
+    }
+
+    /// Adds the shader at the asset path `file_name` as `name`, see
+    /// `add_shader`. Hot reloading watches that file.
+    pub async fn load_shader(&mut self, name: &str, file_name: &str) -> Result<(), asset_error::AssetError> {
+        let source = resources::load_string(file_name).await?;
+        self.add_shader(name, &source)?;
+        self.shader_files.retain(|(_, shader)| shader != name);
+        self.shader_files.push((file_name.to_string(), name.to_string()));
+        Ok(())
+    }
+
+    /// Replaces the light coming from everywhere around the scene, used for
+    /// ambient light and reflections.
+    pub fn set_environment(&mut self, environment: environment::Environment) {
+        self.queue.write_buffer(&self.environment_buffer, 0, bytemuck::cast_slice(&[environment.uniform()]));
+        self.environment = environment;
+        self.create_light_bind_groups();
+    }
+
+    pub fn environment(&self) -> &environment::Environment {
+        &self.environment
+    }
+
+    /// Applies changes to the `ShadowSettings` resource, see
+    /// `apply_window_settings`.
+    pub fn apply_shadow_settings(&mut self, settings: &settings::ShadowSettings) {
+        // A zero sized texture can't be created
+        let settings = &settings::ShadowSettings { map_size: settings.map_size.max(1), ..*settings };
+        if settings.map_size != self.shadow_maps.size {
+            self.shadow_maps.resize(&self.device, settings.map_size);
+            self.create_light_bind_groups();
+        }
+        self.shadow_settings = *settings;
+    }
+
+    /// Applies changes to the `MsaaSettings` resource, see
+    /// `apply_window_settings`. Unsupported sample counts fall back to the
+    /// next lower supported one.
+    pub fn apply_msaa_settings(&mut self, settings: &settings::MsaaSettings) {
+        if *settings == self.msaa_settings {
+            return;
+        }
+        self.msaa_settings = *settings;
+        let sample_count = select_sample_count(settings.samples, &self.supported_sample_counts);
+        if sample_count == self.sample_count {
+            return;
+        }
+        self.sample_count = sample_count;
+
+        let depth = render_target::TargetDescriptor {
+            sample_count,
+            ..*self.render_targets.descriptor(self.depth_target)
+        };
+        self.render_targets.update(&self.device, self.depth_target, depth);
+        let msaa = msaa_target_descriptor(self.config.format, sample_count);
+        match (self.msaa_target, sample_count > 1) {
+            (Some(id), true) => self.render_targets.update(&self.device, id, msaa),
+            (Some(id), false) => {
+                self.render_targets.unregister(id);
+                self.msaa_target = None;
+            }
+            (None, true) => self.msaa_target = Some(self.render_targets.register(&self.device, msaa)),
+            (None, false) => {}
+        }
+
+        // Every pipeline drawing into them has to match
+        self.pipelines.get_mut().set_sample_count(sample_count);
+        self.debug_renderer.set_sample_count(&self.device, sample_count);
+        self.light_render_pipeline = create_render_pipeline(
+            &self.device,
+            &self.light_pipeline_layout,
+            self.config.format,
+            Some(texture::Texture::DEPTH_FORMAT),
+            sample_count,
+            &[model::ModelVertex::desc()],
+            wgpu::ShaderModuleDescriptor {
+                label: Some("Light Shader"),
+                source: wgpu::ShaderSource::Wgsl(self.light_shader_source.as_str().into()),
+            },
+        );
+        log::info!("Drawing with {}x MSAA", sample_count);
+    }
+
+    /// MSAA sample counts the adapter supports for the surface, 1 and 4 at
+    /// least.
+    pub fn supported_sample_counts(&self) -> &[u32] {
+        &self.supported_sample_counts
+    }
+
+    /// The sample count the scene is drawn with, see `MsaaSettings`.
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    // After anything they point to was replaced
+    fn create_light_bind_groups(&mut self) {
+        self.light_bind_group = create_light_bind_group(
+            &self.device,
+            &self.light_bind_group_layout,
+            &self.light_buffer,
+            &self.lights_buffer,
+            &self.environment,
+            &self.environment_buffer,
+            &self.shadow_maps,
+        );
+        self.unshadowed_light_bind_group = create_light_bind_group(
+            &self.device,
+            &self.light_bind_group_layout,
+            &self.light_buffer,
+            &self.unshadowed_lights_buffer,
+            &self.environment,
+            &self.environment_buffer,
+            &self.shadow_maps,
+        );
+    }
+
+    /// Gathers the `DirectionalLight` and `SpotLight` components and fits
+    /// the shadow maps to the camera.
+    fn update_lights(&mut self) {
+        let lights = {
+            let suns = self.borrow_component_vec::<shadow::DirectionalLight>();
+            let sun = suns.as_ref().and_then(|suns| suns.iter().flatten().next());
+            let spots = self.borrow_component_vec::<shadow::SpotLight>();
+            let spots: Vec<_> = spots.iter().flat_map(|spots| spots.iter().flatten()).collect();
+            shadow::LightsUniform::new(sun, &spots, &self.camera, &self.projection, &self.shadow_settings)
+        };
+
+        if self.shadow_maps.fit(&self.device, &lights) {
+            self.create_light_bind_groups();
+        }
+        self.queue.write_buffer(&self.lights_buffer, 0, bytemuck::cast_slice(&[lights]));
+        self.queue.write_buffer(&self.unshadowed_lights_buffer, 0, bytemuck::cast_slice(&[lights.without_shadows()]));
+        self.lights_uniform = lights;
+    }
+
     pub fn update(&mut self, dt: instant::Duration) {
+        self.poll_hot_reload();
+        self.asset_server.update(&asset_server::UploadContext {
+            device: &self.device,
+            queue: &self.queue,
+            texture_bind_group_layout: &self.texture_bind_group_layout,
+        });
+
+        self.animate(dt.as_secs_f32());
+
         // TODO: Abstract player controls away from Camera
         self.camera_controller.update_camera(&mut self.camera, dt);
         self.camera_uniform.update_view_proj(&self.camera, &self.projection);
@@ -421,6 +1010,8 @@ impl State {
             (cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(60.0 * dt.as_secs_f32()))
                 * old_position).into();
         self.queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light_uniform]));
+
+        self.update_lights();
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -430,65 +1021,15 @@ impl State {
             label: Some("Render Encoder"),
         });
 
-        {
-            use model::DrawModel;
-
-            if let Some(mut models) = self.borrow_component_vec_mut::<model::Model>() {
-                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("Render Pass"),
-                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                        view: &view,
-                        resolve_target: None,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(self.clear_color),
-                            store: true,
-                        },
-                    })],
-                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                        view: &self.depth_texture.view,
-                        depth_ops: Some(wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(1.0),
-                            store: true,
-                        }),
-                        stencil_ops: None,
-                    }),
-                });
-
-                // Draw all lights
-                // TODO: add support for different shaders
-                /*
-                use model::DrawLight;
-                render_pass.set_pipeline(&self.light_render_pipeline);
-                render_pass.draw_light_model_instanced(
-                    &self.obj_model,
-                    0..1,
-                    &self.camera_bind_group,
-                    &self.light_bind_group,
-                );
-                */
-
-                // Draw all models
-                for model in models.iter_mut() {
-                    if let Some(model) = model {
-                        render_pass.set_pipeline(&self.render_pipeline);
-
-                        // derive the instance buffer
-                        let instance_data = model.instances.iter().map(model::Instance::to_raw).collect::<Vec<_>>();
-                        model.instance_buffer = self.device.create_buffer_init(
-                            &wgpu::util::BufferInitDescriptor {
-                                label: Some("Instance Buffer"),
-                                contents: bytemuck::cast_slice(&instance_data),
-                                usage: wgpu::BufferUsages::VERTEX,
-                            }
-                        );
-
-                        render_pass.set_vertex_buffer(1, model.instance_buffer.slice(..));
-                        render_pass.draw_model(model, 0..model.instances.len() as u32, &self.camera_bind_group, &self.light_bind_group);
-                    }
-                } 
-
+        self.prepare_instances();
+        self.prepare_debug_lines();
+        let depth_view = &self.render_targets.get(self.depth_target).view;
+        match self.msaa_target {
+            Some(msaa_target) => {
+                let msaa_view = &self.render_targets.get(msaa_target).view;
+                self.draw_scene(&mut encoder, msaa_view, Some(&view), depth_view);
             }
-
+            None => self.draw_scene(&mut encoder, &view, None, depth_view),
         }
 
         // submit will accept anything that implements IntoIter
@@ -498,9 +1039,398 @@ impl State {
         Ok(())
     }
 
+    /// The format render targets have to use, see `render_to_target`.
+    pub fn surface_format(&self) -> wgpu::TextureFormat {
+        self.config.format
+    }
+
+    /// Creates an offscreen target with the current MSAA sample count, which
+    /// `render_to_target` draws into without any extra textures.
+    pub fn create_render_target(&self, width: u32, height: u32) -> render_target::RenderTarget {
+        render_target::RenderTarget::multisampled(&self.device, width, height, self.config.format, self.sample_count)
+    }
+
+    /// Draws the scene into `target` instead of the surface. The projection is
+    /// temporarily adjusted to the target's aspect ratio. Targets with another
+    /// sample count than the pipelines, like those from `RenderTarget::new`
+    /// with MSAA on, are drawn through textures of the state's own and
+    /// resolved into `target.color`. The pipelines are built for the
+    /// surface's format, targets with any other format are rejected.
+    pub fn render_to_target(&mut self, target: &render_target::RenderTarget) -> anyhow::Result<()> {
+        if target.format != self.config.format {
+            anyhow::bail!(
+                "Can't draw into a {:?} render target, the pipelines are built for the surface's {:?}",
+                target.format,
+                self.config.format,
+            );
+        }
+        let matches = target.sample_count == self.sample_count;
+        if !matches {
+            let scratch = &self.offscreen_scratch;
+            let stale = scratch.as_ref().is_none_or(|scratch| {
+                (scratch.width, scratch.height, scratch.format, scratch.sample_count)
+                    != (target.width, target.height, target.format, self.sample_count)
+            });
+            if stale {
+                self.offscreen_scratch = Some(render_target::RenderTarget::multisampled(
+                    &self.device,
+                    target.width,
+                    target.height,
+                    target.format,
+                    self.sample_count,
+                ));
+            }
+        }
+        self.projection.resize(target.width, target.height);
+        self.camera_uniform.update_view_proj(&self.camera, &self.projection);
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Offscreen Render Encoder"),
+        });
+        self.prepare_instances();
+        self.prepare_debug_lines();
+        // Only the scratch's multisampled textures are used, the result
+        // always ends up in the target's color
+        let drawn_with = match &self.offscreen_scratch {
+            Some(scratch) if !matches => scratch,
+            _ => target,
+        };
+        match &drawn_with.msaa_color {
+            Some(msaa_color) => self.draw_scene(&mut encoder, &msaa_color.view, Some(&target.color.view), &drawn_with.depth.view),
+            None => self.draw_scene(&mut encoder, &target.color.view, None, &drawn_with.depth.view),
+        }
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        // Restore the surface projection for the next frame
+        self.projection.resize(self.config.width, self.config.height);
+        self.camera_uniform.update_view_proj(&self.camera, &self.projection);
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+        Ok(())
+    }
+
+    /// Renders the current frame at surface resolution and reads it back.
+    pub fn capture_frame(&mut self) -> anyhow::Result<image::RgbaImage> {
+        let target = self.create_render_target(self.config.width, self.config.height);
+        self.render_to_target(&target)?;
+        target.read_image(&self.device, &self.queue)
+    }
+
+    /// Writes the current frame to `path` as a PNG.
+    pub fn screenshot<P: AsRef<std::path::Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let image = self.capture_frame()?;
+        image.save_with_format(path, image::ImageFormat::Png)?;
+        Ok(())
+    }
+
+    /// Rebuilds instance buffers for every model that is about to be drawn.
+    fn prepare_instances(&mut self) {
+        // Transparent models are drawn back to front, their instances too
+        let eye = self.camera.position.to_vec();
+        let update = |model: &mut model::Model| {
+            if model.is_transparent() {
+                model.update_instance_buffer_back_to_front(&self.device, eye);
+            } else {
+                model.update_instance_buffer(&self.device);
+            }
+        };
+
+        if let Some(mut models) = self.borrow_component_vec_mut::<model::Model>() {
+            for model in models.iter_mut().flatten() {
+                update(model);
+            }
+        }
+
+        let handles = self.model_handles();
+        for handle in handles {
+            if let Some(model) = self.asset_server.get_mut(&handle) {
+                update(model);
+            }
+        }
+    }
+
+    fn show_lights(&self) -> bool {
+        self.resource::<debug_draw::DebugDraw>().is_some_and(|debug| debug.enabled && debug.show_lights)
+    }
+
+    /// Uploads the lines of the `DebugDraw` resource, plus the light markers
+    /// if it asks for them.
+    fn prepare_debug_lines(&mut self) {
+        let mut vertices = match self.resource::<debug_draw::DebugDraw>() {
+            Some(debug) if debug.enabled => debug.vertices().to_vec(),
+            _ => Vec::new(),
+        };
+        if self.show_lights() {
+            vertices.extend_from_slice(self.light_markers().vertices());
+        }
+        self.debug_renderer.upload(&self.device, &self.queue, &vertices);
+    }
+
+    /// Advances every `AnimationPlayer` and poses the model of its entity.
+    fn animate(&self, dt: f32) {
+        let Some(mut players) = self.borrow_component_vec_mut::<animation::AnimationPlayer>() else {
+            return;
+        };
+        let models = self.borrow_component_vec::<model::Model>();
+        let handles = self.borrow_component_vec::<asset_server::Handle<model::Model>>();
+
+        for (entity, player) in players.iter_mut().enumerate() {
+            let Some(player) = player else {
+                continue;
+            };
+            player.advance(dt, &self.asset_server);
+
+            let model = models
+                .as_ref()
+                .and_then(|models| models[entity].as_ref())
+                .or_else(|| {
+                    let handle = handles.as_ref()?[entity].as_ref()?;
+                    self.asset_server.get(handle)
+                });
+            let Some((model, skin)) = model.and_then(|model| Some((model, model.skin.as_ref()?))) else {
+                continue;
+            };
+            let pose = player.sample(skin, &self.asset_server);
+            self.joints(model).write(&self.queue, &skin.joint_matrices(&pose));
+        }
+    }
+
+    fn joints<'a>(&'a self, model: &'a model::Model) -> &'a animation::JointBuffer {
+        let Some(skin) = &model.skin else {
+            return &self.default_joints;
+        };
+        model.joints.get_or_init(|| {
+            animation::JointBuffer::new(&self.device, &self.joint_bind_group_layout, &skin.joint_matrices(&skin.rest_pose()))
+        })
+    }
+
+    /// Every model handle used by an entity, without duplicates.
+    fn model_handles(&self) -> Vec<asset_server::Handle<model::Model>> {
+        let mut handles = Vec::new();
+        if let Some(components) = self.borrow_component_vec::<asset_server::Handle<model::Model>>() {
+            for handle in components.iter().flatten() {
+                if !handles.contains(handle) {
+                    handles.push(*handle);
+                }
+            }
+        }
+        handles
+    }
+
+    fn draw_scene(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        color_view: &wgpu::TextureView,
+        resolve_target: Option<&wgpu::TextureView>,
+        depth_view: &wgpu::TextureView,
+    ) {
+        let models = self.borrow_component_vec::<model::Model>();
+        let handles = self.borrow_component_vec::<asset_server::Handle<model::Model>>();
+        let shadows = self.borrow_component_vec::<shadow::Shadows>();
+        let shadows_of = |entity: usize| {
+            shadows.as_ref().and_then(|shadows| shadows[entity]).unwrap_or_default()
+        };
+
+        // Models shared through the asset server are drawn once, with the
+        // shadow flags of the first entity using them. Their instances are
+        // shared too, so there's nothing to draw the others with.
+        let mut seen: Vec<(asset_server::Handle<model::Model>, shadow::Shadows)> = Vec::new();
+        let mut loaded = Vec::new();
+        for (entity, handle) in handles.iter().flat_map(|handles| handles.iter().enumerate()) {
+            let Some(handle) = handle else {
+                continue;
+            };
+            if let Some((_, first)) = seen.iter().find(|(seen, _)| seen == handle) {
+                let mut conflicts = self.shadow_conflicts.borrow_mut();
+                if *first != shadows_of(entity) && !conflicts.contains(handle) {
+                    conflicts.push(*handle);
+                    log::warn!(
+                        "Entity {} shares its model with an entity that has different Shadows, only the first entity's are used",
+                        entity,
+                    );
+                }
+                continue;
+            }
+            seen.push((*handle, shadows_of(entity)));
+            if let Some(model) = self.asset_server.get(handle) {
+                loaded.push((model, shadows_of(entity)));
+            }
+        }
+        let owned = models
+            .iter()
+            .flat_map(|models| models.iter().enumerate())
+            .filter_map(|(entity, model)| Some((model.as_ref()?, shadows_of(entity))));
+
+        let mut draws = Vec::new();
+        let mut pipelines = self.pipelines.borrow_mut();
+        for (model, shadows) in owned.chain(loaded) {
+            for mesh in &model.meshes {
+                let material = &model.materials[mesh.material];
+                let (pipeline_id, pipeline) = pipelines.get(&self.device, &material.pipeline);
+                draws.push(Draw { pipeline_id, pipeline, model, mesh, material, shadows });
+            }
+        }
+        drop(pipelines);
+
+        // Opaque meshes grouped by pipeline and then material so each is
+        // only bound once, blended ones after them from back to front
+        let (mut transparent, mut draws): (Vec<_>, Vec<_>) = draws
+            .into_iter()
+            .partition(|draw| draw.material.pipeline.alpha_mode.is_transparent());
+        draws.sort_by_key(|draw| (draw.pipeline_id, Arc::as_ptr(draw.material)));
+        let eye = self.camera.position.to_vec();
+        let distance = |draw: &Draw| {
+            let instances = &draw.model.instances;
+            let center = instances
+                .iter()
+                .map(|instance| instance.position + instance.rotation * draw.mesh.center)
+                .sum::<cgmath::Vector3<f32>>()
+                / instances.len().max(1) as f32;
+            (center - eye).magnitude2()
+        };
+        // Squared distances are never negative, so their bits sort the same
+        // way as total_cmp would
+        transparent.sort_by_cached_key(|draw| std::cmp::Reverse(distance(draw).to_bits()));
+
+        // Blended meshes don't cast, masked ones after the rest so the
+        // shadow pipeline only switches once
+        let mut casters: Vec<_> = draws
+            .iter()
+            .filter(|draw| draw.shadows.cast)
+            .map(|draw| shadow::ShadowCaster {
+                model: draw.model,
+                mesh: draw.mesh,
+                joints: &self.joints(draw.model).bind_group,
+                masked: (draw.material.pipeline.alpha_mode == pipeline::AlphaMode::Mask)
+                    .then_some(&draw.material.bind_group),
+            })
+            .collect();
+        casters.sort_by_key(|caster| caster.masked.is_some());
+        self.shadow_maps.render(encoder, &self.queue, &self.lights_uniform, &casters);
+
+        let show_lights = self.show_lights();
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: color_view,
+                resolve_target,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.clear_color),
+                    // Only the resolved image is used after the pass
+                    store: resolve_target.is_none(),
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+
+        // Draw all lights
+        if show_lights {
+            use model::DrawLight;
+            render_pass.set_pipeline(&self.light_render_pipeline);
+            render_pass.draw_light_model_instanced(
+                &self.obj_model,
+                0..1,
+                &self.camera_bind_group,
+                &self.light_bind_group,
+            );
+        }
+
+        // Draw all models, both owned by entities and loaded through the
+        // asset server
+        self.draw_models(&mut render_pass, &draws);
+        self.draw_models(&mut render_pass, &transparent);
+
+        self.debug_renderer.draw(&mut render_pass, &self.camera_bind_group);
+    }
+
+    /// Only binds what changed since the previous draw, `draws` should be
+    /// sorted so neighbours share as much as possible.
+    fn draw_models<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, draws: &'a [Draw<'a>]) {
+        let mut previous: Option<&Draw> = None;
+        for draw in draws {
+            let changed = |same: fn(&Draw, &Draw) -> bool| previous.is_none_or(|previous| !same(previous, draw));
+            if changed(|a, b| a.pipeline_id == b.pipeline_id) {
+                render_pass.set_pipeline(&draw.pipeline);
+            }
+            if previous.is_none() {
+                render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+            }
+            if changed(|a, b| std::ptr::eq(a.model, b.model)) {
+                render_pass.set_vertex_buffer(1, draw.model.instance_buffer.slice(..));
+                render_pass.set_bind_group(3, &self.joints(draw.model).bind_group, &[]);
+            }
+            if changed(|a, b| std::ptr::eq(a.mesh, b.mesh)) {
+                render_pass.set_vertex_buffer(0, draw.mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(draw.mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            }
+            if changed(|a, b| Arc::ptr_eq(a.material, b.material)) {
+                render_pass.set_bind_group(0, &draw.material.bind_group, &[]);
+            }
+            if changed(|a, b| a.shadows.receive == b.shadows.receive) {
+                let light_bind_group = if draw.shadows.receive {
+                    &self.light_bind_group
+                } else {
+                    &self.unshadowed_light_bind_group
+                };
+                render_pass.set_bind_group(2, light_bind_group, &[]);
+            }
+            render_pass.draw_indexed(0..draw.mesh.num_elements, 0, 0..draw.model.instances.len() as u32);
+            previous = Some(draw);
+        }
+    }
+
+    /// Outlines for the `DirectionalLight` and `SpotLight` components, the
+    /// point light has its cube.
+    fn light_markers(&self) -> debug_draw::DebugDraw {
+        let mut markers = debug_draw::DebugDraw::default();
+        markers.enabled = true;
+        if let Some(suns) = self.borrow_component_vec::<shadow::DirectionalLight>() {
+            // The sun has no position, point it at the origin
+            for sun in suns.iter().flatten() {
+                let direction = sun.direction.normalize();
+                markers.arrow(-direction * 3.0, cgmath::Vector3::zero(), sun.color);
+            }
+        }
+        if let Some(spots) = self.borrow_component_vec::<shadow::SpotLight>() {
+            for spot in spots.iter().flatten() {
+                let direction = spot.direction.normalize();
+                let radius = spot.range * cgmath::Rad::from(spot.outer_angle).0.min(1.5).tan();
+                markers.light(spot.position, spot.color);
+                markers.cone(spot.position, spot.position + direction * spot.range, radius, spot.color);
+            }
+        }
+        markers
+    }
+
+    fn resource<R: 'static>(&self) -> Option<&R> {
+        self.resources.iter().find_map(|resource| resource.downcast_ref::<R>())
+    }
+
+    fn borrow_component_vec<ComponentType: 'static>(
+        &self,
+    ) -> Option<Ref<'_, Vec<Option<ComponentType>>>> {
+        for component_vec in self.component_vecs.iter() {
+            if let Some(component_vec) = component_vec
+                .as_any()
+                .downcast_ref::<RefCell<Vec<Option<ComponentType>>>>()
+            {
+                return Some(component_vec.borrow());
+            }
+        }
+        None
+    }
+
     fn borrow_component_vec_mut<ComponentType: 'static>(
         &self,
-    ) -> Option<RefMut<Vec<Option<ComponentType>>>> {
+    ) -> Option<RefMut<'_, Vec<Option<ComponentType>>>> {
         for component_vec in self.component_vecs.iter() {
             if let Some(component_vec) = component_vec
                 .as_any()
@@ -513,11 +1443,133 @@ impl State {
     }
 }
 
+impl Drop for State {
+    fn drop(&mut self) {
+        // The cache would otherwise keep the device alive
+        sampler::forget_device(&self.device);
+    }
+}
+
+/// One mesh to draw, sorted to keep pipeline and bind group changes down.
+struct Draw<'a> {
+    pipeline_id: u32,
+    pipeline: Arc<wgpu::RenderPipeline>,
+    model: &'a model::Model,
+    mesh: &'a model::Mesh,
+    material: &'a Arc<model::Material>,
+    shadows: shadow::Shadows,
+}
+
+fn select_present_mode(requested: wgpu::PresentMode, supported: &[wgpu::PresentMode]) -> wgpu::PresentMode {
+    match requested {
+        // wgpu resolves these to a supported mode itself
+        wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync => requested,
+        _ if supported.contains(&requested) => requested,
+        _ => {
+            log::warn!("Present mode {:?} isn't supported, falling back to Fifo", requested);
+            wgpu::PresentMode::Fifo
+        }
+    }
+}
+
+/// Sample counts from 1 to 8 that both formats can be drawn into with, and
+/// the color format resolved from.
+fn supported_sample_counts(
+    adapter: &wgpu::Adapter,
+    device: &wgpu::Device,
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+) -> Vec<u32> {
+    // wgpu only allows what WebGPU guarantees (1 and 4) unless the device
+    // has this feature, or is downlevel and checks the adapter anyway
+    let adapter_specific = device.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+        || !adapter.get_downlevel_capabilities().is_webgpu_compliant();
+    let flags = |format: wgpu::TextureFormat| {
+        if adapter_specific {
+            adapter.get_texture_format_features(format).flags
+        } else {
+            format.describe().guaranteed_format_features.flags
+        }
+    };
+    let (color, depth) = (flags(color_format), flags(depth_format));
+    [1, 2, 4, 8]
+        .into_iter()
+        .filter(|&count| {
+            color.sample_count_supported(count)
+                && depth.sample_count_supported(count)
+                && (count == 1 || color.contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE))
+        })
+        .collect()
+}
+
+fn select_sample_count(requested: u32, supported: &[u32]) -> u32 {
+    let count = supported.iter().copied().filter(|&count| count <= requested).max().unwrap_or(1);
+    if count != requested {
+        log::warn!("{}x MSAA isn't supported, falling back to {}x", requested, count);
+    }
+    count
+}
+
+// Drawn into instead of the surface and resolved into it while MSAA is on
+fn msaa_target_descriptor(format: wgpu::TextureFormat, sample_count: u32) -> render_target::TargetDescriptor {
+    render_target::TargetDescriptor {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        sample_count,
+        ..render_target::TargetDescriptor::color("msaa_color", format)
+    }
+}
+
+fn create_light_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    light_buffer: &wgpu::Buffer,
+    lights_buffer: &wgpu::Buffer,
+    environment: &environment::Environment,
+    environment_buffer: &wgpu::Buffer,
+    shadow_maps: &shadow::ShadowMaps,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&environment.cube.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(&environment.cube.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: environment_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: lights_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&shadow_maps.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::Sampler(&shadow_maps.sampler),
+            },
+        ],
+        label: None,
+    })
+}
+
 fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
     color_format: wgpu::TextureFormat,
     depth_format: Option<wgpu::TextureFormat>,
+    sample_count: u32,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
 ) -> wgpu::RenderPipeline {
@@ -563,10 +1615,61 @@ fn create_render_pipeline(
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
         multiview: None,
     })
 }
+
+// A device on a software adapter for tests, `None` where there is none
+#[cfg(test)]
+pub(crate) fn software_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    let settings = settings::RendererSettings::software();
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends: settings.backends(),
+        dx12_shader_compiler: Default::default(),
+    });
+    pollster::block_on(async {
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: settings.power_preference(),
+                force_fallback_adapter: true,
+                compatible_surface: None,
+            })
+            .await?;
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: wgpu::Features::empty(),
+                    limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+                },
+                None,
+            )
+            .await
+            .ok()?;
+        Some((device, queue))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_counts_fall_back_to_the_next_supported_one() {
+        let supported = [1, 2, 4, 8];
+        assert_eq!(select_sample_count(4, &supported), 4);
+        assert_eq!(select_sample_count(8, &supported), 8);
+        assert_eq!(select_sample_count(16, &supported), 8);
+        assert_eq!(select_sample_count(6, &supported), 4);
+        assert_eq!(select_sample_count(1, &supported), 1);
+        // Gaps in the list are skipped, 0 is treated as off
+        assert_eq!(select_sample_count(8, &[1, 4]), 4);
+        assert_eq!(select_sample_count(2, &[1, 4]), 1);
+        assert_eq!(select_sample_count(0, &supported), 1);
+        assert_eq!(select_sample_count(4, &[]), 1);
+    }
+}
@@ -1,32 +1,46 @@
 use core::ops::Range;
+use std::cell::OnceCell;
+use std::sync::Arc;
+use wgpu::util::DeviceExt;
+use cgmath::prelude::*;
 
-use crate::engine::texture;
+use crate::engine::{animation, mesh_cache, sampler, texture};
+use crate::engine::gpu_image::ImageData;
+use crate::engine::sampler::SamplerSettings;
+use crate::engine::pipeline::{AlphaMode, MaterialPipeline};
+use crate::engine::asset_error::{AssetError, gpu_scope};
 
 pub trait Vertex {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a>;
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
     pub tangent: [f32; 3],
     pub bitangent: [f32; 3],
+    /// Indices into the model's `Skin::joints`, unused if all weights are zero
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
 }
 
 impl Vertex for ModelVertex {
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
 
-        const ATTRIBS: [wgpu::VertexAttribute; 5] =
+        // Skinning comes after the instance attributes (5 to 11)
+        const ATTRIBS: [wgpu::VertexAttribute; 7] =
             wgpu::vertex_attr_array![
                 0 => Float32x3,
                 1 => Float32x2,
                 2 => Float32x3,
                 3 => Float32x3,
-                4 => Float32x3
+                4 => Float32x3,
+                12 => Uint32x4,
+                13 => Float32x4
             ];
 
         wgpu::VertexBufferLayout {
@@ -39,9 +53,71 @@ impl Vertex for ModelVertex {
  
 pub struct Model {
     pub meshes: Vec<Mesh>,
-    pub materials: Vec<Material>,
+    // Shared so models split out of one file don't upload textures twice
+    pub materials: Vec<Arc<Material>>,
     pub instances: Vec<Instance>,
-    pub(crate) instance_buffer: wgpu::Buffer
+    pub(crate) instance_buffer: wgpu::Buffer,
+    /// Set for models that can be animated with an `AnimationPlayer`
+    pub skin: Option<animation::Skin>,
+    // Created the first time the model is animated or drawn
+    pub(crate) joints: OnceCell<animation::JointBuffer>,
+}
+
+impl Model {
+    pub fn new(
+        device: &wgpu::Device,
+        meshes: Vec<Mesh>,
+        materials: Vec<Arc<Material>>,
+        instances: Vec<Instance>,
+    ) -> Self {
+        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let instance_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Instance Buffer"),
+                contents: bytemuck::cast_slice(&instance_data),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        );
+
+        Self {
+            meshes,
+            materials,
+            instances,
+            instance_buffer,
+            skin: None,
+            joints: OnceCell::new(),
+        }
+    }
+
+    /// Uploads the current `instances`, call after changing them.
+    pub fn update_instance_buffer(&mut self, device: &wgpu::Device) {
+        let instance_data = self.instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        self.upload_instances(device, &instance_data);
+    }
+
+    /// Like `update_instance_buffer`, with the instances farthest from `eye`
+    /// first so transparent ones blend over those behind them.
+    pub(crate) fn update_instance_buffer_back_to_front(&mut self, device: &wgpu::Device, eye: cgmath::Vector3<f32>) {
+        let mut instances = self.instances.iter().collect::<Vec<_>>();
+        instances.sort_by(|a, b| (b.position - eye).magnitude2().total_cmp(&(a.position - eye).magnitude2()));
+        let instance_data = instances.into_iter().map(Instance::to_raw).collect::<Vec<_>>();
+        self.upload_instances(device, &instance_data);
+    }
+
+    /// Whether any material is blended, see `AlphaMode::is_transparent`.
+    pub fn is_transparent(&self) -> bool {
+        self.materials.iter().any(|material| material.pipeline.alpha_mode.is_transparent())
+    }
+
+    fn upload_instances(&mut self, device: &wgpu::Device, instance_data: &[InstanceRaw]) {
+        self.instance_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Instance Buffer"),
+                contents: bytemuck::cast_slice(instance_data),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        );
+    }
 }
 
 pub struct Instance {
@@ -49,6 +125,15 @@ pub struct Instance {
     pub rotation: cgmath::Quaternion<f32>,
 }
 
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0)),
+        }
+    }
+}
+
 impl Instance {
     pub(crate) fn to_raw(&self) -> InstanceRaw {
         InstanceRaw {
@@ -91,49 +176,262 @@ impl InstanceRaw {
     }
 }
  
+/// glTF's metallic-roughness factors, multiplied into the material's
+/// textures and bound next to them.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    /// Linear RGB and alpha
+    pub base_color: [f32; 4],
+    /// Linear RGB, black for materials that don't glow
+    pub emissive: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+    /// How much the occlusion texture darkens ambient light, 0 to 1
+    pub occlusion_strength: f32,
+    /// Scales the normal map's X and Y, 0 flattens it
+    pub normal_scale: f32,
+    /// Fragments with less alpha are discarded, only used with
+    /// `AlphaMode::Mask`
+    pub alpha_cutoff: f32,
+}
+
+impl Default for MaterialUniform {
+    /// Plain white, non-metallic and halfway between glossy and matte.
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            emissive: [0.0; 3],
+            metallic: 0.0,
+            roughness: 0.5,
+            occlusion_strength: 1.0,
+            normal_scale: 1.0,
+            alpha_cutoff: 0.5,
+        }
+    }
+}
+
+/// The textures of a `Material`, all sampled with the base color's sampler
+/// except the normal map.
+pub struct MaterialTextures {
+    /// sRGB color and alpha
+    pub base_color: texture::Texture,
+    /// Roughness in green and metallic in blue, like glTF
+    pub metallic_roughness: texture::Texture,
+    pub normal: texture::Texture,
+    /// Ambient occlusion in red
+    pub occlusion: texture::Texture,
+    /// sRGB
+    pub emissive: texture::Texture,
+}
+
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: texture::Texture,
-    pub normal_texture: texture::Texture,
+    pub textures: MaterialTextures,
+    pub uniform: MaterialUniform,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+    /// What the textures were last sampled with, see `set_sampler`
+    pub sampler: SamplerSettings,
+    /// Shader and render state, read every frame so changes show up right
+    /// away. The alpha cutoff is part of the uniform though, call
+    /// `update_uniform` after changing `alpha_mode`.
+    pub pipeline: MaterialPipeline,
 }
 
 impl Material {
     pub fn new(
         device: &wgpu::Device,
         name: &str,
-        diffuse_texture: texture::Texture,
-        normal_texture: texture::Texture,
+        textures: MaterialTextures,
+        uniform: MaterialUniform,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        // Drawn opaque until a pipeline is set, see gpu_uniform
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[MaterialUniform { alpha_cutoff: 0.0, ..uniform }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = Self::create_bind_group(device, name, &textures, &uniform_buffer, layout);
+
+        Self {
+            name: String::from(name),
+            textures,
+            uniform,
+            uniform_buffer,
+            bind_group,
+            sampler: SamplerSettings::default(),
+            pipeline: MaterialPipeline::default(),
+        }
+    }
+
+    /// Textures, samplers and the uniform buffer `create_bind_group` binds,
+    /// bind group 0 of every material pipeline.
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    // This should match the filterable field of the
+                    // corresponding Texture entry above.
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // Material colors, see model::MaterialUniform
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // Metallic roughness, occlusion and emissive, sampled
+                // with the base color's sampler
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("texture_bind_group_layout"),
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        name: &str,
+        textures: &MaterialTextures,
+        uniform_buffer: &wgpu::Buffer,
+        layout: &wgpu::BindGroupLayout,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&textures.base_color.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&textures.base_color.sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&textures.normal.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&textures.normal.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&textures.metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&textures.occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&textures.emissive.view),
                 },
             ],
             label: Some(name),
-        });
+        })
+    }
 
-        Self {
-            name: String::from(name),
-            diffuse_texture,
-            normal_texture,
-            bind_group,
+    /// Samples every texture with `settings` from now on. Materials loaded
+    /// as part of a model are shared, get to them with `Arc::get_mut`.
+    pub fn set_sampler(&mut self, device: &wgpu::Device, layout: &wgpu::BindGroupLayout, settings: SamplerSettings) {
+        let sampler = sampler::cached(device, &settings);
+        let textures = &mut self.textures;
+        for texture in [
+            &mut textures.base_color,
+            &mut textures.metallic_roughness,
+            &mut textures.normal,
+            &mut textures.occlusion,
+            &mut textures.emissive,
+        ] {
+            texture.sampler = sampler.clone();
+        }
+        self.bind_group = Self::create_bind_group(device, &self.name, &self.textures, &self.uniform_buffer, layout);
+        self.sampler = settings;
+    }
+
+    /// Uploads the current `uniform`, call after changing it or the alpha
+    /// mode.
+    pub fn update_uniform(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.gpu_uniform()]));
+    }
+
+    // The shader discards below the cutoff no matter the alpha mode
+    fn gpu_uniform(&self) -> MaterialUniform {
+        MaterialUniform {
+            alpha_cutoff: match self.pipeline.alpha_mode {
+                AlphaMode::Mask => self.uniform.alpha_cutoff,
+                _ => 0.0,
+            },
+            ..self.uniform
         }
     }
 }
@@ -144,10 +442,375 @@ pub struct Mesh {
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    /// Middle of the mesh's bounds, transparent meshes are sorted by it
+    pub center: cgmath::Vector3<f32>,
+}
+
+/// A decoded image waiting to be uploaded.
+pub struct TextureData {
+    pub label: String,
+    pub image: ImageData,
+    /// The file the image was decoded from, kept for images that only exist
+    /// inside another file like a `.glb` so mesh caches can store them as is
+    pub encoded: Option<Arc<[u8]>>,
 }
 
-pub trait DrawModel<'a> {
-    fn draw_model(
+impl TextureData {
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        is_normal_map: bool,
+        settings: &texture::TextureSettings,
+    ) -> Result<texture::Texture, AssetError> {
+        gpu_scope(device, &self.label, || {
+            texture::Texture::from_image_data(device, queue, &self.image, Some(&self.label), is_normal_map, settings)
+        })?
+        .map_err(|e| AssetError::GpuUpload {
+            path: self.label.clone(),
+            message: e.to_string(),
+        })
+    }
+}
+
+/// Textures left as `None` are replaced by ones that leave the factors in
+/// `uniform` as they are, and a flat normal map.
+pub struct MaterialData {
+    pub name: String,
+    pub base_color_texture: Option<TextureData>,
+    pub metallic_roughness_texture: Option<TextureData>,
+    pub normal_texture: Option<TextureData>,
+    pub occlusion_texture: Option<TextureData>,
+    pub emissive_texture: Option<TextureData>,
+    pub uniform: MaterialUniform,
+    /// Used for every texture, change it before uploading to override what
+    /// the file asked for
+    pub sampler: SamplerSettings,
+    pub pipeline: MaterialPipeline,
+}
+
+impl MaterialData {
+    /// Used for meshes that don't reference any material.
+    pub fn fallback() -> Self {
+        Self {
+            name: String::from("default"),
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+            uniform: MaterialUniform::default(),
+            sampler: SamplerSettings::default(),
+            pipeline: MaterialPipeline::default(),
+        }
+    }
+
+    /// Every texture slot with its name, in the order `MaterialTextures`
+    /// lists them.
+    pub fn textures(&self) -> [(&'static str, &Option<TextureData>); 5] {
+        [
+            ("base color", &self.base_color_texture),
+            ("metallic roughness", &self.metallic_roughness_texture),
+            ("normal", &self.normal_texture),
+            ("occlusion", &self.occlusion_texture),
+            ("emissive", &self.emissive_texture),
+        ]
+    }
+
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Result<Material, AssetError> {
+        let color = texture::TextureSettings {
+            sampler: self.sampler,
+            ..Default::default()
+        };
+        let linear = texture::TextureSettings {
+            srgb: false,
+            ..color
+        };
+        let upload = |texture: &Option<TextureData>, name: &str, is_normal_map, settings| match texture {
+            Some(texture) => texture.upload(device, queue, is_normal_map, settings),
+            None => {
+                // White keeps the factors as they are, (0.5, 0.5, 1.0) is a
+                // normal pointing straight out of the surface
+                let fallback = if is_normal_map { [128, 128, 255, 255] } else { [255; 4] };
+                let label = format!("{} fallback {}", self.name, name);
+                gpu_scope(device, &label, || texture::Texture::from_color(device, queue, fallback, &label, is_normal_map))?
+                    .map_err(|e| AssetError::GpuUpload {
+                        path: label.clone(),
+                        message: e.to_string(),
+                    })
+            }
+        };
+
+        let textures = MaterialTextures {
+            base_color: upload(&self.base_color_texture, "base color", false, &color)?,
+            metallic_roughness: upload(&self.metallic_roughness_texture, "metallic roughness", false, &linear)?,
+            normal: upload(&self.normal_texture, "normal", true, &linear)?,
+            occlusion: upload(&self.occlusion_texture, "occlusion", false, &linear)?,
+            emissive: upload(&self.emissive_texture, "emissive", false, &color)?,
+        };
+
+        let mut material = Material::new(device, &self.name, textures, self.uniform, layout);
+        material.sampler = self.sampler;
+        material.pipeline = self.pipeline.clone();
+        material.update_uniform(queue);
+        Ok(material)
+    }
+}
+
+#[derive(Clone)]
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    pub material: usize,
+}
+
+impl MeshData {
+    pub fn upload(&self, device: &wgpu::Device, label: &str) -> Mesh {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", label)),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", label)),
+            contents: bytemuck::cast_slice(&self.indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let bounds = mesh_cache::Bounds::of(&self.vertices);
+
+        Mesh {
+            name: self.name.clone(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: self.indices.len() as u32,
+            material: self.material,
+            center: if bounds.is_empty() {
+                cgmath::Vector3::zero()
+            } else {
+                (cgmath::Vector3::from(bounds.min) + cgmath::Vector3::from(bounds.max)) / 2.0
+            },
+        }
+    }
+
+    /// Moves the vertices by `matrix`, e.g. to bake in a node's transform.
+    /// Tangents have to be recomputed afterwards.
+    pub fn transform(&mut self, matrix: cgmath::Matrix4<f32>) {
+        // Normals need the inverse transpose to stay perpendicular under non
+        // uniform scale
+        let linear = cgmath::Matrix3::from_cols(matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate());
+        let normal_matrix = linear.invert().map(|m| m.transpose()).unwrap_or(linear);
+        for v in &mut self.vertices {
+            v.position = (matrix * cgmath::Point3::from(v.position).to_homogeneous()).truncate().into();
+            v.normal = normalize_or(normal_matrix * cgmath::Vector3::from(v.normal), cgmath::Vector3::unit_y()).into();
+        }
+
+        // Mirroring turns the triangles inside out
+        if linear.determinant() < 0.0 {
+            for c in self.indices.chunks_exact_mut(3) {
+                c.swap(1, 2);
+            }
+        }
+    }
+
+    /// Averages the normals of the faces around each vertex. Vertices at the
+    /// same position share a normal even if their UVs differ, so texture
+    /// seams don't show up as creases.
+    pub fn compute_smooth_normals(&mut self) {
+        let key = |v: &ModelVertex| v.position.map(f32::to_bits);
+        let mut normals = std::collections::HashMap::<[u32; 3], cgmath::Vector3<f32>>::new();
+        for c in self.indices.chunks_exact(3) {
+            // Not normalized, so bigger faces count for more
+            let normal = self.face_normal(c);
+            for &i in c {
+                *normals.entry(key(&self.vertices[i as usize])).or_insert(cgmath::Vector3::zero()) += normal;
+            }
+        }
+        for v in &mut self.vertices {
+            let normal = normals.get(&key(v)).copied().unwrap_or(cgmath::Vector3::zero());
+            v.normal = normalize_or(normal, cgmath::Vector3::unit_y()).into();
+        }
+    }
+
+    /// Gives every triangle its own vertices facing the way the triangle
+    /// does, for hard edged CAD style shading.
+    pub fn compute_flat_normals(&mut self) {
+        let mut vertices = Vec::with_capacity(self.indices.len());
+        for c in self.indices.chunks_exact(3) {
+            let normal = normalize_or(self.face_normal(c), cgmath::Vector3::unit_y());
+            for &i in c {
+                let mut v = self.vertices[i as usize];
+                v.normal = normal.into();
+                vertices.push(v);
+            }
+        }
+        self.indices = (0..vertices.len() as u32).collect();
+        self.vertices = vertices;
+    }
+
+    fn face_normal(&self, c: &[u32]) -> cgmath::Vector3<f32> {
+        let pos0: cgmath::Vector3<_> = self.vertices[c[0] as usize].position.into();
+        let pos1: cgmath::Vector3<_> = self.vertices[c[1] as usize].position.into();
+        let pos2: cgmath::Vector3<_> = self.vertices[c[2] as usize].position.into();
+        (pos1 - pos0).cross(pos2 - pos0)
+    }
+
+    /// Calculates tangents and bitangents from the UVs, needs normals.
+    pub fn compute_tangents(&mut self) {
+        let vertices = &mut self.vertices;
+        for v in vertices.iter_mut() {
+            v.tangent = [0.0; 3];
+            v.bitangent = [0.0; 3];
+        }
+        let mut triangles_included = vec![0; vertices.len()];
+
+        // Calculate tangents and bitangets. We're going to
+        // use the triangles, so we need to loop through the
+        // indices in chunks of 3
+        for c in self.indices.chunks_exact(3) {
+            let v0 = vertices[c[0] as usize];
+            let v1 = vertices[c[1] as usize];
+            let v2 = vertices[c[2] as usize];
+
+            let pos0: cgmath::Vector3<_> = v0.position.into();
+            let pos1: cgmath::Vector3<_> = v1.position.into();
+            let pos2: cgmath::Vector3<_> = v2.position.into();
+
+            let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
+            let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
+            let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
+
+            // Calculate the edges of the triangle
+            let delta_pos1 = pos1 - pos0;
+            let delta_pos2 = pos2 - pos0;
+
+            // This will give us a direction to calculate the
+            // tangent and bitangent
+            let delta_uv1 = uv1 - uv0;
+            let delta_uv2 = uv2 - uv0;
+
+            // Triangles without UV area (e.g. no UVs at all) would divide by
+            // zero, they get a made up tangent below instead
+            let det = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+            if det.abs() < f32::MIN_POSITIVE {
+                continue;
+            }
+
+            // Solving the following system of equations will
+            // give us the tangent and bitangent.
+            //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+            //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+            // Luckily, the place I found this equation provided
+            // the solution!
+            let r = 1.0 / det;
+            let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+            // We flip the bitangent to enable right-handed normal
+            // maps with wgpu texture coordinate system
+            let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+            // We'll use the same tangent/bitangent for each vertex in the triangle
+            for &i in c {
+                let v = &mut vertices[i as usize];
+                v.tangent = (tangent + cgmath::Vector3::from(v.tangent)).into();
+                v.bitangent = (bitangent + cgmath::Vector3::from(v.bitangent)).into();
+                // Used to average the tangents/bitangents
+                triangles_included[i as usize] += 1;
+            }
+        }
+
+        // Average the tangents/bitangents
+        for (v, n) in vertices.iter_mut().zip(triangles_included) {
+            let normal = cgmath::Vector3::from(v.normal);
+            let mut tangent = cgmath::Vector3::from(v.tangent);
+            let mut bitangent = cgmath::Vector3::from(v.bitangent);
+            if n > 0 {
+                let denom = 1.0 / n as f32;
+                tangent *= denom;
+                bitangent *= denom;
+            }
+
+            // Any direction along the surface works when there's nothing to go by
+            if !is_usable(tangent) {
+                let axis = if normal.x.abs() < 0.9 { cgmath::Vector3::unit_x() } else { cgmath::Vector3::unit_y() };
+                tangent = normalize_or(axis.cross(normal), cgmath::Vector3::unit_x());
+            }
+            if !is_usable(bitangent) {
+                bitangent = normalize_or(tangent.cross(normal), cgmath::Vector3::unit_z());
+            }
+            v.tangent = tangent.into();
+            v.bitangent = bitangent.into();
+        }
+    }
+}
+
+fn is_usable(v: cgmath::Vector3<f32>) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite() && v.magnitude2() > f32::MIN_POSITIVE
+}
+
+fn normalize_or(v: cgmath::Vector3<f32>, fallback: cgmath::Vector3<f32>) -> cgmath::Vector3<f32> {
+    if is_usable(v) {
+        v.normalize()
+    } else {
+        fallback
+    }
+}
+
+/// Everything a `Model` is made of, parsed and decoded on the CPU. Loaders
+/// produce this so the expensive part can happen off the render thread.
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+    pub skin: Option<animation::Skin>,
+}
+
+impl ModelData {
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        label: &str,
+    ) -> Result<Model, AssetError> {
+        let materials = upload_materials(&self.materials, device, queue, layout)?;
+
+        let meshes = gpu_scope(device, label, || {
+            self.meshes.iter().map(|m| m.upload(device, label)).collect()
+        })?;
+
+        let mut model = Model::new(device, meshes, materials, vec![Instance::default()]);
+        model.skin = self.skin.clone();
+        Ok(model)
+    }
+}
+
+pub(crate) fn upload_materials(
+    materials: &[MaterialData],
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> Result<Vec<Arc<Material>>, AssetError> {
+    materials
+        .iter()
+        .map(|m| m.upload(device, queue, layout).map(Arc::new))
+        .collect()
+}
+
+pub trait DrawLight<'a> {
+    fn draw_light_mesh_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        instances: Range<u32>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+
+    fn draw_light_model_instanced(
         &mut self,
         model: &'a Model,
         instances: Range<u32>,
@@ -156,11 +819,25 @@ pub trait DrawModel<'a> {
     );
 }
 
-impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
+impl<'a, 'b> DrawLight<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
 {
-    fn draw_model(
+    fn draw_light_mesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        instances: Range<u32>,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.set_bind_group(0, camera_bind_group, &[]);
+        self.set_bind_group(1, light_bind_group, &[]);
+        self.draw_indexed(0..mesh.num_elements, 0, instances);
+    }
+
+    fn draw_light_model_instanced(
         &mut self,
         model: &'b Model,
         instances: Range<u32>,
@@ -168,18 +845,191 @@ where
         light_bind_group: &'b wgpu::BindGroup,
     ) {
         for mesh in &model.meshes {
-            self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-            self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            self.draw_light_mesh_instanced(mesh, instances.clone(), camera_bind_group, light_bind_group);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::render_target::RenderTarget;
+
+    fn vertex(position: [f32; 3], tex_coords: [f32; 2]) -> ModelVertex {
+        ModelVertex {
+            position,
+            tex_coords,
+            ..Default::default()
+        }
+    }
 
-            // Add bind groups
-            let material = &model.materials[mesh.material];
-            self.set_bind_group(0, &material.bind_group, &[]);
-            self.set_bind_group(1, camera_bind_group, &[]);
+    fn assert_near(a: cgmath::Vector3<f32>, b: cgmath::Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn smooth_normals_are_shared_across_seams() {
+        // A roof, the ridge vertices are split like at a UV seam
+        let mut mesh = MeshData {
+            name: String::from("roof"),
+            vertices: vec![
+                vertex([-1.0, 0.0, 0.0], [0.0, 0.0]),
+                vertex([0.0, 1.0, 1.0], [1.0, 1.0]),
+                vertex([0.0, 1.0, 0.0], [1.0, 0.0]),
+                vertex([1.0, 0.0, 0.0], [0.0, 0.0]),
+                vertex([0.0, 1.0, 0.0], [0.5, 0.0]),
+                vertex([0.0, 1.0, 1.0], [0.5, 1.0]),
+            ],
+            indices: vec![0, 1, 2, 3, 4, 5],
+            material: 0,
+        };
+        mesh.compute_smooth_normals();
+
+        let slope = std::f32::consts::FRAC_1_SQRT_2;
+        let normal = |i: usize| cgmath::Vector3::from(mesh.vertices[i].normal);
+        assert_near(normal(0), cgmath::vec3(-slope, slope, 0.0));
+        assert_near(normal(3), cgmath::vec3(slope, slope, 0.0));
+        for ridge in [1, 2, 4, 5] {
+            assert_near(normal(ridge), cgmath::Vector3::unit_y());
+        }
+
+        // Nothing to average, e.g. a degenerate triangle
+        let mut line = MeshData {
+            vertices: vec![vertex([0.0; 3], [0.0; 2]), vertex([1.0, 0.0, 0.0], [0.0; 2]), vertex([2.0, 0.0, 0.0], [0.0; 2])],
+            indices: vec![0, 1, 2],
+            ..mesh
+        };
+        line.compute_smooth_normals();
+        for v in &line.vertices {
+            assert_eq!(v.normal, [0.0, 1.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn tangents_without_uvs_are_made_up() {
+        let mut mesh = MeshData {
+            name: String::from("quad"),
+            vertices: vec![
+                vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+                vertex([1.0, 0.0, 0.0], [1.0, 1.0]),
+                vertex([1.0, 1.0, 0.0], [1.0, 0.0]),
+            ],
+            indices: vec![0, 1, 2],
+            material: 0,
+        };
+        mesh.compute_smooth_normals();
+        mesh.compute_tangents();
+        for v in &mesh.vertices {
+            // Along U, the bitangent flipped for wgpu's V going down
+            assert_near(v.tangent.into(), cgmath::Vector3::unit_x());
+            assert_near(v.bitangent.into(), cgmath::Vector3::unit_y());
+        }
+
+        // All zero, like OBJ files without `vt` lines
+        for v in &mut mesh.vertices {
+            v.tex_coords = [0.0; 2];
+        }
+        mesh.compute_tangents();
+        for v in &mesh.vertices {
+            let normal = cgmath::Vector3::from(v.normal);
+            for direction in [v.tangent, v.bitangent] {
+                let direction = cgmath::Vector3::from(direction);
+                assert!(is_usable(direction), "{:?}", v);
+                assert!((direction.magnitude() - 1.0).abs() < 1e-5, "{:?}", v);
+                assert!(direction.dot(normal).abs() < 1e-5, "{:?}", v);
+            }
+        }
+    }
+
+    // Draws the texture's center into a 1x1 target to see what it holds,
+    // uploaded textures can't be copied from
+    fn texel(device: &wgpu::Device, queue: &wgpu::Queue, texture: &texture::Texture) -> [u8; 4] {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                "@group(0) @binding(0) var t: texture_2d<f32>;
+                @group(0) @binding(1) var s: sampler;
+                @vertex
+                fn vs_main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {
+                    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.5, 1.0);
+                }
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return textureSampleLevel(t, s, vec2<f32>(0.5), 0.0);
+                }"
+                .into(),
+            ),
+        });
+        let target = RenderTarget::new(device, 1, 1, wgpu::TextureFormat::Rgba8Unorm);
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: None,
+            vertex: wgpu::VertexState { module: &shader, entry_point: "vs_main", buffers: &[] },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(target.format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: wgpu::BindingResource::TextureView(&texture.view) },
+                wgpu::BindGroupEntry { binding: 1, resource: wgpu::BindingResource::Sampler(&texture.sampler) },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.color.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Clear(wgpu::Color::BLACK), store: true },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &target.depth.view,
+                    depth_ops: Some(wgpu::Operations { load: wgpu::LoadOp::Clear(1.0), store: true }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&pipeline);
+            render_pass.set_bind_group(0, &bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+        target.read_image(device, queue).unwrap().get_pixel(0, 0).0
+    }
 
-            // TODO: pass in an array of lights
-            self.set_bind_group(2, light_bind_group, &[]);
+    #[test]
+    fn missing_textures_fall_back_to_neutral_ones() {
+        let Some((device, queue)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let layout = Material::create_bind_group_layout(&device);
+        let material = MaterialData::fallback().upload(&device, &queue, &layout).unwrap();
 
-            self.draw_indexed(0..mesh.num_elements, 0, instances.clone());
+        let textures = &material.textures;
+        assert_eq!(textures.base_color.texture.format(), wgpu::TextureFormat::Rgba8UnormSrgb);
+        assert_eq!(textures.normal.texture.format(), wgpu::TextureFormat::Rgba8Unorm);
+        for white in [&textures.base_color, &textures.metallic_roughness, &textures.occlusion, &textures.emissive] {
+            assert_eq!(texel(&device, &queue, white), [255; 4]);
         }
+        let normal = texel(&device, &queue, &textures.normal);
+        assert!(normal.iter().zip([128, 128, 255, 255]).all(|(&a, b)| a.abs_diff(b) <= 1), "{:?}", normal);
     }
 }
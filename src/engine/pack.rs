@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::engine::asset_io::AssetIo;
+
+// Layout of a pack, all numbers little endian:
+//
+//   magic "BPAK", version: u32, entry count: u32, index offset: u64
+//   file data, one blob after the other
+//   index: per entry
+//     path length: u16, path: utf-8, offset: u64, stored size: u64,
+//     size: u64, compression: u8, crc32 of the uncompressed data: u32
+const MAGIC: &[u8; 4] = b"BPAK";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 4 + 4 + 4 + 8;
+// Index entry with an empty path
+const MIN_ENTRY_SIZE: u64 = 2 + 8 + 8 + 8 + 1 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackCompression {
+    None,
+    Deflate,
+}
+
+impl PackCompression {
+    fn from_u8(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(PackCompression::None),
+            1 => Ok(PackCompression::Deflate),
+            _ => Err(invalid_data(format!("unknown compression {}", value))),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            PackCompression::None => 0,
+            PackCompression::Deflate => 1,
+        }
+    }
+
+    /// Deflate for everything that isn't compressed already.
+    pub fn for_path(path: &str) -> Self {
+        let extension = path.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+        match extension.as_deref() {
+            Some("png" | "jpg" | "jpeg" | "glb" | "ktx2" | "pak") => PackCompression::None,
+            _ => PackCompression::Deflate,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PackEntry {
+    pub offset: u64,
+    pub stored_size: u64,
+    pub size: u64,
+    pub compression: PackCompression,
+    pub crc: u32,
+}
+
+enum PackData {
+    File(Mutex<File>),
+    Memory(Vec<u8>),
+}
+
+/// A pack file mounted as an asset source, see `PackWriter` for making one.
+/// Every read is checked against the checksum stored in the index, so
+/// damaged or edited files are reported instead of loaded.
+pub struct PackAssetIo {
+    entries: HashMap<String, PackEntry>,
+    data: PackData,
+}
+
+impl PackAssetIo {
+    /// Opens a pack on disk, files are read from it on demand.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let entries = read_index(&mut file)?;
+        Ok(Self {
+            entries,
+            data: PackData::File(Mutex::new(file)),
+        })
+    }
+
+    /// Uses a pack that is already in memory, e.g. downloaded on the web.
+    pub fn from_bytes(data: Vec<u8>) -> io::Result<Self> {
+        let entries = read_index(&mut io::Cursor::new(&data))?;
+        Ok(Self {
+            entries,
+            data: PackData::Memory(data),
+        })
+    }
+
+    pub fn entries(&self) -> &HashMap<String, PackEntry> {
+        &self.entries
+    }
+
+    fn read_stored(&self, entry: &PackEntry) -> io::Result<Vec<u8>> {
+        match &self.data {
+            PackData::File(file) => {
+                let mut file = file.lock().unwrap();
+                file.seek(SeekFrom::Start(entry.offset))?;
+                // Read through `take` so a damaged size can't allocate more than the file has
+                let mut data = Vec::new();
+                (&mut *file).take(entry.stored_size).read_to_end(&mut data)?;
+                if data.len() as u64 != entry.stored_size {
+                    return Err(invalid_data("entry points past the end of the pack"));
+                }
+                Ok(data)
+            }
+            PackData::Memory(data) => {
+                let range = usize::try_from(entry.offset).ok().and_then(|start| {
+                    let end = start.checked_add(usize::try_from(entry.stored_size).ok()?)?;
+                    Some(start..end)
+                });
+                range
+                    .and_then(|range| data.get(range))
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| invalid_data("entry points past the end of the pack"))
+            }
+        }
+    }
+}
+
+impl AssetIo for PackAssetIo {
+    fn read(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        let Some(entry) = self.entries.get(path) else {
+            return Ok(None);
+        };
+
+        let stored = self.read_stored(entry)?;
+        let data = match entry.compression {
+            PackCompression::None => stored,
+            PackCompression::Deflate => {
+                // One byte past the expected size is enough to tell it's wrong
+                let mut data = Vec::new();
+                flate2::read::DeflateDecoder::new(&stored[..])
+                    .take(entry.size.saturating_add(1))
+                    .read_to_end(&mut data)?;
+                data
+            }
+        };
+
+        if data.len() as u64 != entry.size || crc32(&data) != entry.crc {
+            return Err(invalid_data(format!("{} is corrupted in the pack", path)));
+        }
+        Ok(Some(data))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    fn size(&self, path: &str) -> Option<u64> {
+        Some(self.entries.get(path)?.size)
+    }
+}
+
+/// Builds a pack file, usually from a whole directory with `add_dir`.
+#[derive(Default)]
+pub struct PackWriter {
+    files: Vec<(String, Vec<u8>, PackCompression)>,
+}
+
+impl PackWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file under the asset path `path`, replacing an earlier one.
+    pub fn add(&mut self, path: &str, data: Vec<u8>, compression: PackCompression) {
+        let path = path.replace('\\', "/");
+        self.files.retain(|(existing, _, _)| *existing != path);
+        self.files.push((path, data, compression));
+    }
+
+    /// Adds everything below `dir`, with paths relative to it. Files that are
+    /// compressed already are stored as they are.
+    pub fn add_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(&current)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let relative = path
+                    .strip_prefix(dir)
+                    .map_err(io::Error::other)?
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let compression = PackCompression::for_path(&relative);
+                self.add(&relative, std::fs::read(&path)?, compression);
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides the compression of every file added so far.
+    pub fn set_compression(&mut self, compression: PackCompression) {
+        for (_, _, file_compression) in &mut self.files {
+            *file_compression = compression;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(self.files.len() as u32).to_le_bytes())?;
+
+        let mut blobs = Vec::with_capacity(self.files.len());
+        let mut offset = HEADER_SIZE;
+        for (path, data, compression) in &self.files {
+            let stored = match compression {
+                PackCompression::None => data.clone(),
+                PackCompression::Deflate => {
+                    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                    encoder.write_all(data)?;
+                    encoder.finish()?
+                }
+            };
+            let entry = PackEntry {
+                offset,
+                stored_size: stored.len() as u64,
+                size: data.len() as u64,
+                compression: *compression,
+                crc: crc32(data),
+            };
+            offset += entry.stored_size;
+            blobs.push((path, stored, entry));
+        }
+
+        writer.write_all(&offset.to_le_bytes())?;
+        for (_, stored, _) in &blobs {
+            writer.write_all(stored)?;
+        }
+        for (path, _, entry) in &blobs {
+            let path_length = u16::try_from(path.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("path {} is too long", path)))?;
+            writer.write_all(&path_length.to_le_bytes())?;
+            writer.write_all(path.as_bytes())?;
+            writer.write_all(&entry.offset.to_le_bytes())?;
+            writer.write_all(&entry.stored_size.to_le_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+            writer.write_all(&[entry.compression.to_u8()])?;
+            writer.write_all(&entry.crc.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(io::BufWriter::new(File::create(path)?))
+    }
+}
+
+fn read_index<R: Read + Seek>(reader: &mut R) -> io::Result<HashMap<String, PackEntry>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a pack file"));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(invalid_data(format!("pack version {} isn't supported, expected {}", version, VERSION)));
+    }
+    let count = read_u32(reader)?;
+    let index_offset = read_u64(reader)?;
+    // Everything below comes from the file, so check it fits before trusting it
+    if index_offset < HEADER_SIZE || index_offset > length {
+        return Err(invalid_data("index points outside the pack"));
+    }
+    if u64::from(count) > (length - index_offset) / MIN_ENTRY_SIZE {
+        return Err(invalid_data("index is truncated"));
+    }
+
+    reader.seek(SeekFrom::Start(index_offset))?;
+    let mut entries = HashMap::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut path_length = [0; 2];
+        reader.read_exact(&mut path_length)?;
+        let mut path = vec![0; u16::from_le_bytes(path_length) as usize];
+        reader.read_exact(&mut path)?;
+        let path = String::from_utf8(path).map_err(invalid_data)?;
+
+        let offset = read_u64(reader)?;
+        let stored_size = read_u64(reader)?;
+        let size = read_u64(reader)?;
+        let mut compression = [0; 1];
+        reader.read_exact(&mut compression)?;
+        let crc = read_u32(reader)?;
+        if offset < HEADER_SIZE || offset.checked_add(stored_size).is_none_or(|end| end > index_offset) {
+            return Err(invalid_data(format!("{} points outside the pack data", path)));
+        }
+        entries.insert(path, PackEntry {
+            offset,
+            stored_size,
+            size,
+            compression: PackCompression::from_u8(compression[0])?,
+            crc,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack() -> Vec<u8> {
+        let mut writer = PackWriter::new();
+        writer.add("models/cube.obj", b"v 0 0 0\n".repeat(100), PackCompression::Deflate);
+        writer.add("textures/grass.png", vec![1, 2, 3, 4], PackCompression::None);
+        writer.add("empty.txt", Vec::new(), PackCompression::Deflate);
+        let mut data = Vec::new();
+        writer.write(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn written_packs_read_back() {
+        let data = pack();
+        let check = |io: &PackAssetIo| {
+            assert_eq!(io.entries().len(), 3);
+            assert_eq!(io.read("models/cube.obj").unwrap(), Some(b"v 0 0 0\n".repeat(100)));
+            assert_eq!(io.read("textures/grass.png").unwrap(), Some(vec![1, 2, 3, 4]));
+            assert_eq!(io.read("empty.txt").unwrap(), Some(Vec::new()));
+            assert_eq!(io.read("missing.txt").unwrap(), None);
+            assert!(io.entries()["models/cube.obj"].stored_size < 800);
+        };
+        check(&PackAssetIo::from_bytes(data.clone()).unwrap());
+
+        let path = std::env::temp_dir().join(format!("bones-pack-{}.pak", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        check(&PackAssetIo::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_packs_are_rejected() {
+        let data = pack();
+        for length in [0, 3, HEADER_SIZE as usize, data.len() - 1] {
+            assert!(PackAssetIo::from_bytes(data[..length].to_vec()).is_err(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn corrupt_indices_are_rejected() {
+        let data = pack();
+        let index_offset = u64::from_le_bytes(data[12..20].try_into().unwrap()) as usize;
+
+        // Huge entry count
+        let mut corrupt = data.clone();
+        corrupt[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(PackAssetIo::from_bytes(corrupt).is_err());
+
+        // Index past the end
+        let mut corrupt = data.clone();
+        corrupt[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(PackAssetIo::from_bytes(corrupt).is_err());
+
+        // First entry's stored size overflowing its offset
+        let path_length = u16::from_le_bytes([data[index_offset], data[index_offset + 1]]) as usize;
+        let stored_size = index_offset + 2 + path_length + 8;
+        let mut corrupt = data.clone();
+        corrupt[stored_size..stored_size + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(PackAssetIo::from_bytes(corrupt).is_err());
+    }
+
+    #[test]
+    fn damaged_files_fail_their_checksum() {
+        let mut data = pack();
+        let io = PackAssetIo::from_bytes(data.clone()).unwrap();
+        let offset = io.entries()["textures/grass.png"].offset as usize;
+        data[offset] ^= 0xff;
+        let io = PackAssetIo::from_bytes(data).unwrap();
+        assert_eq!(io.read("textures/grass.png").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(io.read("models/cube.obj").unwrap().is_some());
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::engine::asset_error::{AssetError, gpu_scope};
+use crate::engine::model::{self, Vertex};
+
+/// The shader materials are drawn with unless they name another one.
+pub const DEFAULT_SHADER: &str = "shader.wgsl";
+
+/// How a material's alpha is used, like glTF's alpha mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum AlphaMode {
+    /// Alpha is ignored
+    #[default]
+    Opaque,
+    /// Fragments less opaque than `MaterialUniform::alpha_cutoff` are
+    /// discarded, the rest is opaque, in shadows too. For leaves, fences
+    /// and the like.
+    Mask,
+    /// Blended over what's behind, e.g. glass facades and water
+    Blend,
+    /// Like `Blend` for textures whose colors are already multiplied by
+    /// their alpha
+    Premultiplied,
+    /// Added to what's behind, scaled by alpha. For glows and fire.
+    Additive,
+}
+
+impl AlphaMode {
+    /// Drawn after everything opaque, back to front, and without casting
+    /// shadows.
+    pub fn is_transparent(self) -> bool {
+        matches!(self, AlphaMode::Blend | AlphaMode::Premultiplied | AlphaMode::Additive)
+    }
+
+    fn blend_state(self) -> wgpu::BlendState {
+        match self {
+            AlphaMode::Opaque | AlphaMode::Mask => wgpu::BlendState::REPLACE,
+            AlphaMode::Blend => wgpu::BlendState::ALPHA_BLENDING,
+            AlphaMode::Premultiplied => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            AlphaMode::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+        }
+    }
+}
+
+/// Which shader a material is drawn with, along with the fixed function
+/// state around it. Materials with equal settings share a pipeline.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MaterialPipeline {
+    /// Name the shader was added under, see `State::add_shader`
+    pub shader: String,
+    /// Picks the blend state, and whether the material is drawn with the
+    /// transparent meshes. Call `Material::update_uniform` after changing
+    /// it, the cutoff only applies to `AlphaMode::Mask`.
+    pub alpha_mode: AlphaMode,
+    /// `None` draws both sides
+    pub cull_mode: Option<wgpu::Face>,
+    pub depth_write: bool,
+    pub depth_compare: wgpu::CompareFunction,
+}
+
+impl Default for MaterialPipeline {
+    fn default() -> Self {
+        Self {
+            shader: String::from(DEFAULT_SHADER),
+            alpha_mode: AlphaMode::Opaque,
+            cull_mode: Some(wgpu::Face::Back),
+            depth_write: true,
+            depth_compare: wgpu::CompareFunction::Less,
+        }
+    }
+}
+
+impl MaterialPipeline {
+    pub fn with_shader(mut self, shader: &str) -> Self {
+        self.shader = shader.to_string();
+        self
+    }
+
+    /// Also turns depth writes off for transparent modes, so meshes behind
+    /// them still show up.
+    pub fn with_alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.alpha_mode = alpha_mode;
+        self.depth_write = !alpha_mode.is_transparent();
+        self
+    }
+
+    /// Turns off back face culling, for leaves, fences and other cards.
+    pub fn double_sided(mut self) -> Self {
+        self.cull_mode = None;
+        self
+    }
+}
+
+struct CachedPipeline {
+    id: u32,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+/// Compiled shaders and the pipelines built from them, one per distinct
+/// `MaterialPipeline`. Every pipeline shares the model layout: material,
+/// camera, light and joints bind groups, `ModelVertex` and `InstanceRaw`
+/// vertex buffers and `vs_main` / `fs_main` entry points.
+pub struct PipelineCache {
+    layout: wgpu::PipelineLayout,
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+    sample_count: u32,
+    shaders: HashMap<String, wgpu::ShaderModule>,
+    pipelines: HashMap<MaterialPipeline, CachedPipeline>,
+    next_id: u32,
+}
+
+impl PipelineCache {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: wgpu::PipelineLayout,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let mut cache = Self {
+            layout,
+            color_format,
+            depth_format,
+            sample_count,
+            shaders: HashMap::new(),
+            pipelines: HashMap::new(),
+            next_id: 0,
+        };
+        cache.shaders.insert(
+            String::from(DEFAULT_SHADER),
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(DEFAULT_SHADER),
+                source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
+            }),
+        );
+        cache
+    }
+
+    /// Pipelines have to match the sample count of the targets they draw
+    /// into, all of them are built again when it changes.
+    pub fn set_sample_count(&mut self, sample_count: u32) {
+        if sample_count != self.sample_count {
+            self.sample_count = sample_count;
+            self.pipelines.clear();
+        }
+    }
+
+    pub fn has_shader(&self, name: &str) -> bool {
+        self.shaders.contains_key(name)
+    }
+
+    /// Compiles `source` and makes it available as `name`. Replaces the
+    /// shader with the same name and every pipeline built from it, unless
+    /// the new one doesn't compile.
+    pub fn add_shader(&mut self, device: &wgpu::Device, name: &str, source: &str) -> Result<(), AssetError> {
+        let module = gpu_scope(device, name, || {
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(name),
+                source: wgpu::ShaderSource::Wgsl(source.into()),
+            })
+        })?;
+        self.shaders.insert(name.to_string(), module);
+        // Includes materials that fell back to the default shader while this
+        // one was missing
+        self.pipelines.retain(|key, _| key.shader != name);
+        Ok(())
+    }
+
+    /// The pipeline for `key` and an id to sort draws by, built on first
+    /// use. Materials whose shader is missing or doesn't fit the layout are
+    /// drawn with the default shader instead.
+    pub fn get(&mut self, device: &wgpu::Device, key: &MaterialPipeline) -> (u32, Arc<wgpu::RenderPipeline>) {
+        if let Some(cached) = self.pipelines.get(key) {
+            return (cached.id, cached.pipeline.clone());
+        }
+
+        let built = match self.shaders.get(key.shader.as_str()) {
+            Some(shader) => gpu_scope(device, &key.shader, || self.create(device, shader, key)).map_err(|e| e.to_string()),
+            None => Err(format!("shader {} hasn't been added", key.shader)),
+        };
+        let cached = match built {
+            Ok(pipeline) => {
+                self.next_id += 1;
+                CachedPipeline { id: self.next_id, pipeline: Arc::new(pipeline) }
+            }
+            Err(e) => {
+                // Try the default shader, then the default state as well
+                let fallback = if key.shader != DEFAULT_SHADER {
+                    key.clone().with_shader(DEFAULT_SHADER)
+                } else if *key != MaterialPipeline::default() {
+                    MaterialPipeline::default()
+                } else {
+                    panic!("the default pipeline doesn't build: {}", e);
+                };
+                log::error!("Drawing materials using {:?} with {:?} instead: {}", key, fallback, e);
+                let (id, pipeline) = self.get(device, &fallback);
+                CachedPipeline { id, pipeline }
+            }
+        };
+        let result = (cached.id, cached.pipeline.clone());
+        self.pipelines.insert(key.clone(), cached);
+        result
+    }
+
+    fn create(&self, device: &wgpu::Device, shader: &wgpu::ShaderModule, key: &MaterialPipeline) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&key.shader),
+            layout: Some(&self.layout),
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[model::ModelVertex::desc(), model::InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: self.color_format,
+                    blend: Some(key.alpha_mode.blend_state()),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: key.cull_mode,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: self.depth_format,
+                depth_write_enabled: key.depth_write,
+                depth_compare: key.depth_compare,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: self.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fits an empty layout, stands in for shader.wgsl which needs every
+    // bind group of the real one
+    const PLAIN: &str = "
+        @vertex
+        fn vs_main(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {
+            return vec4<f32>(position, 1.0);
+        }
+        @fragment
+        fn fs_main() -> @location(0) vec4<f32> {
+            return vec4<f32>(1.0);
+        }";
+
+    #[test]
+    fn broken_shaders_fall_back_to_the_default() {
+        let Some((device, _)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        let mut cache = PipelineCache::new(
+            &device,
+            layout,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            crate::engine::texture::Texture::DEPTH_FORMAT,
+            1,
+        );
+        cache.add_shader(&device, DEFAULT_SHADER, PLAIN).unwrap();
+        let (default_id, _) = cache.get(&device, &MaterialPipeline::default());
+
+        // Doesn't parse, so it isn't added at all
+        assert!(cache.add_shader(&device, "typo.wgsl", "fn vs_main( {").is_err());
+        assert!(!cache.has_shader("typo.wgsl"));
+        let typo = MaterialPipeline::default().with_shader("typo.wgsl");
+        assert_eq!(cache.get(&device, &typo).0, default_id);
+
+        // Compiles, but has no fs_main to build a pipeline with
+        let vertex_only = PLAIN.split("@fragment").next().unwrap();
+        cache.add_shader(&device, "vertex_only.wgsl", vertex_only).unwrap();
+        let broken = MaterialPipeline::default().with_shader("vertex_only.wgsl");
+        assert_eq!(cache.get(&device, &broken).0, default_id);
+
+        // Fixing it replaces the fallback
+        cache.add_shader(&device, "vertex_only.wgsl", PLAIN).unwrap();
+        assert_ne!(cache.get(&device, &broken).0, default_id);
+    }
+}
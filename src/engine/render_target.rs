@@ -0,0 +1,453 @@
+use anyhow::*;
+use std::path::Path;
+
+use crate::engine::texture;
+
+/// An offscreen color + depth pair that the scene can be drawn into instead of
+/// the swapchain. Useful for thumbnails, screenshots and golden-image tests.
+pub struct RenderTarget {
+    /// Always single sampled, multisampled targets are resolved into it
+    pub color: texture::Texture,
+    /// What the scene is drawn into when `sample_count` is above 1
+    pub msaa_color: Option<texture::Texture>,
+    pub depth: texture::Texture,
+    pub format: wgpu::TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    pub sample_count: u32,
+}
+
+impl RenderTarget {
+    /// A single sampled target. `State::render_to_target` still draws it
+    /// with MSAA, through multisampled textures it keeps for that. It only
+    /// draws into targets with `State::surface_format` though.
+    pub fn new(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        Self::multisampled(device, width, height, format, 1)
+    }
+
+    /// A target for pipelines with `sample_count` samples, see
+    /// `MsaaSettings`.
+    pub fn multisampled(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let color = texture::Texture::create_render_target(device, width, height, format, "render_target_color");
+        let msaa_color = (sample_count > 1).then(|| {
+            texture::Texture::create_target(
+                device,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::RENDER_ATTACHMENT,
+                sample_count,
+                "render_target_msaa_color",
+            )
+        });
+        let depth = texture::Texture::create_target(
+            device,
+            width,
+            height,
+            texture::Texture::DEPTH_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            sample_count,
+            "render_target_depth",
+        );
+
+        Self {
+            color,
+            msaa_color,
+            depth,
+            format,
+            width,
+            height,
+            sample_count,
+        }
+    }
+
+    /// Copies the color texture back to the CPU. This blocks until the GPU has
+    /// finished all work submitted so far.
+    pub fn read_image(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<image::RgbaImage> {
+        read_texture(device, queue, &self.color.texture, self.width, self.height, self.format)
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, device: &wgpu::Device, queue: &wgpu::Queue, path: P) -> Result<()> {
+        let image = self.read_image(device, queue)?;
+        image.save_with_format(path, image::ImageFormat::Png)?;
+        Ok(())
+    }
+}
+
+/// Describes a render target whose size follows the surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TargetDescriptor {
+    pub label: &'static str,
+    pub format: wgpu::TextureFormat,
+    pub usage: wgpu::TextureUsages,
+    pub sample_count: u32,
+    /// Fraction of the surface size, e.g. 0.5 for a half resolution post buffer
+    pub scale: f32,
+}
+
+impl TargetDescriptor {
+    pub fn depth(label: &'static str) -> Self {
+        Self {
+            label,
+            format: texture::Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            sample_count: 1,
+            scale: 1.0,
+        }
+    }
+
+    pub fn color(label: &'static str, format: wgpu::TextureFormat) -> Self {
+        Self {
+            label,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            sample_count: 1,
+            scale: 1.0,
+        }
+    }
+
+    /// The target's size for a surface of `width` by `height`.
+    pub fn size(&self, width: u32, height: u32) -> (u32, u32) {
+        // Never let a scaled target collapse to zero pixels
+        (
+            ((width as f32 * self.scale) as u32).max(1),
+            ((height as f32 * self.scale) as u32).max(1),
+        )
+    }
+
+    fn create(&self, device: &wgpu::Device, width: u32, height: u32) -> texture::Texture {
+        let (width, height) = self.size(width, height);
+        texture::Texture::create_target(
+            device,
+            width,
+            height,
+            self.format,
+            self.usage,
+            self.sample_count,
+            self.label,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TargetId(usize);
+
+/// Owns every size-dependent render target (depth, HDR, MSAA, post buffers...)
+/// and recreates them all when the surface is resized.
+pub struct RenderTargets {
+    width: u32,
+    height: u32,
+    // Unregistered targets leave a gap so the other ids stay valid
+    targets: Vec<Option<(TargetDescriptor, texture::Texture)>>,
+}
+
+impl RenderTargets {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            targets: Vec::new(),
+        }
+    }
+
+    pub fn register(&mut self, device: &wgpu::Device, desc: TargetDescriptor) -> TargetId {
+        let texture = desc.create(device, self.width, self.height);
+        let id = match self.targets.iter().position(Option::is_none) {
+            Some(free) => free,
+            None => {
+                self.targets.push(None);
+                self.targets.len() - 1
+            }
+        };
+        self.targets[id] = Some((desc, texture));
+        TargetId(id)
+    }
+
+    /// Frees a target that isn't needed anymore, e.g. the MSAA target once
+    /// MSAA is turned off. Its id may be handed out again by `register`.
+    pub fn unregister(&mut self, id: TargetId) {
+        self.targets[id.0] = None;
+    }
+
+    /// Panics if `id` was unregistered.
+    pub fn get(&self, id: TargetId) -> &texture::Texture {
+        &self.target(id).1
+    }
+
+    pub fn descriptor(&self, id: TargetId) -> &TargetDescriptor {
+        &self.target(id).0
+    }
+
+    fn target(&self, id: TargetId) -> &(TargetDescriptor, texture::Texture) {
+        self.targets[id.0].as_ref().unwrap_or_else(|| panic!("render target {:?} was unregistered", id))
+    }
+
+    /// Changes the descriptor of an existing target, e.g. to switch its
+    /// sample count, and recreates it.
+    pub fn update(&mut self, device: &wgpu::Device, id: TargetId, desc: TargetDescriptor) {
+        let texture = desc.create(device, self.width, self.height);
+        self.targets[id.0] = Some((desc, texture));
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        if (width, height) == (self.width, self.height) || width == 0 || height == 0 {
+            return;
+        }
+        self.width = width;
+        self.height = height;
+        for (desc, texture) in self.targets.iter_mut().flatten() {
+            *texture = desc.create(device, width, height);
+        }
+    }
+}
+
+/// Reads back a single-sampled, 8 bit per channel RGBA or BGRA texture into an image.
+pub fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+) -> Result<image::RgbaImage> {
+    let swizzle = match format {
+        wgpu::TextureFormat::Rgba8Unorm | wgpu::TextureFormat::Rgba8UnormSrgb => false,
+        wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb => true,
+        _ => bail!("Can't read back texture with format {:?}", format),
+    };
+
+    // Rows copied into a buffer have to be padded to COPY_BYTES_PER_ROW_ALIGNMENT
+    let unpadded_bytes_per_row = 4 * width;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Buffer"),
+        size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(padded_bytes_per_row),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        sender.send(result).ok();
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver.recv()??;
+
+    let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+    {
+        let data = slice.get_mapped_range();
+        for row in data.chunks(padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+        }
+    }
+    buffer.unmap();
+
+    if swizzle {
+        for pixel in pixels.chunks_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    image::RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| anyhow!("Readback buffer doesn't match a {}x{} image", width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_sizes_never_reach_zero() {
+        let half = TargetDescriptor {
+            scale: 0.5,
+            ..TargetDescriptor::depth("half")
+        };
+        assert_eq!(half.size(1280, 720), (640, 360));
+        assert_eq!(half.size(1, 3), (1, 1));
+        let none = TargetDescriptor {
+            scale: 0.0,
+            ..TargetDescriptor::depth("none")
+        };
+        assert_eq!(none.size(1920, 1080), (1, 1));
+    }
+
+    #[test]
+    fn resize_recreates_every_target() {
+        let Some((device, _)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let mut targets = RenderTargets::new(64, 48);
+        let depth = targets.register(&device, TargetDescriptor::depth("depth"));
+        let quarter = targets.register(
+            &device,
+            TargetDescriptor {
+                scale: 0.25,
+                ..TargetDescriptor::color("quarter", wgpu::TextureFormat::Rgba8Unorm)
+            },
+        );
+
+        for (width, height) in [(800, 600), (3, 2), (1920, 1080), (7, 1000), (800, 600)] {
+            targets.resize(&device, width, height);
+            assert_eq!(targets.size(), (width, height));
+            for id in [depth, quarter] {
+                let texture = &targets.get(id).texture;
+                let expected = targets.descriptor(id).size(width, height);
+                assert_eq!((texture.width(), texture.height()), expected);
+            }
+        }
+
+        // Minimized windows report a zero size, the targets stay as they are
+        targets.resize(&device, 0, 0);
+        assert_eq!(targets.size(), (800, 600));
+        assert_eq!(targets.get(depth).texture.width(), 800);
+    }
+
+    #[test]
+    fn unregistered_ids_are_reused() {
+        let Some((device, _)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let mut targets = RenderTargets::new(64, 48);
+        let depth = targets.register(&device, TargetDescriptor::depth("depth"));
+        let color = targets.register(&device, TargetDescriptor::color("color", wgpu::TextureFormat::Rgba8Unorm));
+
+        targets.unregister(depth);
+        // Gaps are skipped when resizing
+        targets.resize(&device, 32, 32);
+        assert_eq!(targets.get(color).texture.width(), 32);
+        let again = targets.register(&device, TargetDescriptor::depth("depth again"));
+        assert_eq!(again, depth);
+        assert_eq!(targets.descriptor(again).label, "depth again");
+        assert_ne!(targets.register(&device, TargetDescriptor::depth("new")), color);
+    }
+
+    // Fills a single sampled target with one color from a fullscreen triangle
+    fn fill(device: &wgpu::Device, queue: &wgpu::Queue, target: &RenderTarget) {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(
+                "@vertex
+                fn vs_main(@builtin(vertex_index) i: u32) -> @builtin(position) vec4<f32> {
+                    let uv = vec2<f32>(f32((i << 1u) & 2u), f32(i & 2u));
+                    return vec4<f32>(uv * 2.0 - 1.0, 0.5, 1.0);
+                }
+                @fragment
+                fn fs_main() -> @location(0) vec4<f32> {
+                    return vec4<f32>(1.0, 0.0, 0.2, 1.0);
+                }"
+                .into(),
+            ),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: None,
+            vertex: wgpu::VertexState { module: &shader, entry_point: "vs_main", buffers: &[] },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(target.format.into())],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.color.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Clear(wgpu::Color::BLACK), store: true },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &target.depth.view,
+                    depth_ops: Some(wgpu::Operations { load: wgpu::LoadOp::Clear(1.0), store: true }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&pipeline);
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    #[test]
+    fn drawn_pixels_are_read_back() {
+        let Some((device, queue)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        // 70 pixels wide so rows need padding to COPY_BYTES_PER_ROW_ALIGNMENT.
+        // Single sampled only, the GL software adapter leaves resolve targets
+        // untouched.
+        for format in [wgpu::TextureFormat::Rgba8Unorm, wgpu::TextureFormat::Bgra8UnormSrgb] {
+            let target = RenderTarget::new(&device, 70, 5, format);
+            fill(&device, &queue, &target);
+            let image = target.read_image(&device, &queue).unwrap();
+            assert_eq!(image.dimensions(), (70, 5));
+            for pixel in image.pixels() {
+                // Stored as is in Unorm, encoded as sRGB in UnormSrgb
+                let expected = if format.describe().srgb { [255, 0, 124, 255] } else { [255, 0, 51, 255] };
+                assert!(pixel.0.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) <= 1), "{:?}: {:?}", format, pixel);
+            }
+        }
+
+        let float = RenderTarget::new(&device, 4, 4, wgpu::TextureFormat::Rgba16Float);
+        assert!(float.read_image(&device, &queue).is_err());
+    }
+}
@@ -1,83 +1,244 @@
+use std::cell::RefCell;
 use std::io::{BufReader, Cursor};
+use std::path::PathBuf;
+use std::sync::{OnceLock, RwLock};
 use cfg_if::cfg_if;
-use wgpu::util::DeviceExt;
-use cgmath::prelude::*;
 
-use crate::engine::{model, texture};
-
-use super::model::Instance;
+use crate::engine::{gltf_loader, gpu_image, mesh_cache, model};
+use crate::engine::gpu_image::ImageData;
+use crate::engine::asset_io::{AssetIo, AssetSources, MemoryAssetIo};
+use crate::engine::pack::PackAssetIo;
+use crate::engine::sampler::SamplerSettings;
+use crate::engine::pipeline::{AlphaMode, MaterialPipeline};
+use crate::engine::asset_error::{AssetError, find_obj_error_line};
 
 #[cfg(target_arch = "wasm32")]
-fn format_url(file_name: &str) -> reqwest::Url {
-    let window = web_sys::window().unwrap();
-    let location = window.location();
+fn format_url(root: &std::path::Path, file_name: &str) -> Result<reqwest::Url, AssetError> {
+    let invalid = |message: String| AssetError::Network {
+        path: file_name.to_string(),
+        message,
+    };
+    let origin = web_sys::window()
+        .and_then(|window| window.location().origin().ok())
+        .ok_or_else(|| invalid(String::from("page has no origin to load assets from")))?;
     let base = reqwest::Url::parse(&format!(
         "{}/{}/",
-        location.origin().unwrap(),
-        option_env!("RES_PATH").unwrap_or("res"),
-    )).unwrap();
-    base.join(file_name).unwrap()
+        origin,
+        root.to_string_lossy().trim_matches('/'),
+    )).map_err(|e| invalid(e.to_string()))?;
+    base.join(file_name).map_err(|e| invalid(e.to_string()))
+}
+
+fn sources() -> &'static RwLock<AssetSources> {
+    static SOURCES: OnceLock<RwLock<AssetSources>> = OnceLock::new();
+    SOURCES.get_or_init(Default::default)
+}
+
+// Files fetched by `preload`, served before touching the disk or network
+fn preloaded() -> &'static MemoryAssetIo {
+    static PRELOADED: OnceLock<MemoryAssetIo> = OnceLock::new();
+    PRELOADED.get_or_init(Default::default)
+}
+
+/// Replaces the directory (or URL path on the web) assets are loaded from.
+pub fn set_asset_root<P: Into<PathBuf>>(root: P) {
+    sources().write().unwrap().root = root.into();
+}
+
+pub fn asset_root() -> PathBuf {
+    sources().read().unwrap().root.clone()
+}
+
+/// Adds a directory that is searched before the root and earlier search paths.
+pub fn add_search_path<P: Into<PathBuf>>(path: P) {
+    sources().write().unwrap().add_search_path(path);
+}
+
+/// Mounts another source on top of everything mounted so far.
+pub fn mount<I: AssetIo + 'static>(io: I) {
+    sources().write().unwrap().mount(io);
 }
 
-pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
+/// Mounts the pack file `file_name` from the asset root on top of everything
+/// mounted so far. Natively it's read on demand, on the web it's downloaded
+/// whole first.
+pub async fn mount_pack(file_name: &str) -> Result<(), AssetError> {
     cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
-            let url = format_url(file_name);
-            let txt = reqwest::get(url)
-                .await?
-                .text()
-                .await?;
+            let data = load_binary(file_name).await?;
+            let pack = PackAssetIo::from_bytes(data);
         } else {
-            let path = std::path::Path::new(env!("CARGO_TARGET_DIR"))
-                .join("res")
-                .join(file_name);
-            let txt = std::fs::read_to_string(path)?;
+            let pack = PackAssetIo::open(asset_root().join(file_name));
         }
     }
+    mount(pack.map_err(|e| AssetError::io(file_name, e))?);
+    Ok(())
+}
+
+/// Called with `(loaded, total)` while preloading.
+pub type PreloadProgress = fn(usize, usize);
+
+/// Fetches `files` into memory so later loads complete without waiting,
+/// which is what makes the blocking loaders usable on the web.
+pub async fn preload(files: &[String], progress: Option<PreloadProgress>) -> Result<(), AssetError> {
+    for (i, file_name) in files.iter().enumerate() {
+        let data = load_binary(file_name).await?;
+        preloaded().insert(file_name, data);
+        if let Some(progress) = progress {
+            progress(i + 1, files.len());
+        }
+    }
+    Ok(())
+}
+
+pub async fn load_string(file_name: &str) -> Result<String, AssetError> {
+    let data = load_binary(file_name).await?;
+    String::from_utf8(data).map_err(|e| AssetError::parse(file_name, None, e))
+}
+
+thread_local! {
+    static READS: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` and returns every file it loaded along with its result, so hot
+/// reloading knows which files an asset was built from.
+pub(crate) fn record_reads<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    let outer = READS.with(|reads| reads.borrow_mut().replace(Vec::new()));
+    let result = f();
+    let reads = READS.with(|reads| std::mem::replace(&mut *reads.borrow_mut(), outer));
+    (result, reads.unwrap_or_default())
+}
 
-    Ok(txt)
+/// When the file behind `file_name` last changed, `None` if that can't be
+/// told (e.g. on the web).
+pub(crate) fn modified(file_name: &str) -> Option<std::time::SystemTime> {
+    sources().read().unwrap().modified(file_name)
 }
 
-pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
+pub(crate) fn size(file_name: &str) -> Option<u64> {
+    preloaded().size(file_name).or_else(|| sources().read().unwrap().size(file_name))
+}
+
+/// Drops a preloaded copy so the next load reads the file again.
+pub(crate) fn forget_preloaded(file_name: &str) {
+    preloaded().remove(file_name);
+}
+
+pub async fn load_binary(file_name: &str) -> Result<Vec<u8>, AssetError> {
+    READS.with(|reads| {
+        if let Some(reads) = reads.borrow_mut().as_mut() {
+            reads.push(file_name.to_string());
+        }
+    });
+
+    let from_overlays = |io: &dyn AssetIo| io.read(file_name).map_err(|e| AssetError::io(file_name, e));
+    if let Some(data) = from_overlays(preloaded())? {
+        return Ok(data);
+    }
+
+    let root = {
+        let sources = sources().read().unwrap();
+        if let Some(data) = sources.read_overlays(file_name).map_err(|e| AssetError::io(file_name, e))? {
+            return Ok(data);
+        }
+        sources.root.clone()
+    };
+
     cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
-            let url = format_url(file_name);
+            let url = format_url(&root, file_name)?;
+            let network_error = |e: reqwest::Error| {
+                if e.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+                    AssetError::NotFound { path: file_name.to_string() }
+                } else {
+                    AssetError::Network { path: file_name.to_string(), message: e.to_string() }
+                }
+            };
             let data = reqwest::get(url)
-                .await?
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(network_error)?
                 .bytes()
-                .await?
+                .await
+                .map_err(network_error)?
                 .to_vec();
         } else {
-            let path = std::path::Path::new(env!("CARGO_TARGET_DIR"))
-                .join("res")
-                .join(file_name);
-            let data = std::fs::read(path)?;
+            let data = std::fs::read(root.join(file_name))
+                .map_err(|e| AssetError::io(file_name, e))?;
         }
     }
 
     Ok(data)
 }
 
-pub async fn load_texture(
-    file_name: &str,
-    is_normal_map: bool,
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-) -> anyhow::Result<texture::Texture> {
+pub async fn load_window_icon(file_name: &str) -> anyhow::Result<winit::window::Icon> {
+    let image = load_image(file_name).await?;
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    Ok(winit::window::Icon::from_rgba(rgba.into_raw(), width, height)?)
+}
+
+pub async fn load_image(file_name: &str) -> Result<image::DynamicImage, AssetError> {
     let data = load_binary(file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name, is_normal_map)
+    image::load_from_memory(&data).map_err(|e| AssetError::image(file_name, e))
 }
 
+/// Like `load_image`, but also takes KTX2 and DDS files, which keep their
+/// GPU format instead of being decoded.
+pub async fn load_texture_image(file_name: &str) -> Result<ImageData, AssetError> {
+    let data = load_binary(file_name).await?;
+    gpu_image::decode(file_name, &data)
+}
+
+/// Loads a model and uploads it to the GPU.
 pub async fn load_model(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
-) -> anyhow::Result<model::Model> {
+) -> Result<model::Model, AssetError> {
+    let data = load_model_data(file_name).await?;
+    data.upload(device, queue, layout, file_name)
+}
+
+/// Parses a model and decodes its textures without touching the GPU, so it
+/// can run on a background thread. An up to date mesh cache next to the
+/// model (see `MeshCache::import`) is used instead of the source if present.
+pub async fn load_model_data(file_name: &str) -> Result<model::ModelData, AssetError> {
+    if let Some(data) = mesh_cache::load_cached(file_name).await? {
+        return Ok(data);
+    }
+    load_source_model_data(file_name).await
+}
+
+/// Loads the model's CPU side data from its source, picking the format by
+/// file extension.
+pub async fn load_source_model_data(file_name: &str) -> Result<model::ModelData, AssetError> {
+    let extension = std::path::Path::new(file_name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("obj") => load_obj_data(file_name).await,
+        Some("gltf" | "glb") => gltf_loader::load_gltf_data(file_name).await,
+        Some("mesh") => {
+            let data = load_binary(file_name).await?;
+            mesh_cache::MeshCache::from_bytes(file_name, &data)?.load(file_name).await
+        }
+        _ => Err(AssetError::UnsupportedFormat {
+            path: file_name.to_string(),
+            reason: String::from("models have to be .obj, .gltf, .glb or .mesh files"),
+        }),
+    }
+}
+
+pub async fn load_obj_data(file_name: &str) -> Result<model::ModelData, AssetError> {
     let obj_text = load_string(file_name).await?;
-    let obj_cursor = Cursor::new(obj_text);
+    let obj_cursor = Cursor::new(&obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
 
+    // tobj only lets the material loader return its own error type, so keep
+    // the real one around
+    let mtl_error = RefCell::new(None);
     let (models, obj_materials) = tobj::load_obj_buf_async(
         &mut obj_reader,
         &tobj::LoadOptions {
@@ -85,150 +246,313 @@ pub async fn load_model(
             single_index: true,
             ..Default::default()
         },
-        |p| async move {
-            let mat_text = load_string(&p).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+        |p| {
+            let mtl_error = &mtl_error;
+            async move {
+                match load_string(&p).await {
+                    Ok(mat_text) => tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+                        .inspect_err(|&e| {
+                            *mtl_error.borrow_mut() = Some(AssetError::parse(&p, None, e));
+                        }),
+                    Err(e) => {
+                        *mtl_error.borrow_mut() = Some(e);
+                        Err(tobj::LoadError::OpenFileFailed)
+                    }
+                }
+            }
         },
     )
-    .await?;
+    .await
+    .map_err(|e| AssetError::parse(file_name, find_obj_error_line(&obj_text, &e), e))?;
+
+    let obj_materials = match obj_materials {
+        Ok(materials) => materials,
+        Err(e) => return Err(mtl_error.into_inner().unwrap_or_else(|| AssetError::parse(file_name, None, e))),
+    };
+
+    let load_material_texture = |material: &tobj::Material, texture: &str| {
+        let material = material.name.clone();
+        let texture = split_texture_options(texture).0.to_string();
+        async move {
+            if texture.is_empty() {
+                return Ok(None);
+            }
+            let image = load_texture_image(&texture).await.map_err(|e| AssetError::MissingTexture {
+                path: file_name.to_string(),
+                material,
+                texture: texture.clone(),
+                source: Box::new(e),
+            })?;
+            Ok(Some(model::TextureData { label: texture, image, encoded: None }))
+        }
+    };
 
     let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture, false, device, queue).await?;
-        let normal_texture = load_texture(&m.normal_texture, true, device, queue).await?;
-
-        materials.push(model::Material::new(
-            device,
-            &m.name,
-            diffuse_texture,
+    for m in obj_materials {
+        // tobj only knows the classic Phong statements, the PBR extension
+        // ends up in unknown_param
+        let param = |name: &str| m.unknown_param.get(name).map(String::as_str);
+        let float_param = |name: &str| param(name).and_then(|value| value.trim().parse::<f32>().ok());
+        let emissive_map = param("map_Ke").unwrap_or("").to_string();
+
+        let mut base_color_texture = load_material_texture(&m, &m.diffuse_texture).await?;
+        let dissolve_texture = load_material_texture(&m, &m.dissolve_texture).await?;
+        let normal_texture = load_material_texture(&m, &m.normal_texture).await?;
+        let emissive_texture = load_material_texture(&m, &emissive_map).await?;
+
+        // Kd is what untextured exports use for their color. Exporters also
+        // write it next to textures, where it would only darken them.
+        let base_color = match base_color_texture {
+            Some(_) => [1.0; 3],
+            None => m.diffuse,
+        };
+
+        // map_d is mostly used for cutouts, the shader only looks at the
+        // base color's alpha so it goes in there
+        let mut masked = false;
+        if let Some(dissolve) = &dissolve_texture {
+            match with_dissolve_alpha(base_color_texture.as_ref(), dissolve) {
+                Some(texture) => {
+                    base_color_texture = Some(texture);
+                    masked = true;
+                }
+                None => log::warn!(
+                    "{}: compressed map_d or map_Kd in material {} can't be combined, drawing it opaque",
+                    file_name,
+                    m.name,
+                ),
+            }
+        }
+        let emissive = param("Ke")
+            .and_then(|value| {
+                let rgb = value.split_whitespace().map(|v| v.parse::<f32>().ok()).collect::<Option<Vec<_>>>()?;
+                match rgb[..] {
+                    [r, g, b] => Some([r, g, b]),
+                    [l] => Some([l; 3]),
+                    _ => None,
+                }
+            })
+            .unwrap_or(if emissive_texture.is_some() { [1.0; 3] } else { [0.0; 3] });
+        let uniform = model::MaterialUniform {
+            base_color: [base_color[0], base_color[1], base_color[2], m.dissolve.clamp(0.0, 1.0)],
+            emissive,
+            metallic: float_param("Pm").unwrap_or(0.0).clamp(0.0, 1.0),
+            // Blinn-Phong exponent to GGX roughness, Ns 0 is fully rough
+            roughness: float_param("Pr")
+                .unwrap_or_else(|| (2.0 / (m.shininess.max(0.0) + 2.0)).sqrt())
+                .clamp(0.0, 1.0),
+            ..Default::default()
+        };
+
+        // tobj leaves texture options in the path, only -clamp matters to us
+        let clamp = [&m.diffuse_texture, &m.normal_texture, &emissive_map]
+            .into_iter()
+            .any(|texture| split_texture_options(texture).1);
+        let sampler = if clamp {
+            SamplerSettings::default().with_address_mode(wgpu::AddressMode::ClampToEdge)
+        } else {
+            SamplerSettings::default()
+        };
+
+        let alpha_mode = if m.dissolve < 1.0 {
+            AlphaMode::Blend
+        } else if masked {
+            AlphaMode::Mask
+        } else {
+            AlphaMode::Opaque
+        };
+
+        materials.push(model::MaterialData {
+            name: m.name,
+            base_color_texture,
+            metallic_roughness_texture: None,
             normal_texture,
-            layout,
-        ))
+            occlusion_texture: None,
+            emissive_texture,
+            uniform,
+            sampler,
+            pipeline: MaterialPipeline::default().with_alpha_mode(alpha_mode),
+        });
     }
 
-    let meshes = models
-        .into_iter()
-        .map(|m| {
-            let mut vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| model::ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                    tangent: [0.0; 3],
-                    bitangent: [0.0; 3],
-                })
-                .collect::<Vec<_>>();
-
-            let indices = &m.mesh.indices;
-            let mut triangles_included = vec![0; vertices.len()];
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0: cgmath::Vector3<_> = v0.position.into();
-                let pos1: cgmath::Vector3<_> = v1.position.into();
-                let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                // We flip the bitangent to enable right-handed normal
-                // maps with wgpu texture coordinate system
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
-            }
+    // Meshes without a usable material share a plain white one
+    let mut fallback_material = None;
+    let material_count = materials.len();
+    let mut material_for = |id: Option<usize>| match id {
+        Some(id) if id < material_count => id,
+        _ => *fallback_material.get_or_insert(material_count),
+    };
 
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let mut v = &mut vertices[i];
-                v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
-                v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
-            }
+    let mut meshes = Vec::new();
+    for m in models {
+        let mesh = m.mesh;
+        let vertex_count = mesh.positions.len() / 3;
+        let has_tex_coords = mesh.texcoords.len() >= vertex_count * 2;
+        let has_normals = mesh.normals.len() >= vertex_count * 3;
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
-
-            model::Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            }
-        })
-        .collect::<Vec<_>>();
+        let vertices = (0..vertex_count)
+            .map(|i| model::ModelVertex {
+                position: [
+                    mesh.positions[i * 3],
+                    mesh.positions[i * 3 + 1],
+                    mesh.positions[i * 3 + 2],
+                ],
+                tex_coords: if has_tex_coords {
+                    [mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1]]
+                } else {
+                    [0.0; 2]
+                },
+                normal: if has_normals {
+                    [
+                        mesh.normals[i * 3],
+                        mesh.normals[i * 3 + 1],
+                        mesh.normals[i * 3 + 2],
+                    ]
+                } else {
+                    [0.0; 3]
+                },
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(&index) = mesh.indices.iter().find(|&&index| index as usize >= vertex_count) {
+            return Err(AssetError::parse(
+                file_name,
+                None,
+                format!("mesh {} references vertex {} but only has {}", m.name, index, vertex_count),
+            ));
+        }
 
-    let instance = Instance {
-        position: cgmath::Vector3::new(0.0, 0.0, 0.0),
-        rotation: cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
+        let mut mesh_data = model::MeshData {
+            name: file_name.to_string(),
+            vertices,
+            indices: mesh.indices,
+            material: material_for(mesh.material_id),
+        };
+        if !has_normals {
+            mesh_data.compute_smooth_normals();
+        }
+        mesh_data.compute_tangents();
+        meshes.push(mesh_data);
+    }
+
+    if fallback_material.is_some() {
+        materials.push(model::MaterialData::fallback());
+    }
+
+    Ok(model::ModelData { meshes, materials, skin: None })
+}
+
+/// `base` with its alpha multiplied by the brightness of `dissolve`, white
+/// without a `base`. `None` if either is compressed.
+fn with_dissolve_alpha(base: Option<&model::TextureData>, dissolve: &model::TextureData) -> Option<model::TextureData> {
+    let ImageData::Decoded(dissolve_image) = &dissolve.image else {
+        return None;
+    };
+    let (mut image, label) = match base {
+        Some(base) => {
+            let ImageData::Decoded(image) = &base.image else {
+                return None;
+            };
+            (image.to_rgba8(), format!("{}+{}", base.label, dissolve.label))
+        }
+        None => {
+            let white = image::RgbaImage::from_pixel(dissolve_image.width(), dissolve_image.height(), image::Rgba([255; 4]));
+            (white, format!("{}#alpha", dissolve.label))
+        }
+    };
+    let mut alpha = dissolve_image.to_luma8();
+    if alpha.dimensions() != image.dimensions() {
+        alpha = image::imageops::resize(&alpha, image.width(), image.height(), image::imageops::FilterType::Triangle);
+    }
+    for (pixel, value) in image.pixels_mut().zip(alpha.pixels()) {
+        pixel[3] = (pixel[3] as u32 * value[0] as u32 / 255) as u8;
+    }
+    // A new label so mesh caches embed the result instead of the base file
+    Some(model::TextureData { label, image: ImageData::Decoded(image.into()), encoded: None })
+}
+
+/// Splits the options off an MTL texture statement like
+/// `map_Kd -clamp on -s 2 2 road.png`, returning the path and whether
+/// `-clamp on` was given.
+fn split_texture_options(texture: &str) -> (&str, bool) {
+    let mut rest = texture.trim();
+    let mut clamp = false;
+    let next_word = |rest: &mut &str| {
+        let (word, remainder) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        *rest = remainder.trim_start();
+        word.to_string()
     };
-    let instances = vec![instance];
-    let instance_data = instances.iter().map(model::Instance::to_raw).collect::<Vec<_>>();
-    let instance_buffer = device.create_buffer_init(
-        &wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
+
+    while rest.starts_with('-') {
+        let option = next_word(&mut rest);
+        // How many values each option takes, -o, -s and -t take up to three
+        let (min, max) = match option.as_str() {
+            "-blendu" | "-blendv" | "-cc" | "-clamp" | "-bm" | "-boost" | "-texres" | "-imfchan" => (1, 1),
+            "-mm" => (2, 2),
+            "-o" | "-s" | "-t" => (1, 3),
+            // Unknown, leave it to the image loader to complain
+            _ => return (texture.trim(), clamp),
+        };
+        for i in 0..max {
+            let is_number = rest.split_whitespace().next().is_some_and(|word| word.parse::<f32>().is_ok());
+            if i >= min && !is_number {
+                break;
+            }
+            let value = next_word(&mut rest);
+            if option == "-clamp" {
+                clamp = value == "on";
+            }
         }
-    );
+    }
+    (rest, clamp)
+}
 
-    Ok(model::Model { meshes, materials, instances, instance_buffer } )
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texture_options_are_split_off() {
+        assert_eq!(split_texture_options("road.png"), ("road.png", false));
+        assert_eq!(split_texture_options("  road.png "), ("road.png", false));
+        assert_eq!(split_texture_options("-clamp on -s 2 2 road.png"), ("road.png", true));
+        assert_eq!(split_texture_options("-s 2 2 -clamp off road.png"), ("road.png", false));
+        assert_eq!(split_texture_options("-o 0.5 -bm 1.5 textures/brick normal.png"), ("textures/brick normal.png", false));
+        assert_eq!(split_texture_options("-mm 0 1 -t 1 1 1 -clamp on grass.png"), ("grass.png", true));
+        // Unknown options are left in for the image loader to report
+        assert_eq!(split_texture_options("-zz 1 road.png"), ("-zz 1 road.png", false));
+        assert_eq!(split_texture_options(""), ("", false));
+    }
+
+    #[test]
+    fn dissolve_maps_become_alpha() {
+        let texture = |label: &str, image: image::DynamicImage| model::TextureData {
+            label: label.to_string(),
+            image: ImageData::Decoded(image),
+            encoded: None,
+        };
+        let base = texture("leaf.png", image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 200, 30, 255])).into());
+        let mut mask = image::GrayImage::new(2, 2);
+        mask.put_pixel(1, 0, image::Luma([255]));
+        let dissolve = texture("leaf_mask.png", mask.into());
+
+        let combined = with_dissolve_alpha(Some(&base), &dissolve).unwrap();
+        assert_ne!(combined.label, base.label);
+        let ImageData::Decoded(image) = &combined.image else {
+            panic!("not decoded");
+        };
+        let image = image.to_rgba8();
+        assert_eq!(image.dimensions(), (4, 4));
+        assert_eq!(image.get_pixel(0, 3).0, [10, 200, 30, 0]);
+        assert_eq!(image.get_pixel(3, 0).0, [10, 200, 30, 255]);
+
+        // Without a color texture the mask goes on plain white
+        let alone = with_dissolve_alpha(None, &dissolve).unwrap();
+        let ImageData::Decoded(image) = &alone.image else {
+            panic!("not decoded");
+        };
+        assert_eq!(image.to_rgba8().get_pixel(1, 0).0, [255; 4]);
+        assert_eq!(image.to_rgba8().get_pixel(0, 0).0, [255, 255, 255, 0]);
+    }
 }
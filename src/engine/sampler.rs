@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::engine::texture::Filtering;
+
+/// How a texture is addressed and filtered. Textures with identical
+/// settings share a single `wgpu::Sampler`, see `cached`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SamplerSettings {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub address_mode_w: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+    /// 1 turns it off, otherwise 2, 4, 8 or 16 samples
+    pub anisotropy: u8,
+    /// Only used with `AddressMode::ClampToBorder`
+    pub border_color: Option<wgpu::SamplerBorderColor>,
+}
+
+impl Default for SamplerSettings {
+    /// Repeating and trilinear, what tiled textures and most models expect.
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            anisotropy: 1,
+            border_color: None,
+        }
+    }
+}
+
+impl SamplerSettings {
+    pub fn with_address_mode(mut self, mode: wgpu::AddressMode) -> Self {
+        self.address_mode_u = mode;
+        self.address_mode_v = mode;
+        self.address_mode_w = mode;
+        self
+    }
+
+    /// Sets the minification filters and anisotropy, magnification stays
+    /// linear.
+    pub fn with_filtering(mut self, filtering: Filtering) -> Self {
+        self.mag_filter = wgpu::FilterMode::Linear;
+        self.min_filter = wgpu::FilterMode::Linear;
+        (self.mipmap_filter, self.anisotropy) = match filtering {
+            Filtering::Bilinear => (wgpu::FilterMode::Nearest, 1),
+            Filtering::Trilinear => (wgpu::FilterMode::Linear, 1),
+            Filtering::Anisotropic(samples) => (wgpu::FilterMode::Linear, samples),
+        };
+        self
+    }
+
+    /// Clamps to `color` outside of the texture. Needs
+    /// `Features::ADDRESS_MODE_CLAMP_TO_BORDER`, without it the edge is
+    /// repeated instead.
+    pub fn with_border_color(mut self, color: wgpu::SamplerBorderColor) -> Self {
+        self.border_color = Some(color);
+        self.with_address_mode(wgpu::AddressMode::ClampToBorder)
+    }
+
+    /// Settings the device can't handle are swapped for the closest ones it
+    /// can, instead of failing validation.
+    fn supported(mut self, features: wgpu::Features) -> Self {
+        if !features.contains(wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER) {
+            for mode in [&mut self.address_mode_u, &mut self.address_mode_v, &mut self.address_mode_w] {
+                if *mode == wgpu::AddressMode::ClampToBorder {
+                    *mode = wgpu::AddressMode::ClampToEdge;
+                }
+            }
+        }
+        // wgpu only takes powers of two up to 16, and anisotropy only works
+        // with every filter set to linear
+        let all_linear = [self.mag_filter, self.min_filter, self.mipmap_filter]
+            .iter()
+            .all(|&filter| filter == wgpu::FilterMode::Linear);
+        self.anisotropy = if all_linear {
+            self.anisotropy.clamp(1, 16).next_power_of_two()
+        } else {
+            1
+        };
+        self
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; 8] {
+        let address_mode = |mode| match mode {
+            wgpu::AddressMode::ClampToEdge => 0,
+            wgpu::AddressMode::Repeat => 1,
+            wgpu::AddressMode::MirrorRepeat => 2,
+            wgpu::AddressMode::ClampToBorder => 3,
+        };
+        let filter = |filter| match filter {
+            wgpu::FilterMode::Nearest => 0,
+            wgpu::FilterMode::Linear => 1,
+        };
+        let border_color = match self.border_color {
+            None => 0,
+            Some(wgpu::SamplerBorderColor::TransparentBlack) => 1,
+            Some(wgpu::SamplerBorderColor::OpaqueBlack) => 2,
+            Some(wgpu::SamplerBorderColor::OpaqueWhite) => 3,
+            Some(wgpu::SamplerBorderColor::Zero) => 4,
+        };
+        [
+            address_mode(self.address_mode_u),
+            address_mode(self.address_mode_v),
+            address_mode(self.address_mode_w),
+            filter(self.mag_filter),
+            filter(self.min_filter),
+            filter(self.mipmap_filter),
+            self.anisotropy,
+            border_color,
+        ]
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 8]) -> Result<Self, String> {
+        let address_mode = |value| match value {
+            0 => Ok(wgpu::AddressMode::ClampToEdge),
+            1 => Ok(wgpu::AddressMode::Repeat),
+            2 => Ok(wgpu::AddressMode::MirrorRepeat),
+            3 => Ok(wgpu::AddressMode::ClampToBorder),
+            _ => Err(format!("unknown address mode {}", value)),
+        };
+        let filter = |value| match value {
+            0 => Ok(wgpu::FilterMode::Nearest),
+            1 => Ok(wgpu::FilterMode::Linear),
+            _ => Err(format!("unknown filter {}", value)),
+        };
+        let border_color = match bytes[7] {
+            0 => None,
+            1 => Some(wgpu::SamplerBorderColor::TransparentBlack),
+            2 => Some(wgpu::SamplerBorderColor::OpaqueBlack),
+            3 => Some(wgpu::SamplerBorderColor::OpaqueWhite),
+            4 => Some(wgpu::SamplerBorderColor::Zero),
+            value => return Err(format!("unknown border color {}", value)),
+        };
+        Ok(Self {
+            address_mode_u: address_mode(bytes[0])?,
+            address_mode_v: address_mode(bytes[1])?,
+            address_mode_w: address_mode(bytes[2])?,
+            mag_filter: filter(bytes[3])?,
+            min_filter: filter(bytes[4])?,
+            mipmap_filter: filter(bytes[5])?,
+            anisotropy: bytes[6],
+            border_color,
+        })
+    }
+}
+
+thread_local! {
+    // Keyed by device too, samplers can't be shared between devices. All
+    // uploads happen on the thread that owns the device, so there's no need
+    // to lock. Device ids are only unique within an instance, the engine
+    // only ever creates one. Samplers hold on to their device, `State`
+    // drops a device's entries along with it, see `forget_device`.
+    static SAMPLERS: RefCell<HashMap<(wgpu::Id, SamplerSettings), Arc<wgpu::Sampler>>> = RefCell::new(HashMap::new());
+}
+
+/// The sampler for `settings`, created on first use and shared afterwards.
+pub fn cached(device: &wgpu::Device, settings: &SamplerSettings) -> Arc<wgpu::Sampler> {
+    let settings = settings.supported(device.features());
+    SAMPLERS.with(|samplers| {
+        samplers
+            .borrow_mut()
+            .entry((device.global_id(), settings))
+            .or_insert_with(|| {
+                Arc::new(device.create_sampler(&wgpu::SamplerDescriptor {
+                    label: Some("Cached Sampler"),
+                    address_mode_u: settings.address_mode_u,
+                    address_mode_v: settings.address_mode_v,
+                    address_mode_w: settings.address_mode_w,
+                    mag_filter: settings.mag_filter,
+                    min_filter: settings.min_filter,
+                    mipmap_filter: settings.mipmap_filter,
+                    anisotropy_clamp: std::num::NonZeroU8::new(settings.anisotropy).filter(|&clamp| clamp.get() > 1),
+                    border_color: settings.border_color,
+                    ..Default::default()
+                }))
+            })
+            .clone()
+    })
+}
+
+/// Drops the samplers cached for `device`, so it can be freed once nothing
+/// else uses them. Samplers handed out before stay valid.
+pub fn forget_device(device: &wgpu::Device) {
+    let id = device.global_id();
+    SAMPLERS.with(|samplers| samplers.borrow_mut().retain(|(device, _), _| *device != id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_round_trip_through_bytes() {
+        let settings = [
+            SamplerSettings::default(),
+            SamplerSettings::default().with_filtering(Filtering::Anisotropic(8)),
+            SamplerSettings::default().with_filtering(Filtering::Bilinear).with_address_mode(wgpu::AddressMode::MirrorRepeat),
+            SamplerSettings::default().with_border_color(wgpu::SamplerBorderColor::OpaqueWhite),
+            SamplerSettings {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Nearest,
+                border_color: Some(wgpu::SamplerBorderColor::Zero),
+                ..Default::default()
+            },
+        ];
+        for settings in settings {
+            assert_eq!(SamplerSettings::from_bytes(settings.to_bytes()), Ok(settings));
+        }
+    }
+
+    #[test]
+    fn unknown_bytes_are_rejected() {
+        let bytes = SamplerSettings::default().to_bytes();
+        for (i, value) in [(0, 4), (3, 2), (5, 9), (7, 5)] {
+            let mut broken = bytes;
+            broken[i] = value;
+            assert!(SamplerSettings::from_bytes(broken).is_err(), "byte {} = {}", i, value);
+        }
+    }
+
+    #[test]
+    fn unsupported_settings_fall_back() {
+        let border = SamplerSettings::default().with_border_color(wgpu::SamplerBorderColor::OpaqueBlack);
+        let supported = border.supported(wgpu::Features::empty());
+        assert_eq!(supported.address_mode_u, wgpu::AddressMode::ClampToEdge);
+        assert_eq!(supported.address_mode_w, wgpu::AddressMode::ClampToEdge);
+        assert_eq!(border.supported(wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER), border);
+
+        let anisotropy = |samples| SamplerSettings::default().with_filtering(Filtering::Anisotropic(samples)).supported(wgpu::Features::empty()).anisotropy;
+        assert_eq!(anisotropy(0), 1);
+        assert_eq!(anisotropy(3), 4);
+        assert_eq!(anisotropy(16), 16);
+        assert_eq!(anisotropy(64), 16);
+
+        // Anisotropy needs every filter linear
+        let nearest = SamplerSettings {
+            anisotropy: 8,
+            mag_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        };
+        assert_eq!(nearest.supported(wgpu::Features::empty()).anisotropy, 1);
+    }
+
+    #[test]
+    fn samplers_are_shared_until_their_device_is_forgotten() {
+        let Some((device, _)) = crate::engine::software_device() else {
+            eprintln!("No software adapter, skipping");
+            return;
+        };
+        let settings = SamplerSettings::default();
+        let sampler = cached(&device, &settings);
+        assert!(Arc::ptr_eq(&sampler, &cached(&device, &settings)));
+        assert_eq!(Arc::strong_count(&sampler), 2);
+
+        forget_device(&device);
+        assert_eq!(Arc::strong_count(&sampler), 1);
+        assert!(!Arc::ptr_eq(&sampler, &cached(&device, &settings)));
+        forget_device(&device);
+    }
+}
@@ -0,0 +1,196 @@
+/// Controls which backend, adapter and device the renderer asks wgpu for.
+///
+/// `WGPU_BACKEND` (e.g. `vulkan,gl`) and `WGPU_POWER_PREF` (`low` / `high`)
+/// override `backends` and `power_preference` at runtime when set.
+#[derive(Debug, Clone)]
+pub struct RendererSettings {
+    /// Set to e.g. `wgpu::Backends::VULKAN` to only try one backend
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    /// Only accept a software adapter (llvmpipe, WARP, SwiftShader, ...)
+    pub force_fallback_adapter: bool,
+    /// The device request fails if any of these are missing
+    pub required_features: wgpu::Features,
+    /// Enabled when the adapter supports them, silently skipped otherwise
+    pub optional_features: wgpu::Features,
+    /// `None` picks sensible defaults for the target (WebGL2 limits on the web)
+    pub limits: Option<wgpu::Limits>,
+}
+
+impl Default for RendererSettings {
+    fn default() -> Self {
+        Self {
+            // WebGL is only in `all`, natively the primary backends are
+            // Vulkan, Metal, DX12 and WebGPU
+            backends: if cfg!(target_arch = "wasm32") {
+                wgpu::Backends::all()
+            } else {
+                wgpu::Backends::PRIMARY
+            },
+            power_preference: wgpu::PowerPreference::default(),
+            force_fallback_adapter: false,
+            required_features: wgpu::Features::empty(),
+            // Lets SamplerSettings::with_border_color work, compressed
+            // textures skip the CPU fallback and MSAA use 2 and 8 samples
+            // where supported
+            optional_features: wgpu::Features::ADDRESS_MODE_CLAMP_TO_BORDER
+                | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+                | wgpu::Features::TEXTURE_COMPRESSION_BC
+                | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+                | wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR,
+            limits: None,
+        }
+    }
+}
+
+impl RendererSettings {
+    /// Settings for running on a software adapter, e.g. for golden-image tests in CI.
+    pub fn software() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            force_fallback_adapter: true,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn backends(&self) -> wgpu::Backends {
+        wgpu::util::backend_bits_from_env().unwrap_or(self.backends)
+    }
+
+    pub(crate) fn power_preference(&self) -> wgpu::PowerPreference {
+        wgpu::util::power_preference_from_env().unwrap_or(self.power_preference)
+    }
+
+    pub(crate) fn limits(&self) -> wgpu::Limits {
+        self.limits.clone().unwrap_or_else(|| {
+            // WebGL doesn't support all of wgpu's features, so if
+            // we're building for the web we'll have to disable some.
+            if cfg!(target_arch = "wasm32") {
+                wgpu::Limits::downlevel_webgl2_defaults()
+            } else {
+                wgpu::Limits::default()
+            }
+        })
+    }
+}
+
+/// How the window occupies the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowMode {
+    Windowed,
+    /// Covers the current monitor without changing its video mode
+    BorderlessFullscreen,
+    /// Switches the current monitor to its largest video mode
+    Fullscreen,
+}
+
+/// Window options used when the window is created. The same struct is
+/// inserted into the world as a resource, systems can change it at runtime
+/// and the differences are applied before the next frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowSettings {
+    pub title: String,
+    /// Logical size of the window's client area
+    pub width: u32,
+    pub height: u32,
+    pub min_size: Option<(u32, u32)>,
+    pub resizable: bool,
+    pub mode: WindowMode,
+    /// Keeps the cursor inside the window, useful for mouse look
+    pub cursor_grab: bool,
+    pub cursor_visible: bool,
+    /// Image file (relative to the asset root) used as the window icon
+    pub icon: Option<String>,
+    /// `AutoVsync` / `Fifo` for vsync, `Mailbox` or `Immediate` to uncap the frame rate.
+    /// Falls back to `Fifo` when the surface doesn't support the requested mode.
+    pub present_mode: wgpu::PresentMode,
+    /// Web only: id of the `<canvas>` to draw into, or of the element the
+    /// canvas is appended to. The canvas is sized to fit that container.
+    pub canvas_id: Option<String>,
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        Self {
+            title: String::from("bones"),
+            width: 1280,
+            height: 720,
+            min_size: None,
+            resizable: true,
+            mode: WindowMode::Windowed,
+            cursor_grab: false,
+            cursor_visible: true,
+            icon: None,
+            present_mode: wgpu::PresentMode::AutoVsync,
+            canvas_id: Some(String::from("wasm-canvas")),
+        }
+    }
+}
+
+impl WindowSettings {
+    pub(crate) fn window_builder(&self) -> winit::window::WindowBuilder {
+        let mut builder = winit::window::WindowBuilder::new()
+            .with_title(&self.title)
+            .with_inner_size(winit::dpi::LogicalSize::new(self.width, self.height))
+            .with_resizable(self.resizable);
+        if let Some((width, height)) = self.min_size {
+            builder = builder.with_min_inner_size(winit::dpi::LogicalSize::new(width, height));
+        }
+        if self.mode == WindowMode::BorderlessFullscreen {
+            builder = builder.with_fullscreen(Some(winit::window::Fullscreen::Borderless(None)));
+        }
+        #[cfg(target_arch = "wasm32")]
+        {
+            use winit::platform::web::WindowBuilderExtWebSys;
+            builder = builder.with_canvas(self.canvas_id.as_deref().and_then(super::web::find_canvas));
+        }
+        builder
+    }
+}
+
+/// How shadows from `DirectionalLight` and `SpotLight` are rendered. Lives
+/// in the world as a resource, changes are applied before the next frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowSettings {
+    /// Width and height of every shadow map, 0 counts as 1
+    pub map_size: u32,
+    /// Shadow maps the sun's shadow is split into, 1 to 4. Each one covers
+    /// a range further away from the camera at a lower resolution.
+    pub cascades: u32,
+    /// How far from the camera the sun's shadow reaches
+    pub distance: f32,
+    /// Shadow map texels averaged in each direction to soften edges, 0 for
+    /// hard shadows. Costs (2 * radius + 1)² samples.
+    pub pcf_radius: u32,
+    /// Moves the shadow lookup along the surface normal, in world units,
+    /// against shadow acne on surfaces facing away from the light
+    pub normal_bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            map_size: 2048,
+            cascades: 4,
+            distance: 200.0,
+            pcf_radius: 1,
+            normal_bias: 0.02,
+        }
+    }
+}
+
+/// Multisample anti-aliasing of the scene. Lives in the world as a
+/// resource, changes are applied before the next frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsaaSettings {
+    /// Samples per pixel, 1 turns MSAA off. 4 works everywhere, 2 and 8
+    /// depend on the adapter and fall back to the next lower count, see
+    /// `State::supported_sample_counts`.
+    pub samples: u32,
+}
+
+impl Default for MsaaSettings {
+    fn default() -> Self {
+        Self { samples: 4 }
+    }
+}
@@ -13,6 +13,62 @@ struct Light {
 }
 @group(2) @binding(0)
 var<uniform> light: Light;
+@group(2) @binding(1)
+var t_environment: texture_cube<f32>;
+@group(2) @binding(2)
+var s_environment: sampler;
+
+// Matches environment::EnvironmentUniform
+struct Environment {
+    // Irradiance divided by pi as spherical harmonics, rgb only
+    sh: array<vec4<f32>, 9>,
+    intensity: f32,
+    mip_count: f32,
+}
+@group(2) @binding(3)
+var<uniform> environment: Environment;
+
+// Matches shadow::SpotLightUniform
+struct SpotLight {
+    position: vec3<f32>,
+    range: f32,
+    direction: vec3<f32>,
+    cos_outer: f32,
+    color: vec3<f32>,
+    cos_inner: f32,
+    view_proj: mat4x4<f32>,
+    // -1 without a shadow
+    shadow_layer: i32,
+}
+
+// Matches shadow::LightsUniform
+struct Lights {
+    sun_direction: vec3<f32>,
+    sun_shadows: u32,
+    sun_color: vec3<f32>,
+    cascade_count: u32,
+    cascade_view_proj: array<mat4x4<f32>, 4>,
+    cascade_splits: vec4<f32>,
+    spots: array<SpotLight, 4>,
+    spot_count: u32,
+    pcf_radius: i32,
+    texel_size: f32,
+    normal_bias: f32,
+}
+@group(2) @binding(4)
+var<uniform> lights: Lights;
+// A layer per cascade, then one per spot light casting shadows
+@group(2) @binding(5)
+var t_shadow: texture_depth_2d_array;
+@group(2) @binding(6)
+var s_shadow: sampler_comparison;
+
+// Matches animation::MAX_JOINTS
+struct Skin {
+    joints: array<mat4x4<f32>, 128>,
+}
+@group(3) @binding(0)
+var<uniform> skin: Skin;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -20,6 +76,8 @@ struct VertexInput {
     @location(2) normal: vec3<f32>,
     @location(3) tangent: vec3<f32>,
     @location(4) bitangent: vec3<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
 }
 struct InstanceInput {
     @location(5) model_matrix_0: vec4<f32>,
@@ -34,9 +92,12 @@ struct InstanceInput {
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
-    @location(1) tangent_position: vec3<f32>,
-    @location(2) tangent_light_position: vec3<f32>,
-    @location(3) tangent_view_position: vec3<f32>,
+    @location(1) world_position: vec3<f32>,
+    @location(2) world_normal: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
+    // Distance from the camera along its view direction, picks the cascade
+    @location(5) view_depth: f32,
 }
 
 @vertex
@@ -56,60 +117,238 @@ fn vs_main(
         instance.normal_matrix_2,
     );
 
-    // Construct the tangent matrix
-    let world_normal = normalize(normal_matrix * model.normal);
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
-    let tangent_matrix = transpose(mat3x3<f32>(
-        world_tangent,
-        world_bitangent,
-        world_normal,
-    ));
-    
-    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    // Static meshes have no weights and stay as they are
+    var skin_matrix = mat4x4<f32>(
+        vec4<f32>(1.0, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, 1.0, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, 1.0, 0.0),
+        vec4<f32>(0.0, 0.0, 0.0, 1.0),
+    );
+    if (dot(model.weights, vec4<f32>(1.0)) > 0.0) {
+        skin_matrix = skin.joints[model.joints.x] * model.weights.x
+            + skin.joints[model.joints.y] * model.weights.y
+            + skin.joints[model.joints.z] * model.weights.z
+            + skin.joints[model.joints.w] * model.weights.w;
+    }
+    let skin_normal_matrix = mat3x3<f32>(skin_matrix[0].xyz, skin_matrix[1].xyz, skin_matrix[2].xyz);
+
+    let world_position = model_matrix * skin_matrix * vec4<f32>(model.position, 1.0);
 
+    // Lighting happens in world space, where the environment is
     var out: VertexOutput;
     out.clip_position = camera.view_proj * world_position;
+    out.view_depth = out.clip_position.w;
     out.tex_coords = model.tex_coords;
-    out.tangent_position = tangent_matrix * world_position.xyz;
-    out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
-    out.tangent_light_position = tangent_matrix * light.position;
+    out.world_position = world_position.xyz;
+    out.world_normal = normal_matrix * skin_normal_matrix * model.normal;
+    out.world_tangent = normal_matrix * skin_normal_matrix * model.tangent;
+    out.world_bitangent = normal_matrix * skin_normal_matrix * model.bitangent;
     return out;
 }
 
 // Fragment shader
 
 @group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
-@group(0)@binding(1)
-var s_diffuse: sampler;
-@group(0)@binding(2)
+var t_base_color: texture_2d<f32>;
+@group(0) @binding(1)
+var s_base_color: sampler;
+@group(0) @binding(2)
 var t_normal: texture_2d<f32>;
 @group(0) @binding(3)
 var s_normal: sampler;
+@group(0) @binding(5)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(6)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(7)
+var t_emissive: texture_2d<f32>;
+
+// Matches model::MaterialUniform
+struct Material {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+    normal_scale: f32,
+    // 0 unless the material is masked
+    alpha_cutoff: f32,
+}
+@group(0) @binding(4)
+var<uniform> material: Material;
+
+const PI: f32 = 3.14159265;
+
+// Trowbridge-Reitz (GGX) normal distribution
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+// Height correlated Smith visibility, the geometry term with the 4 n.l n.v
+// of the denominator already divided out
+fn visibility_smith(n_dot_v: f32, n_dot_l: f32, alpha: f32) -> f32 {
+    let alpha2 = alpha * alpha;
+    let v = n_dot_l * sqrt(n_dot_v * n_dot_v * (1.0 - alpha2) + alpha2);
+    let l = n_dot_v * sqrt(n_dot_l * n_dot_l * (1.0 - alpha2) + alpha2);
+    return 0.5 / max(v + l, 0.0001);
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(1.0 - cos_theta, 5.0);
+}
+
+// The split sum's BRDF lookup as a curve fit (Karis, "Physically Based
+// Shading on Mobile"), saves a lookup texture
+fn env_brdf_approx(f0: vec3<f32>, roughness: f32, n_dot_v: f32) -> vec3<f32> {
+    let c0 = vec4<f32>(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = vec4<f32>(1.0, 0.0425, 1.04, -0.04);
+    let r = roughness * c0 + c1;
+    let a004 = min(r.x * r.x, exp2(-9.28 * n_dot_v)) * r.x + r.y;
+    let ab = vec2<f32>(-1.04, 1.04) * a004 + r.zw;
+    return f0 * ab.x + ab.y;
+}
+
+// Matches the order and constants in environment.rs
+fn environment_irradiance(n: vec3<f32>) -> vec3<f32> {
+    let sh = environment.sh;
+    var result = sh[0].rgb * 0.282095;
+    result += sh[1].rgb * 0.488603 * n.y;
+    result += sh[2].rgb * 0.488603 * n.z;
+    result += sh[3].rgb * 0.488603 * n.x;
+    result += sh[4].rgb * 1.092548 * n.x * n.y;
+    result += sh[5].rgb * 1.092548 * n.y * n.z;
+    result += sh[6].rgb * 0.315392 * (3.0 * n.z * n.z - 1.0);
+    result += sh[7].rgb * 1.092548 * n.x * n.z;
+    result += sh[8].rgb * 0.546274 * (n.x * n.x - n.y * n.y);
+    return max(result, vec3<f32>(0.0));
+}
+
+// How much of the light reaching `world_position` isn't blocked, from a
+// layer of the shadow maps rendered with `view_proj`
+fn shadow_factor(layer: i32, view_proj: mat4x4<f32>, world_position: vec3<f32>) -> f32 {
+    let position = view_proj * vec4<f32>(world_position, 1.0);
+    let ndc = position.xyz / position.w;
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    // Outside of the map nothing is known to block the light
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    // Percentage closer filtering, averages the comparisons around the texel
+    var lit = 0.0;
+    var samples = 0.0;
+    for (var y = -lights.pcf_radius; y <= lights.pcf_radius; y += 1) {
+        for (var x = -lights.pcf_radius; x <= lights.pcf_radius; x += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * lights.texel_size;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, layer, ndc.z);
+            samples += 1.0;
+        }
+    }
+    return lit / samples;
+}
+
+fn sun_shadow(world_position: vec3<f32>, view_depth: f32) -> f32 {
+    if (lights.sun_shadows == 0u) {
+        return 1.0;
+    }
+    for (var i = 0u; i < lights.cascade_count; i += 1u) {
+        if (view_depth < lights.cascade_splits[i]) {
+            return shadow_factor(i32(i), lights.cascade_view_proj[i], world_position);
+        }
+    }
+    // Past the shadow distance
+    return 1.0;
+}
+
+struct Surface {
+    normal: vec3<f32>,
+    view: vec3<f32>,
+    n_dot_v: f32,
+    diffuse_color: vec3<f32>,
+    f0: vec3<f32>,
+    alpha: f32,
+}
+
+// Cook-Torrance, light arriving from direction `l` with `radiance`
+fn direct_light(surface: Surface, l: vec3<f32>, radiance: vec3<f32>) -> vec3<f32> {
+    let h = normalize(surface.view + l);
+    let n_dot_l = max(dot(surface.normal, l), 0.0);
+    let n_dot_h = max(dot(surface.normal, h), 0.0);
+    let fresnel = fresnel_schlick(max(dot(surface.view, h), 0.0), surface.f0);
+    let specular = fresnel * distribution_ggx(n_dot_h, surface.alpha) * visibility_smith(surface.n_dot_v, n_dot_l, surface.alpha);
+    let diffuse = (1.0 - fresnel) * surface.diffuse_color / PI;
+    return (diffuse + specular) * radiance * n_dot_l;
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
-    
-    // We don't need (or want) much ambient light, so 0.1 is fine
-    let ambient_strength = 0.1;
-    let ambient_color = light.color * ambient_strength;
-    
-    // Create the lighting vectors
-    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
-    let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
-    let half_dir = normalize(view_dir + light_dir);
-
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
-
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
-
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-
-    return vec4<f32>(result, object_color.a);
-}
\ No newline at end of file
+    let base_color = textureSample(t_base_color, s_base_color, in.tex_coords) * material.base_color;
+    // Roughness in green and metallic in blue, like glTF
+    let metallic_roughness = textureSample(t_metallic_roughness, s_base_color, in.tex_coords);
+    let metallic = clamp(material.metallic * metallic_roughness.b, 0.0, 1.0);
+    let roughness = clamp(material.roughness * metallic_roughness.g, 0.045, 1.0);
+    let occlusion = 1.0 + material.occlusion_strength * (textureSample(t_occlusion, s_base_color, in.tex_coords).r - 1.0);
+    let emissive = textureSample(t_emissive, s_base_color, in.tex_coords).rgb * material.emissive;
+
+    // Normal map from tangent to world space
+    let tangent_normal = (textureSample(t_normal, s_normal, in.tex_coords).xyz * 2.0 - 1.0)
+        * vec3<f32>(material.normal_scale, material.normal_scale, 1.0);
+    let tangent_matrix = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let n = normalize(tangent_matrix * tangent_normal);
+    let v = normalize(camera.view_pos.xyz - in.world_position);
+    let n_dot_v = max(dot(n, v), 0.0001);
+
+    var surface: Surface;
+    surface.normal = n;
+    surface.view = v;
+    surface.n_dot_v = n_dot_v;
+    surface.alpha = roughness * roughness;
+    surface.diffuse_color = base_color.rgb * (1.0 - metallic);
+    // Dielectrics reflect about 4% head on, metals reflect their color
+    surface.f0 = mix(vec3<f32>(0.04), base_color.rgb, metallic);
+    let diffuse_color = surface.diffuse_color;
+    let f0 = surface.f0;
+
+    // The point light, without falloff or shadows
+    var direct = direct_light(surface, normalize(light.position - in.world_position), light.color);
+
+    // Shadows are looked up a little off the surface, against acne
+    let shadow_position = in.world_position + normalize(in.world_normal) * lights.normal_bias;
+    direct += direct_light(surface, -lights.sun_direction, lights.sun_color)
+        * sun_shadow(shadow_position, in.view_depth);
+
+    for (var i = 0u; i < lights.spot_count; i += 1u) {
+        let spot = lights.spots[i];
+        let to_light = spot.position - in.world_position;
+        let distance = length(to_light);
+        let l = to_light / distance;
+        // Inverse square falloff, brought to 0 at the range
+        let window = clamp(1.0 - pow(distance / spot.range, 4.0), 0.0, 1.0);
+        let falloff = window * window / max(distance * distance, 0.0001);
+        let cone = smoothstep(spot.cos_outer, spot.cos_inner, dot(-l, spot.direction));
+        var shadow = 1.0;
+        if (spot.shadow_layer >= 0) {
+            shadow = shadow_factor(spot.shadow_layer, spot.view_proj, shadow_position);
+        }
+        direct += direct_light(surface, l, spot.color * falloff * cone) * shadow;
+    }
+
+    // Image based light from the environment, rougher surfaces reflect
+    // blurrier mips
+    let r = reflect(-v, n);
+    let prefiltered = textureSampleLevel(t_environment, s_environment, r, roughness * (environment.mip_count - 1.0)).rgb;
+    let ambient_diffuse = environment_irradiance(n) * diffuse_color;
+    let ambient_specular = prefiltered * env_brdf_approx(f0, roughness, n_dot_v);
+    let ambient = (ambient_diffuse + ambient_specular) * environment.intensity * occlusion;
+
+    // Only after all texture reads, those need uniform control flow
+    if (base_color.a < material.alpha_cutoff) {
+        discard;
+    }
+    return vec4<f32>(direct + ambient + emissive, base_color.a);
+}
@@ -0,0 +1,600 @@
+use cgmath::prelude::*;
+use cgmath::{Deg, Matrix4, Point3, Vector3, Vector4};
+
+use crate::engine::{camera, model};
+use crate::engine::model::Vertex;
+use crate::engine::settings::ShadowSettings;
+
+/// Most shadow maps the sun's shadow is split into.
+pub const MAX_CASCADES: usize = 4;
+/// Spot lights past this many are ignored.
+pub const MAX_SPOT_LIGHTS: usize = 4;
+
+// One layer per cascade, then one per spot light
+const MAX_LAYERS: u32 = (MAX_CASCADES + MAX_SPOT_LIGHTS) as u32;
+// The GL backend makes single layer textures plain 2D ones, which can't be
+// bound as an array
+const MIN_LAYERS: u32 = 2;
+const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Light from far away that only has a direction, like the sun. Only the
+/// first entity with one lights the scene.
+#[derive(Debug, Clone, Copy)]
+pub struct DirectionalLight {
+    /// The way the light travels, doesn't need to be normalized
+    pub direction: Vector3<f32>,
+    /// Linear RGB, brighter than 1 for strong lights
+    pub color: [f32; 3],
+    pub cast_shadows: bool,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vector3<f32>, color: [f32; 3]) -> Self {
+        Self { direction, color, cast_shadows: true }
+    }
+}
+
+/// Light shining from a point into a cone, like a street lamp.
+#[derive(Debug, Clone, Copy)]
+pub struct SpotLight {
+    pub position: Vector3<f32>,
+    /// Where the cone points, doesn't need to be normalized
+    pub direction: Vector3<f32>,
+    /// Linear RGB, brighter than 1 for strong lights
+    pub color: [f32; 3],
+    /// Nothing further away than this is lit
+    pub range: f32,
+    /// Half of the cone's opening angle, the light fades out between
+    /// `inner_angle` and this
+    pub outer_angle: Deg<f32>,
+    pub inner_angle: Deg<f32>,
+    pub cast_shadows: bool,
+}
+
+impl SpotLight {
+    pub fn new(position: Vector3<f32>, direction: Vector3<f32>, color: [f32; 3], range: f32, outer_angle: Deg<f32>) -> Self {
+        Self {
+            position,
+            direction,
+            color,
+            range,
+            outer_angle,
+            inner_angle: outer_angle * 0.8,
+            cast_shadows: true,
+        }
+    }
+}
+
+/// Whether the models of an entity cast and receive shadows, both do for
+/// entities without this component. Models shared through the asset server
+/// use the flags of the first entity using them, with a warning if the
+/// others differ. Give an entity its own `Model`, e.g. from
+/// `World::load_model`, for flags of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shadows {
+    pub cast: bool,
+    pub receive: bool,
+}
+
+impl Default for Shadows {
+    fn default() -> Self {
+        Self { cast: true, receive: true }
+    }
+}
+
+// Matches SpotLight in shader.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpotLightUniform {
+    position: [f32; 3],
+    range: f32,
+    direction: [f32; 3],
+    cos_outer: f32,
+    color: [f32; 3],
+    cos_inner: f32,
+    view_proj: [[f32; 4]; 4],
+    // Shadow map layer, -1 without a shadow
+    shadow_layer: i32,
+    _padding: [i32; 3],
+}
+
+/// The sun, spot lights and the matrices of their shadow maps, bound next
+/// to the point light.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct LightsUniform {
+    sun_direction: [f32; 3],
+    sun_shadows: u32,
+    sun_color: [f32; 3],
+    cascade_count: u32,
+    cascade_view_proj: [[[f32; 4]; 4]; MAX_CASCADES],
+    // How far from the camera each cascade reaches
+    cascade_splits: [f32; MAX_CASCADES],
+    spots: [SpotLightUniform; MAX_SPOT_LIGHTS],
+    spot_count: u32,
+    pcf_radius: i32,
+    texel_size: f32,
+    normal_bias: f32,
+}
+
+impl LightsUniform {
+    pub(crate) fn new(
+        sun: Option<&DirectionalLight>,
+        spots: &[&SpotLight],
+        camera: &camera::Camera,
+        projection: &camera::Projection,
+        settings: &ShadowSettings,
+    ) -> Self {
+        let mut uniform: Self = bytemuck::Zeroable::zeroed();
+        uniform.pcf_radius = settings.pcf_radius as i32;
+        uniform.texel_size = 1.0 / settings.map_size as f32;
+        uniform.normal_bias = settings.normal_bias;
+
+        if let Some(sun) = sun {
+            let direction = sun.direction.normalize();
+            uniform.sun_direction = direction.into();
+            uniform.sun_color = sun.color;
+            if sun.cast_shadows {
+                uniform.sun_shadows = 1;
+                uniform.cascade_count = settings.cascades.clamp(1, MAX_CASCADES as u32);
+                let splits = cascade_splits(projection.znear(), settings.distance, uniform.cascade_count as usize);
+                let mut near = projection.znear();
+                for (i, far) in splits.into_iter().enumerate() {
+                    let frustum = projection.calc_matrix_between(near, far) * camera.calc_matrix();
+                    uniform.cascade_view_proj[i] = cascade_matrix(frustum, direction, settings).into();
+                    uniform.cascade_splits[i] = far;
+                    near = far;
+                }
+            }
+        }
+
+        // Spot lights with shadows take the layers after the cascades
+        let mut next_layer = uniform.cascade_count as i32;
+        for (i, spot) in spots.iter().take(MAX_SPOT_LIGHTS).enumerate() {
+            let shadow_layer = if spot.cast_shadows {
+                next_layer += 1;
+                next_layer - 1
+            } else {
+                -1
+            };
+            let direction = spot.direction.normalize();
+            let view = Matrix4::look_to_rh(Point3::from_vec(spot.position), direction, up_for(direction));
+            let fovy = Deg((spot.outer_angle.0 * 2.0).min(170.0));
+            let projection = camera::OPENGL_TO_WGPU_MATRIX * cgmath::perspective(fovy, 1.0, 0.05, spot.range);
+            uniform.spots[i] = SpotLightUniform {
+                position: spot.position.into(),
+                range: spot.range,
+                direction: direction.into(),
+                cos_outer: spot.outer_angle.cos(),
+                color: spot.color,
+                cos_inner: Deg(spot.inner_angle.0.min(spot.outer_angle.0)).cos(),
+                view_proj: (projection * view).into(),
+                shadow_layer,
+                _padding: [0; 3],
+            };
+        }
+        uniform.spot_count = spots.len().min(MAX_SPOT_LIGHTS) as u32;
+        uniform
+    }
+
+    /// The same lights for models that don't receive shadows.
+    pub(crate) fn without_shadows(&self) -> Self {
+        let mut uniform = *self;
+        uniform.sun_shadows = 0;
+        for spot in &mut uniform.spots {
+            spot.shadow_layer = -1;
+        }
+        uniform
+    }
+
+    /// How many shadow map layers these lights use.
+    pub(crate) fn layer_count(&self) -> u32 {
+        let spot_layers = self.spots[..self.spot_count as usize].iter().filter(|spot| spot.shadow_layer >= 0).count();
+        self.cascade_count + spot_layers as u32
+    }
+
+    /// Shadow map layers to render and the light's matrix for each.
+    fn shadow_layers(&self) -> Vec<(u32, [[f32; 4]; 4])> {
+        let mut layers = Vec::new();
+        if self.sun_shadows != 0 {
+            for i in 0..self.cascade_count as usize {
+                layers.push((i as u32, self.cascade_view_proj[i]));
+            }
+        }
+        for spot in &self.spots[..self.spot_count as usize] {
+            if spot.shadow_layer >= 0 {
+                layers.push((spot.shadow_layer as u32, spot.view_proj));
+            }
+        }
+        layers
+    }
+}
+
+/// Where each cascade ends. Mostly logarithmic so the close cascades stay
+/// small, with a bit of even steps mixed in so the first one isn't tiny.
+fn cascade_splits(near: f32, far: f32, count: usize) -> Vec<f32> {
+    const LAMBDA: f32 = 0.9;
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let uniform = near + (far - near) * t;
+            LAMBDA * logarithmic + (1.0 - LAMBDA) * uniform
+        })
+        .collect()
+}
+
+/// An orthographic light matrix enclosing the part of the camera frustum
+/// `frustum` projects, sized by its bounding sphere and snapped to whole
+/// texels so shadow edges don't shimmer when the camera moves or turns.
+fn cascade_matrix(frustum: Matrix4<f32>, direction: Vector3<f32>, settings: &ShadowSettings) -> Matrix4<f32> {
+    let inverse = frustum.invert().unwrap_or_else(Matrix4::identity);
+    let mut corners = Vec::with_capacity(8);
+    for x in [-1.0, 1.0] {
+        for y in [-1.0, 1.0] {
+            for z in [0.0, 1.0] {
+                let corner = inverse * Vector4::new(x, y, z, 1.0);
+                corners.push(corner.truncate() / corner.w);
+            }
+        }
+    }
+    let center = corners.iter().fold(Vector3::zero(), |sum, corner| sum + corner) / corners.len() as f32;
+    let radius = corners.iter().map(|corner| (corner - center).magnitude()).fold(0.0, f32::max);
+    // Round the size up as well, otherwise it changes a little every frame
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let view = Matrix4::look_to_rh(Point3::origin(), direction, up_for(direction));
+    let center = (view * center.extend(1.0)).truncate();
+    let texel = 2.0 * radius / settings.map_size as f32;
+    let (x, y) = ((center.x / texel).floor() * texel, (center.y / texel).floor() * texel);
+    // Buildings behind the camera still throw shadows into view, so reach
+    // back as far as the shadow distance
+    let near = -center.z - radius - settings.distance;
+    let far = -center.z + radius;
+    camera::OPENGL_TO_WGPU_MATRIX * cgmath::ortho(x - radius, x + radius, y - radius, y + radius, near, far) * view
+}
+
+fn up_for(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() }
+}
+
+/// A mesh drawn into the shadow maps.
+pub(crate) struct ShadowCaster<'a> {
+    pub model: &'a model::Model,
+    pub mesh: &'a model::Mesh,
+    pub joints: &'a wgpu::BindGroup,
+    /// The material's bind group for `AlphaMode::Mask` materials, whose
+    /// cut out parts don't cast a shadow
+    pub masked: Option<&'a wgpu::BindGroup>,
+}
+
+/// A depth texture array with a layer per cascade and spot light casting
+/// shadows, and the depth only pipelines rendering them. Each layer takes
+/// 4 bytes per texel, 16 MB at the default size, so the array only has as
+/// many as the lights use, see `fit`.
+pub(crate) struct ShadowMaps {
+    pub size: u32,
+    layers: u32,
+    pub view: wgpu::TextureView,
+    layer_views: Vec<wgpu::TextureView>,
+    /// Compares against the stored depth, with linear filtering blending
+    /// four texels for free
+    pub sampler: wgpu::Sampler,
+    matrix_buffer: wgpu::Buffer,
+    matrix_stride: u32,
+    matrix_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    // Same with a fragment stage discarding below the alpha cutoff
+    masked_pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowMaps {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        size: u32,
+        joint_bind_group_layout: &wgpu::BindGroupLayout,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let (view, layer_views) = Self::create_maps(device, size, MIN_LAYERS);
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        // Every layer's matrix in one buffer, picked with a dynamic offset
+        let matrix_stride = device
+            .limits()
+            .min_uniform_buffer_offset_alignment
+            .max(std::mem::size_of::<[[f32; 4]; 4]>() as u32);
+        let matrix_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Matrix Buffer"),
+            size: (matrix_stride * MAX_LAYERS) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let matrix_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<[[f32; 4]; 4]>() as u64),
+                },
+                count: None,
+            }],
+            label: Some("shadow_matrix_bind_group_layout"),
+        });
+        let matrix_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &matrix_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &matrix_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<[[f32; 4]; 4]>() as u64),
+                }),
+            }],
+            label: Some("shadow_matrix_bind_group"),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Shadow Pipeline Layout"),
+            bind_group_layouts: &[&matrix_bind_group_layout, joint_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let masked_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Masked Shadow Pipeline Layout"),
+            bind_group_layouts: &[&matrix_bind_group_layout, joint_bind_group_layout, texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shadow.wgsl"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+        });
+        let pipeline = create_pipeline(device, "Shadow Pipeline", &layout, &shader, "vs_main", None);
+        let masked_pipeline = create_pipeline(
+            device,
+            "Masked Shadow Pipeline",
+            &masked_layout,
+            &shader,
+            "vs_masked",
+            Some("fs_masked"),
+        );
+
+        Self {
+            size,
+            layers: MIN_LAYERS,
+            view,
+            layer_views,
+            sampler,
+            matrix_buffer,
+            matrix_stride,
+            matrix_bind_group,
+            pipeline,
+            masked_pipeline,
+        }
+    }
+
+    fn create_maps(device: &wgpu::Device, size: u32, layers: u32) -> (wgpu::TextureView, Vec<wgpu::TextureView>) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow_maps"),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let layer_views = (0..layers)
+            .map(|layer| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: layer,
+                    array_layer_count: std::num::NonZeroU32::new(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        (view, layer_views)
+    }
+
+    /// Recreates the maps at a new size. Bind groups using `view` have to
+    /// be created again.
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, size: u32) {
+        (self.view, self.layer_views) = Self::create_maps(device, size, self.layers);
+        self.size = size;
+    }
+
+    /// Recreates the maps if `lights` needs another number of layers, e.g.
+    /// after a spot light was added or the cascade count changed. Returns
+    /// whether it did, bind groups using `view` have to be created again
+    /// then.
+    pub(crate) fn fit(&mut self, device: &wgpu::Device, lights: &LightsUniform) -> bool {
+        let layers = lights.layer_count().max(MIN_LAYERS);
+        if layers == self.layers {
+            return false;
+        }
+        (self.view, self.layer_views) = Self::create_maps(device, self.size, layers);
+        self.layers = layers;
+        true
+    }
+
+    /// Renders `casters` into the layers `lights` has shadows in.
+    pub(crate) fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        queue: &wgpu::Queue,
+        lights: &LightsUniform,
+        casters: &[ShadowCaster],
+    ) {
+        for (layer, view_proj) in lights.shadow_layers() {
+            let offset = layer * self.matrix_stride;
+            queue.write_buffer(&self.matrix_buffer, offset as wgpu::BufferAddress, bytemuck::cast_slice(&view_proj));
+
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.layer_views[layer as usize],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_bind_group(0, &self.matrix_bind_group, &[offset]);
+            let mut masked = None;
+            for caster in casters {
+                if masked != Some(caster.masked.is_some()) {
+                    masked = Some(caster.masked.is_some());
+                    render_pass.set_pipeline(if caster.masked.is_some() { &self.masked_pipeline } else { &self.pipeline });
+                }
+                if let Some(material) = caster.masked {
+                    render_pass.set_bind_group(2, material, &[]);
+                }
+                render_pass.set_bind_group(1, caster.joints, &[]);
+                render_pass.set_vertex_buffer(0, caster.mesh.vertex_buffer.slice(..));
+                render_pass.set_vertex_buffer(1, caster.model.instance_buffer.slice(..));
+                render_pass.set_index_buffer(caster.mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..caster.mesh.num_elements, 0, 0..caster.model.instances.len() as u32);
+            }
+        }
+    }
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    label: &str,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    vertex_entry: &str,
+    fragment_entry: Option<&str>,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: vertex_entry,
+            buffers: &[model::ModelVertex::desc(), model::InstanceRaw::desc()],
+        },
+        fragment: fragment_entry.map(|entry_point| wgpu::FragmentState {
+            module: shader,
+            entry_point,
+            targets: &[],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            // Open meshes and double sided materials cast from both sides
+            cull_mode: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: SHADOW_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            // Against shadow acne, grows with the surface's slope
+            bias: wgpu::DepthBiasState {
+                constant: 2,
+                slope_scale: 2.0,
+                clamp: 0.0,
+            },
+        }),
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view() -> (camera::Camera, camera::Projection) {
+        (
+            camera::Camera::new((3.0, 5.0, 10.0), Deg(-110.0), Deg(-20.0)),
+            camera::Projection::new(1280, 720, Deg(45.0), 0.1, 100.0),
+        )
+    }
+
+    #[test]
+    fn cascade_splits_grow_to_the_far_plane() {
+        for count in 1..=MAX_CASCADES {
+            let splits = cascade_splits(0.1, 200.0, count);
+            assert_eq!(splits.len(), count);
+            assert!(splits[0] > 0.1, "{:?}", splits);
+            assert!(splits.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", splits);
+            assert!((splits[count - 1] - 200.0).abs() < 1e-3, "{:?}", splits);
+        }
+    }
+
+    #[test]
+    fn cascades_contain_their_part_of_the_frustum() {
+        let (camera, projection) = view();
+        let settings = ShadowSettings::default();
+        let splits = cascade_splits(projection.znear(), settings.distance, MAX_CASCADES);
+        for direction in [Vector3::new(-0.3, -1.0, -0.5), Vector3::new(1.0, -0.2, 0.0), -Vector3::unit_y()] {
+            let direction = direction.normalize();
+            let mut near = projection.znear();
+            for far in splits.iter().copied() {
+                let frustum = projection.calc_matrix_between(near, far) * camera.calc_matrix();
+                let light = cascade_matrix(frustum, direction, &settings);
+                let inverse = frustum.invert().unwrap();
+                for corner in [-1.0, 1.0].into_iter().flat_map(|x| {
+                    [-1.0, 1.0].into_iter().flat_map(move |y| [0.0, 1.0].map(|z| Vector4::new(x, y, z, 1.0)))
+                }) {
+                    let world = inverse * corner;
+                    let clip = light * (world / world.w);
+                    let inside = clip.x.abs() <= 1.0 && clip.y.abs() <= 1.0 && (0.0..=1.0).contains(&clip.z);
+                    assert!(inside, "{:?} from {} to {}: {:?}", direction, near, far, clip);
+                }
+                near = far;
+            }
+        }
+    }
+
+    #[test]
+    fn spot_lights_take_the_layers_after_the_cascades() {
+        let (camera, projection) = view();
+        let settings = ShadowSettings { cascades: 3, ..Default::default() };
+        let sun = DirectionalLight::new(Vector3::new(0.0, -1.0, -1.0), [1.0; 3]);
+        let lamp = SpotLight::new(Vector3::unit_y(), -Vector3::unit_y(), [1.0; 3], 10.0, Deg(30.0));
+        let unshadowed = SpotLight { cast_shadows: false, ..lamp };
+        let spots = [&lamp, &unshadowed, &lamp];
+
+        let lights = LightsUniform::new(Some(&sun), &spots, &camera, &projection, &settings);
+        let layers: Vec<_> = lights.spots[..3].iter().map(|spot| spot.shadow_layer).collect();
+        assert_eq!(layers, [3, -1, 4]);
+        assert_eq!(lights.layer_count(), 5);
+        let rendered: Vec<_> = lights.shadow_layers().into_iter().map(|(layer, _)| layer).collect();
+        assert_eq!(rendered, [0, 1, 2, 3, 4]);
+
+        let shadowless_sun = DirectionalLight { cast_shadows: false, ..sun };
+        let lights = LightsUniform::new(Some(&shadowless_sun), &spots, &camera, &projection, &settings);
+        assert_eq!(lights.spots[2].shadow_layer, 1);
+        assert_eq!(lights.layer_count(), 2);
+        assert_eq!(LightsUniform::new(None, &[], &camera, &projection, &settings).layer_count(), 0);
+    }
+}
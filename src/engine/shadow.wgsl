@@ -0,0 +1,101 @@
+// Depth only, renders shadow casters from a light's point of view. Masked
+// materials go through vs_masked and fs_masked to cut out their holes.
+
+@group(0) @binding(0)
+var<uniform> light_view_proj: mat4x4<f32>;
+
+// Matches animation::MAX_JOINTS
+struct Skin {
+    joints: array<mat4x4<f32>, 128>,
+}
+@group(1) @binding(0)
+var<uniform> skin: Skin;
+
+// Only bound for masked materials, matches the start of shader.wgsl's
+@group(2) @binding(0)
+var t_base_color: texture_2d<f32>;
+@group(2) @binding(1)
+var s_base_color: sampler;
+
+// Matches model::MaterialUniform
+struct Material {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+    normal_scale: f32,
+    alpha_cutoff: f32,
+}
+@group(2) @binding(4)
+var<uniform> material: Material;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
+}
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+}
+
+fn light_position(model: VertexInput, instance: InstanceInput) -> vec4<f32> {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    // Same skinning as shader.wgsl
+    var skin_matrix = mat4x4<f32>(
+        vec4<f32>(1.0, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, 1.0, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, 1.0, 0.0),
+        vec4<f32>(0.0, 0.0, 0.0, 1.0),
+    );
+    if (dot(model.weights, vec4<f32>(1.0)) > 0.0) {
+        skin_matrix = skin.joints[model.joints.x] * model.weights.x
+            + skin.joints[model.joints.y] * model.weights.y
+            + skin.joints[model.joints.z] * model.weights.z
+            + skin.joints[model.joints.w] * model.weights.w;
+    }
+
+    return light_view_proj * model_matrix * skin_matrix * vec4<f32>(model.position, 1.0);
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> @builtin(position) vec4<f32> {
+    return light_position(model, instance);
+}
+
+struct MaskedOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_masked(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> MaskedOutput {
+    var out: MaskedOutput;
+    out.clip_position = light_position(model, instance);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+@fragment
+fn fs_masked(in: MaskedOutput) {
+    let alpha = textureSample(t_base_color, s_base_color, in.tex_coords).a * material.base_color.a;
+    if (alpha < material.alpha_cutoff) {
+        discard;
+    }
+}
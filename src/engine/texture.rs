@@ -1,10 +1,55 @@
 use image::GenericImageView;
 use anyhow::*;
 
+use std::sync::Arc;
+
+use crate::engine::{mipmap, sampler};
+use crate::engine::sampler::SamplerSettings;
+use crate::engine::gpu_image::{GpuImage, ImageData};
+
+/// How textures are filtered when they're drawn smaller than they are,
+/// see `SamplerSettings::with_filtering`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filtering {
+    /// Smooth within a mip level, but visibly switches between levels
+    Bilinear,
+    /// Also blends between mip levels
+    #[default]
+    Trilinear,
+    /// Trilinear with up to this many samples (2 to 16) along the view
+    /// direction, keeps surfaces seen at a grazing angle sharp
+    Anisotropic(u8),
+}
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
-    pub sampler: wgpu::Sampler,
+    /// Shared with every other texture using the same `SamplerSettings`
+    pub sampler: Arc<wgpu::Sampler>,
+    /// What the view has to be bound as, e.g. `Cube` for cubemaps
+    pub view_dimension: wgpu::TextureViewDimension,
+}
+
+/// How a texture made from an image is uploaded and sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TextureSettings {
+    pub sampler: SamplerSettings,
+    /// Generate a full mip chain, only worth turning off for textures that
+    /// are never drawn smaller than they are, like UI
+    pub mipmaps: bool,
+    /// Colors are stored as sRGB, data like roughness or occlusion isn't.
+    /// Normal maps are always linear.
+    pub srgb: bool,
+}
+
+impl Default for TextureSettings {
+    fn default() -> Self {
+        Self {
+            sampler: SamplerSettings::default(),
+            mipmaps: true,
+            srgb: true,
+        }
+    }
 }
 
 impl Texture {
@@ -26,62 +71,188 @@ impl Texture {
         label: Option<&str>,
         is_normal_map: bool,
     ) -> Result<Self> {
-        let format = if is_normal_map {
-            wgpu::TextureFormat::Rgba8Unorm
+        Self::from_image_with_settings(device, queue, img, label, is_normal_map, &TextureSettings::default())
+    }
+
+    /// Uploads the image, along with a mip chain generated on the CPU unless
+    /// `settings.mipmaps` is off. Float images (HDR, EXR) are uploaded as
+    /// `Rgba16Float`.
+    pub fn from_image_with_settings(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        is_normal_map: bool,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        let (format, levels) = image_levels(img, is_normal_map, settings);
+        Ok(RawTexture::d2(format, img.dimensions(), levels).upload(device, queue, label, settings))
+    }
+
+    /// Uploads a KTX2 or DDS image as is if the device supports its format,
+    /// otherwise it's decompressed to RGBA8 first.
+    pub fn from_gpu_image(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &GpuImage,
+        label: Option<&str>,
+        is_normal_map: bool,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        if img.is_supported(device) {
+            let levels = if settings.mipmaps { &img.levels[..] } else { &img.levels[..1] };
+            let raw = RawTexture::d2(img.format, (img.width, img.height), levels.to_vec());
+            return Ok(raw.upload(device, queue, label, settings));
+        }
+
+        log::warn!(
+            "{} uses {:?} which the device doesn't support, decompressing it on the CPU",
+            label.unwrap_or("texture"),
+            img.format,
+        );
+        let mut mips = img.decompress(label.unwrap_or("texture"))?;
+        let srgb = img.format.describe().srgb;
+        let (format, levels) = if mips.len() == 1 {
+            // Mipmapped like any other image
+            let settings = TextureSettings { srgb, ..*settings };
+            image_levels(&image::DynamicImage::ImageRgba8(mips.remove(0)), is_normal_map, &settings)
         } else {
-            wgpu::TextureFormat::Rgba8UnormSrgb
+            if !settings.mipmaps {
+                mips.truncate(1);
+            }
+            let format = if srgb {
+                wgpu::TextureFormat::Rgba8UnormSrgb
+            } else {
+                wgpu::TextureFormat::Rgba8Unorm
+            };
+            (format, mips.into_iter().map(|mip| mip.into_raw()).collect())
         };
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        Ok(RawTexture::d2(format, (img.width, img.height), levels).upload(device, queue, label, settings))
+    }
 
-        let size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            depth_or_array_layers: 1,
+    pub fn from_image_data(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &ImageData,
+        label: Option<&str>,
+        is_normal_map: bool,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        match img {
+            ImageData::Decoded(img) => Self::from_image_with_settings(device, queue, img, label, is_normal_map, settings),
+            ImageData::Gpu(img) => Self::from_gpu_image(device, queue, img, label, is_normal_map, settings),
+        }
+    }
+
+    /// A 2D array with one layer per image, e.g. a set of facade variations
+    /// picked by index in the shader. Every image has to be the same size.
+    pub fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: &[image::DynamicImage],
+        label: Option<&str>,
+        is_normal_map: bool,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        let raw = RawTexture::layered(layers, wgpu::TextureViewDimension::D2Array, is_normal_map, settings)?;
+        Ok(raw.upload(device, queue, label, settings))
+    }
+
+    /// A cubemap from six square faces in the order +X, -X, +Y, -Y, +Z, -Z.
+    pub fn from_cube_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage; 6],
+        label: Option<&str>,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        let (width, height) = faces[0].dimensions();
+        if width != height {
+            bail!("cubemap faces have to be square, got {}x{}", width, height);
+        }
+        let raw = RawTexture::layered(faces, wgpu::TextureViewDimension::Cube, false, settings)?;
+        Ok(raw.upload(device, queue, label, settings))
+    }
+
+    /// A cubemap with `face_size` pixel faces projected from an
+    /// equirectangular (latitude/longitude) panorama, the usual layout for
+    /// HDR environment maps.
+    pub fn from_equirectangular(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        face_size: u32,
+        label: Option<&str>,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        let faces = equirectangular_to_cube_faces(img, face_size);
+        Self::from_cube_faces(device, queue, &faces, label, settings)
+    }
+
+    /// A 3D texture from tightly packed texels, slice after slice. Not
+    /// mipmapped, it's meant for lookup tables.
+    pub fn from_volume(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        data: &[u8],
+        (width, height, depth): (u32, u32, u32),
+        format: wgpu::TextureFormat,
+        label: Option<&str>,
+        settings: &TextureSettings,
+    ) -> Result<Self> {
+        let expected = (width * height * depth) as usize * format.describe().block_size as usize;
+        if data.len() != expected {
+            bail!("{}x{}x{} {:?} volume needs {} bytes, got {}", width, height, depth, format, expected, data.len());
+        }
+        let raw = RawTexture {
+            format,
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: depth },
+            view_dimension: wgpu::TextureViewDimension::D3,
+            layers: vec![vec![data.to_vec()]],
         };
-        let texture = device.create_texture(
-            &wgpu::TextureDescriptor {
-                label,
-                size,
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-                view_formats: &[],
-            }
-        );
+        Ok(raw.upload(device, queue, label, settings))
+    }
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-                rows_per_image: std::num::NonZeroU32::new(dimensions.1),
-            },
-            size,
-        );
+    /// A color grading LUT from the common strip layout: `size` slices of
+    /// `size` x `size` side by side, blue increasing from left to right.
+    /// Sampled with clamped, linear filtering.
+    pub fn from_lut_image(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let (data, size) = lut_volume(img)?;
+        let settings = TextureSettings {
+            sampler: SamplerSettings::default().with_address_mode(wgpu::AddressMode::ClampToEdge),
+            mipmaps: false,
+            ..Default::default()
+        };
+        // Stays Unorm, the grading shader looks it up with sRGB colors
+        Self::from_volume(device, queue, &data, (size, size, size), wgpu::TextureFormat::Rgba8Unorm, label, &settings)
+    }
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            }
-        );
-        
-        Ok(Self { texture, view, sampler })
+    /// Binding type for a bind group layout entry matching this texture's
+    /// format and view dimension.
+    pub fn binding_type(&self) -> wgpu::BindingType {
+        wgpu::BindingType::Texture {
+            multisampled: self.texture.sample_count() > 1,
+            view_dimension: self.view_dimension,
+            sample_type: self.texture.format().describe().sample_type,
+        }
+    }
+
+    /// A 1x1 texture of a single color, used in place of textures a material
+    /// doesn't have.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
 
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
@@ -121,48 +292,374 @@ impl Texture {
             }
         );
 
-        Self { texture, view, sampler }
+        Self { texture, view, sampler: Arc::new(sampler), view_dimension: wgpu::TextureViewDimension::D2 }
     }
 
     pub fn create_depth_texture_non_comparison_sampler(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
         label: &str,
+    ) -> Self {
+        Self::create_depth_target(device, config.width, config.height, label)
+    }
+
+    /// Same as `create_depth_texture_non_comparison_sampler`, but sized explicitly
+    /// so it can back render targets that don't match the surface.
+    pub fn create_depth_target(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        label: &str,
+    ) -> Self {
+        Self::create_target(
+            device,
+            width,
+            height,
+            Self::DEPTH_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            1,
+            label,
+        )
+    }
+
+    /// Creates a color texture that can be rendered into and copied back to the CPU.
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        label: &str,
+    ) -> Self {
+        Self::create_target(
+            device,
+            width,
+            height,
+            format,
+            wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC,
+            1,
+            label,
+        )
+    }
+
+    /// Creates a 2D attachment texture. Depth formats get a non-comparison
+    /// nearest sampler, color formats a linear one.
+    pub fn create_target(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsages,
+        sample_count: u32,
+        label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
+            width,
+            height,
             depth_or_array_layers: 1,
         };
-        let desc = wgpu::TextureDescriptor {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
-            view_formats: &[Self::DEPTH_FORMAT],
-        };
-        let texture = device.create_texture(&desc);
+            format,
+            usage,
+            view_formats: &[],
+        });
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let filter = match format.describe().sample_type {
+            wgpu::TextureSampleType::Depth => wgpu::FilterMode::Nearest,
+            _ => wgpu::FilterMode::Linear,
+        };
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
+            mag_filter: filter,
+            min_filter: filter,
             mipmap_filter: wgpu::FilterMode::Nearest,
-            compare: None,
             lod_min_clamp: 0.0,
             lod_max_clamp: 100.0,
             ..Default::default()
         });
 
+        Self { texture, view, sampler: Arc::new(sampler), view_dimension: wgpu::TextureViewDimension::D2 }
+    }
+}
+
+/// The format and tightly packed levels an image is uploaded with.
+fn image_levels(img: &image::DynamicImage, is_normal_map: bool, settings: &TextureSettings) -> (wgpu::TextureFormat, Vec<Vec<u8>>) {
+    match img.color() {
+        image::ColorType::Rgb32F | image::ColorType::Rgba32F => {
+            let img = img.to_rgba32f();
+            let mips = if settings.mipmaps {
+                mipmap::generate_float_mipmaps(&img)
+            } else {
+                vec![img]
+            };
+            let levels = mips
+                .iter()
+                .map(|mip| mip.iter().flat_map(|&value| half::f16::from_f32(value).to_le_bytes()).collect())
+                .collect();
+            (wgpu::TextureFormat::Rgba16Float, levels)
+        }
+        _ => {
+            let linear = is_normal_map || !settings.srgb;
+            let format = if linear {
+                wgpu::TextureFormat::Rgba8Unorm
+            } else {
+                wgpu::TextureFormat::Rgba8UnormSrgb
+            };
+            let mips = if !settings.mipmaps {
+                vec![img.to_rgba8()]
+            } else if linear && !is_normal_map {
+                // Plain averages, the values aren't sRGB encoded
+                mipmap::generate_float_mipmaps(&img.to_rgba32f())
+                    .into_iter()
+                    .map(|mip| image::DynamicImage::ImageRgba32F(mip).to_rgba8())
+                    .collect()
+            } else {
+                mipmap::generate_mipmaps(&img.to_rgba8(), is_normal_map)
+            };
+            (format, mips.into_iter().map(|mip| mip.into_raw()).collect())
+        }
+    }
+}
+
+/// Texture data ready to be uploaded, every level tightly packed and
+/// compressed ones in whole blocks.
+struct RawTexture {
+    format: wgpu::TextureFormat,
+    size: wgpu::Extent3d,
+    view_dimension: wgpu::TextureViewDimension,
+    /// Mip levels per array layer, a 3D texture has one layer holding every
+    /// slice
+    layers: Vec<Vec<Vec<u8>>>,
+}
+
+impl RawTexture {
+    fn d2(format: wgpu::TextureFormat, (width, height): (u32, u32), levels: Vec<Vec<u8>>) -> Self {
         Self {
-            texture,
-            view,
-            sampler,
+            format,
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            layers: vec![levels],
+        }
+    }
+
+    fn layered(images: &[image::DynamicImage], view_dimension: wgpu::TextureViewDimension, is_normal_map: bool, settings: &TextureSettings) -> Result<Self> {
+        let Some(first) = images.first() else {
+            bail!("texture needs at least one layer");
+        };
+        let (width, height) = first.dimensions();
+        let mut format = None;
+        let mut layers = Vec::new();
+        for (i, img) in images.iter().enumerate() {
+            if img.dimensions() != (width, height) {
+                bail!("layer {} is {}x{}, the first one is {}x{}", i, img.width(), img.height(), width, height);
+            }
+            let (layer_format, levels) = image_levels(img, is_normal_map, settings);
+            if *format.get_or_insert(layer_format) != layer_format {
+                bail!("layer {} is {:?}, the first one is {:?}", i, layer_format, format.unwrap());
+            }
+            layers.push(levels);
+        }
+        Ok(Self {
+            format: format.unwrap(),
+            size: wgpu::Extent3d { width, height, depth_or_array_layers: images.len() as u32 },
+            view_dimension,
+            layers,
+        })
+    }
+
+    fn upload(self, device: &wgpu::Device, queue: &wgpu::Queue, label: Option<&str>, settings: &TextureSettings) -> Texture {
+        let dimension = match self.view_dimension {
+            wgpu::TextureViewDimension::D1 => wgpu::TextureDimension::D1,
+            wgpu::TextureViewDimension::D3 => wgpu::TextureDimension::D3,
+            _ => wgpu::TextureDimension::D2,
+        };
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label,
+                size: self.size,
+                mip_level_count: self.layers[0].len() as u32,
+                sample_count: 1,
+                dimension,
+                format: self.format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            }
+        );
+
+        let info = self.format.describe();
+        let (block_width, block_height) = (info.block_dimensions.0 as u32, info.block_dimensions.1 as u32);
+        for (layer, levels) in self.layers.iter().enumerate() {
+            for (level, data) in levels.iter().enumerate() {
+                let mip_size = self.size.mip_level_size(level as u32, dimension);
+                // The physical size, a 2x2 level of a BC texture is still
+                // written as a whole 4x4 block. Array layers go one at a time.
+                let mut copy_size = mip_size.physical_size(self.format);
+                if dimension != wgpu::TextureDimension::D3 {
+                    copy_size.depth_or_array_layers = 1;
+                }
+                queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        aspect: wgpu::TextureAspect::All,
+                        texture: &texture,
+                        mip_level: level as u32,
+                        origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                    },
+                    data,
+                    wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: std::num::NonZeroU32::new(mip_size.width.div_ceil(block_width) * info.block_size as u32),
+                        rows_per_image: std::num::NonZeroU32::new(mip_size.height.div_ceil(block_height)),
+                    },
+                    copy_size,
+                );
+            }
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(self.view_dimension),
+            ..Default::default()
+        });
+        let sampler = sampler::cached(device, &settings.sampler);
+
+        Texture { texture, view, sampler, view_dimension: self.view_dimension }
+    }
+}
+
+/// Reorders a LUT strip into volume texture data, one slice after the
+/// other. Returns the data and the LUT's size.
+fn lut_volume(img: &image::DynamicImage) -> Result<(Vec<u8>, u32)> {
+    let (width, size) = img.dimensions();
+    if width != size * size {
+        bail!("LUT strip has to be size * size wide and size high, got {}x{}", width, size);
+    }
+    let img = img.to_rgba8();
+    let mut data = Vec::with_capacity(img.as_raw().len());
+    for slice in 0..size {
+        for y in 0..size {
+            for x in 0..size {
+                data.extend_from_slice(&img.get_pixel(slice * size + x, y).0);
+            }
+        }
+    }
+    Ok((data, size))
+}
+
+/// Projects an equirectangular panorama onto the six faces of a cube, in
+/// the order `Texture::from_cube_faces` takes them. Float panoramas stay
+/// float.
+pub fn equirectangular_to_cube_faces(img: &image::DynamicImage, face_size: u32) -> [image::DynamicImage; 6] {
+    use std::f32::consts::PI;
+
+    let source = img.to_rgba32f();
+    let (width, height) = source.dimensions();
+    // Bilinear, wrapping around horizontally and clamped at the poles
+    let sample = |u: f32, v: f32| {
+        let x = u * width as f32 - 0.5;
+        let y = (v * height as f32 - 0.5).clamp(0.0, height as f32 - 1.0);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(width as i64) as u32;
+            let y = (y as u32).min(height - 1);
+            source.get_pixel(x, y).0
+        };
+        let (a, b, c, d) = (texel(x0, y0), texel(x0 + 1.0, y0), texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+        std::array::from_fn(|i| {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            top + (bottom - top) * fy
+        })
+    };
+
+    let is_float = matches!(img.color(), image::ColorType::Rgb32F | image::ColorType::Rgba32F);
+    std::array::from_fn(|face| {
+        let mut pixels = image::Rgba32FImage::new(face_size, face_size);
+        for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+            let u = (x as f32 + 0.5) / face_size as f32 * 2.0 - 1.0;
+            let v = (y as f32 + 0.5) / face_size as f32 * 2.0 - 1.0;
+            // Same face orientation the GPU uses when sampling a cube
+            let [dx, dy, dz] = match face {
+                0 => [1.0, -v, -u],
+                1 => [-1.0, -v, u],
+                2 => [u, 1.0, v],
+                3 => [u, -1.0, -v],
+                4 => [u, -v, 1.0],
+                _ => [-u, -v, -1.0],
+            };
+            let longitude = dz.atan2(dx);
+            let latitude = (dy / (dx * dx + dy * dy + dz * dz).sqrt()).asin();
+            *pixel = image::Rgba(sample(0.5 + longitude / (2.0 * PI), 0.5 - latitude / PI));
+        }
+        if is_float {
+            image::DynamicImage::ImageRgba32F(pixels)
+        } else {
+            image::DynamicImage::ImageRgba32F(pixels).to_rgba8().into()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lut_strips_become_slices() {
+        // Each texel holds its position in the strip
+        let strip = image::RgbaImage::from_fn(4, 2, |x, y| image::Rgba([x as u8, y as u8, 0, 255]));
+        let (data, size) = lut_volume(&strip.into()).unwrap();
+        assert_eq!(size, 2);
+        let positions = data.chunks_exact(4).map(|texel| (texel[0], texel[1])).collect::<Vec<_>>();
+        assert_eq!(positions, [(0, 0), (1, 0), (0, 1), (1, 1), (2, 0), (3, 0), (2, 1), (3, 1)]);
+
+        assert!(lut_volume(&image::RgbaImage::new(4, 4).into()).is_err());
+    }
+
+    #[test]
+    fn cube_faces_look_the_right_way() {
+        // Red and green hold where in the panorama a texel came from, so
+        // red is 0.5 looking down +X, 0.75 down +Z and 0.25 down -Z
+        let panorama = image::Rgba32FImage::from_fn(64, 32, |x, y| {
+            image::Rgba([(x as f32 + 0.5) / 64.0, (y as f32 + 0.5) / 32.0, 0.0, 1.0])
+        });
+        let faces = equirectangular_to_cube_faces(&panorama.into(), 2).map(|face| face.to_rgba32f());
+        let texel = |face: usize, x, y| faces[face].get_pixel(x, y).0;
+        let mean = |face: usize, channel: usize| faces[face].pixels().map(|pixel| pixel.0[channel]).sum::<f32>() / 4.0;
+        let close = |a: f32, b: f32| (a - b).abs() < 0.02;
+
+        for (face, longitude) in [(0, 0.5), (4, 0.75), (5, 0.25)] {
+            assert!(close(mean(face, 0), longitude), "face {} is at {}", face, mean(face, 0));
+            assert!(close(mean(face, 1), 0.5));
+            // Right is clockwise seen from above, down is towards -Y
+            assert!(texel(face, 1, 0)[0] < texel(face, 0, 0)[0], "face {}", face);
+            assert!(texel(face, 0, 1)[1] > texel(face, 0, 0)[1], "face {}", face);
+        }
+
+        // -X straddles the seam
+        let seam = texel(1, 0, 0)[0];
+        assert!(!(0.1..0.9).contains(&seam), "-X is at {}", seam);
+
+        // +Y has -Z at its top, -Y has +Z
+        assert!(faces[2].pixels().all(|pixel| pixel.0[1] < 0.25));
+        assert!(texel(2, 0, 0)[0] < 0.5 && texel(2, 0, 1)[0] > 0.5);
+        assert!(faces[3].pixels().all(|pixel| pixel.0[1] > 0.75));
+        assert!(texel(3, 0, 0)[0] > 0.5 && texel(3, 0, 1)[0] < 0.5);
+    }
+
+    #[test]
+    fn byte_panoramas_stay_bytes() {
+        let faces = equirectangular_to_cube_faces(&image::RgbaImage::from_pixel(8, 4, image::Rgba([10, 20, 30, 255])).into(), 3);
+        for face in faces {
+            assert_eq!(face.dimensions(), (3, 3));
+            assert!(face.as_rgba8().unwrap().pixels().all(|pixel| pixel.0 == [10, 20, 30, 255]));
         }
     }
 }
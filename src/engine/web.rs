@@ -0,0 +1,50 @@
+use wasm_bindgen::JsCast;
+use winit::dpi::PhysicalSize;
+use winit::platform::web::WindowExtWebSys;
+use winit::window::Window;
+
+fn document() -> Option<web_sys::Document> {
+    web_sys::window()?.document()
+}
+
+/// Returns the element with `id` if it is a `<canvas>` winit can draw into.
+pub(crate) fn find_canvas(id: &str) -> Option<web_sys::HtmlCanvasElement> {
+    document()?
+        .get_element_by_id(id)?
+        .dyn_into::<web_sys::HtmlCanvasElement>()
+        .ok()
+}
+
+/// Puts the window's canvas into the page unless it already is, either inside
+/// the element with `container_id` or at the end of the body.
+pub(crate) fn attach_canvas(window: &Window, container_id: Option<&str>) -> anyhow::Result<()> {
+    let canvas = window.canvas();
+    if canvas.parent_node().is_some() {
+        return Ok(());
+    }
+
+    let document = document().ok_or_else(|| anyhow::anyhow!("No document to attach the canvas to"))?;
+    let container: web_sys::Element = match container_id.and_then(|id| document.get_element_by_id(id)) {
+        Some(element) => element,
+        None => document
+            .body()
+            .ok_or_else(|| anyhow::anyhow!("Document has no body to attach the canvas to"))?
+            .into(),
+    };
+    container
+        .append_child(&canvas)
+        .map_err(|e| anyhow::anyhow!("Couldn't append canvas to the document: {:?}", e))?;
+    Ok(())
+}
+
+/// Physical size of the element the canvas sits in, as laid out by CSS.
+pub(crate) fn container_size(window: &Window) -> Option<PhysicalSize<u32>> {
+    let container = window.canvas().parent_element()?;
+    let scale = web_sys::window()?.device_pixel_ratio();
+    let width = (container.client_width() as f64 * scale) as u32;
+    let height = (container.client_height() as f64 * scale) as u32;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some(PhysicalSize::new(width, height))
+}
@@ -4,6 +4,23 @@ use wasm_bindgen::prelude::*;
 mod engine;
 pub mod ecs;
 
+/// The CPU side of asset loading and the offline formats, for tools that
+/// run without a window like the `bones-asset` binary.
+pub mod assets {
+    pub use crate::engine::{
+        resources::{set_asset_root, asset_root, load_binary, load_string, load_image, load_texture_image, load_model_data, load_source_model_data},
+        model::{ModelData, MeshData, MaterialData, TextureData, ModelVertex},
+        mesh_cache::{MeshCache, Bounds, Submesh, CachedMaterial, TextureRef, Dependency, cache_path},
+        pack::{PackWriter, PackAssetIo, PackCompression, PackEntry},
+        asset_io::AssetIo,
+        gpu_image::{GpuImage, ImageData},
+        mipmap::{generate_mipmaps, generate_float_mipmaps, mip_level_count},
+        texture::equirectangular_to_cube_faces,
+        pipeline::AlphaMode,
+        asset_error::AssetError,
+    };
+}
+
 pub mod prelude {
     pub use crate::{
         ecs::*,
@@ -11,8 +28,28 @@ pub mod prelude {
             Model,
             Mesh,
             Material,
-            Instance
-        }
+            MaterialUniform,
+            MaterialTextures,
+            Instance,
+        },
+        engine::animation::{
+            AnimationClip,
+            AnimationPlayer,
+            Skin,
+        },
+        engine::render_target::RenderTarget,
+        engine::asset_io::{AssetIo, FileAssetIo, MemoryAssetIo},
+        engine::mesh_cache::MeshCache,
+        engine::gpu_image::{GpuImage, ImageData},
+        engine::pack::{PackAssetIo, PackWriter, PackCompression},
+        engine::asset_server::{AssetServer, Handle, LoadState, AssetEvent},
+        engine::asset_error::AssetError,
+        engine::texture::{Texture, TextureSettings, Filtering},
+        engine::pipeline::{AlphaMode, MaterialPipeline},
+        engine::environment::Environment,
+        engine::shadow::{DirectionalLight, SpotLight, Shadows},
+        engine::debug_draw::DebugDraw,
+        engine::sampler::SamplerSettings,
     };
     pub use cgmath::{ Vector3, Quaternion, Deg };
     pub use cgmath::prelude::*;